@@ -0,0 +1,339 @@
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use madt::{
+    InterruptOverride, IoApicInfo, LocalApicNmi, Madt, Polarity, Processor, TriggerMode,
+};
+pub use mcfg::{ConfigRegion, Mcfg};
+
+use crate::memory;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::convert::TryInto;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const SDT_HEADER_SIZE: usize = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,
+    BadChecksum([u8; 4]),
+    BadLength([u8; 4]),
+    TableNotFound([u8; 4]),
+    AlreadyInitialized,
+    NotInitialized,
+    /// The DSDT has no `\_S5` package to power off with.
+    NoSoftOff,
+    ResetUnsupported,
+    /// The firmware did not hand over to ACPI mode.
+    EnableTimeout,
+}
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+/// Views physical memory through the mapping of all physical memory.
+///
+/// # Safety
+///
+/// The range must be backed by memory that stays valid, like firmware
+/// tables.
+unsafe fn physical_bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    let virt = memory::physical_memory_offset() + addr.as_u64();
+    core::slice::from_raw_parts(virt.as_ptr(), len)
+}
+
+/// Looks for the RSDP in the first KiB of the EBDA and in the BIOS area
+/// between 0xE0000 and 0xFFFFF.
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = unsafe { read_u16(physical_bytes(PhysAddr::new(0x40e), 2), 0) } as u64;
+    let areas = [(ebda << 4, 1024), (0xe0000, 0x20000)];
+    for (start, len) in IntoIterator::into_iter(areas) {
+        if start == 0 {
+            continue;
+        }
+        let bytes = unsafe { physical_bytes(PhysAddr::new(start), len) };
+        for offset in (0..len - 20).step_by(16) {
+            if &bytes[offset..offset + 8] == RSDP_SIGNATURE
+                && checksum_ok(&bytes[offset..offset + 20])
+            {
+                return Some(PhysAddr::new(start + offset as u64));
+            }
+        }
+    }
+    None
+}
+
+/// A system description table whose length and checksum have been
+/// validated.
+#[derive(Clone, Copy)]
+pub struct Sdt {
+    pub address: PhysAddr,
+    data: &'static [u8],
+}
+
+impl Sdt {
+    unsafe fn load(address: PhysAddr) -> Result<Self, AcpiError> {
+        let header = physical_bytes(address, SDT_HEADER_SIZE);
+        let signature: [u8; 4] = header[0..4].try_into().unwrap();
+        let len = read_u32(header, 4) as usize;
+        if len < SDT_HEADER_SIZE {
+            return Err(AcpiError::BadLength(signature));
+        }
+        let data = physical_bytes(address, len);
+        if !checksum_ok(data) {
+            return Err(AcpiError::BadChecksum(signature));
+        }
+        Ok(Self { address, data })
+    }
+
+    pub fn signature(&self) -> [u8; 4] {
+        self.data[0..4].try_into().unwrap()
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.data[8]
+    }
+
+    pub fn oem_id(&self) -> &'static [u8] {
+        &self.data[10..16]
+    }
+
+    /// The whole table, header included.
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+
+    /// The table contents following the common header.
+    pub fn body(&self) -> &'static [u8] {
+        &self.data[SDT_HEADER_SIZE..]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterSpace {
+    Memory,
+    Io,
+    PciConfig,
+    Other(u8),
+}
+
+/// A register location in the Generic Address Structure format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: RegisterSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Parses the 12 byte structure, returning `None` for a zero address,
+    /// which means the register is not implemented.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let address = read_u64(bytes, 4);
+        if address == 0 {
+            return None;
+        }
+        Some(GenericAddress {
+            space: match bytes[0] {
+                0 => RegisterSpace::Memory,
+                1 => RegisterSpace::Io,
+                2 => RegisterSpace::PciConfig,
+                other => RegisterSpace::Other(other),
+            },
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address,
+        })
+    }
+}
+
+pub struct Acpi {
+    pub revision: u8,
+    tables: Vec<Sdt>,
+    madt: Option<Madt>,
+    fadt: Option<Fadt>,
+    hpet: Option<Hpet>,
+    mcfg: Option<Mcfg>,
+    dsdt: Option<Sdt>,
+}
+
+impl Acpi {
+    pub fn table(&self, signature: &[u8; 4]) -> Result<&Sdt, AcpiError> {
+        self.tables
+            .iter()
+            .find(|t| &t.signature() == signature)
+            .ok_or(AcpiError::TableNotFound(*signature))
+    }
+
+    pub fn tables(&self) -> impl Iterator<Item = &Sdt> {
+        self.tables.iter()
+    }
+
+    pub fn madt(&self) -> Option<&Madt> {
+        self.madt.as_ref()
+    }
+
+    pub fn fadt(&self) -> Option<&Fadt> {
+        self.fadt.as_ref()
+    }
+
+    pub fn hpet(&self) -> Option<&Hpet> {
+        self.hpet.as_ref()
+    }
+
+    pub fn mcfg(&self) -> Option<&Mcfg> {
+        self.mcfg.as_ref()
+    }
+
+    /// The DSDT, which the FADT points to instead of the root table.
+    pub fn dsdt(&self) -> Option<&Sdt> {
+        self.dsdt.as_ref()
+    }
+}
+
+static ACPI: OnceCell<Acpi> = OnceCell::uninit();
+
+/// Locates and validates the ACPI tables. Tables with a bad checksum are
+/// skipped. Needs the heap and the physical memory mapping.
+pub fn init() -> Result<&'static Acpi, AcpiError> {
+    let rsdp_addr = find_rsdp().ok_or(AcpiError::NoRsdp)?;
+    let rsdp = unsafe { physical_bytes(rsdp_addr, 36) };
+    let revision = rsdp[15];
+    let (root, entry_size) = if revision >= 2 {
+        let len = read_u32(rsdp, 20) as usize;
+        if len < 36 || !checksum_ok(unsafe { physical_bytes(rsdp_addr, len) }) {
+            return Err(AcpiError::BadChecksum(*b"RSDP"));
+        }
+        (PhysAddr::new(read_u64(rsdp, 24)), 8)
+    } else {
+        (PhysAddr::new(read_u32(rsdp, 16) as u64), 4)
+    };
+
+    let root = unsafe { Sdt::load(root)? };
+    let tables = root
+        .body()
+        .chunks_exact(entry_size)
+        .map(|entry| match entry_size {
+            8 => read_u64(entry, 0),
+            _ => read_u32(entry, 0) as u64,
+        })
+        .filter_map(|addr| unsafe { Sdt::load(PhysAddr::new(addr)).ok() })
+        .collect();
+
+    let mut acpi = Acpi {
+        revision,
+        tables,
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: None,
+        dsdt: None,
+    };
+    acpi.madt = acpi.table(b"APIC").ok().map(Madt::parse);
+    acpi.fadt = acpi.table(b"FACP").ok().and_then(Fadt::parse);
+    acpi.hpet = acpi.table(b"HPET").ok().and_then(Hpet::parse);
+    acpi.mcfg = acpi.table(b"MCFG").ok().map(Mcfg::parse);
+    acpi.dsdt = acpi
+        .fadt
+        .and_then(|fadt| unsafe { Sdt::load(fadt.dsdt).ok() });
+
+    ACPI.try_init_once(|| acpi)
+        .map_err(|_| AcpiError::AlreadyInitialized)?;
+    Ok(ACPI.get().unwrap())
+}
+
+/// Returns the tables found by `init`, if it succeeded.
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.get()
+}
+
+const SCI_ENABLED: u16 = 1;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+/// Switches the chipset from legacy to ACPI mode through the SMI command
+/// port, unless the firmware already did.
+fn enable(fadt: &Fadt) -> Result<(), AcpiError> {
+    let mut control = Port::<u16>::new(fadt.pm1a_control as u16);
+    if unsafe { control.read() } & SCI_ENABLED != 0 {
+        return Ok(());
+    }
+    if fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return Ok(());
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable) };
+    for _ in 0..1_000_000 {
+        if unsafe { control.read() } & SCI_ENABLED != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(AcpiError::EnableTimeout)
+}
+
+/// Enters the S5 soft-off state. Only returns if the machine is still
+/// running afterwards.
+pub fn shutdown() -> Result<(), AcpiError> {
+    let acpi = get().ok_or(AcpiError::NotInitialized)?;
+    let fadt = acpi.fadt().ok_or(AcpiError::TableNotFound(*b"FACP"))?;
+    let dsdt = acpi.dsdt().ok_or(AcpiError::TableNotFound(*b"DSDT"))?;
+    let (type_a, type_b) = fadt::parse_s5(dsdt.body())
+        .filter(|_| fadt.pm1a_control != 0)
+        .ok_or(AcpiError::NoSoftOff)?;
+    enable(fadt)?;
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u16>::new(fadt.pm1a_control as u16).write(type_a << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+        if fadt.pm1b_control != 0 {
+            Port::<u16>::new(fadt.pm1b_control as u16)
+                .write(type_b << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+        }
+    });
+    Ok(())
+}
+
+/// Resets the machine through the FADT reset register. Only returns if the
+/// machine is still running afterwards.
+pub fn reset() -> Result<(), AcpiError> {
+    let acpi = get().ok_or(AcpiError::NotInitialized)?;
+    let fadt = acpi.fadt().ok_or(AcpiError::TableNotFound(*b"FACP"))?;
+    let register = match fadt.reset_register {
+        Some(register) if fadt.supports_reset() => register,
+        _ => return Err(AcpiError::ResetUnsupported),
+    };
+    match register.space {
+        RegisterSpace::Io => unsafe {
+            Port::<u8>::new(register.address as u16).write(fadt.reset_value)
+        },
+        RegisterSpace::Memory => {
+            let addr = memory::map_mmio(PhysAddr::new(register.address), 1)
+                .map_err(|_| AcpiError::ResetUnsupported)?;
+            unsafe { addr.as_mut_ptr::<u8>().write_volatile(fadt.reset_value) };
+        }
+        _ => return Err(AcpiError::ResetUnsupported),
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use super::{read_u16, read_u32, read_u64, GenericAddress, Sdt};
+use x86_64::PhysAddr;
+
+/// The platform supports the reset register.
+pub const RESET_REG_SUPPORTED: u32 = 1 << 10;
+
+/// Fixed ACPI Description Table, the fixed hardware registers for power
+/// management. Offsets below are from the start of the table.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    pub sci_interrupt: u16,
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event: u32,
+    pub pm1b_event: u32,
+    pub pm1a_control: u32,
+    pub pm1b_control: u32,
+    pub pm_timer: u32,
+    /// CMOS RAM index of the century, 0 if there is none.
+    pub century: u8,
+    pub boot_architecture: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Parses the table if it is at least as long as the ACPI 1.0 layout.
+    pub(super) fn parse(sdt: &Sdt) -> Option<Self> {
+        let data = sdt.data();
+        if data.len() < 116 {
+            return None;
+        }
+        let mut dsdt = read_u32(data, 40) as u64;
+        if data.len() >= 148 && read_u64(data, 140) != 0 {
+            dsdt = read_u64(data, 140);
+        }
+        let (reset_register, reset_value) = match data.len() {
+            len if len >= 129 => (GenericAddress::parse(&data[116..128]), data[128]),
+            _ => (None, 0),
+        };
+        Some(Fadt {
+            dsdt: PhysAddr::new(dsdt),
+            sci_interrupt: read_u16(data, 46),
+            smi_command: read_u32(data, 48),
+            acpi_enable: data[52],
+            acpi_disable: data[53],
+            pm1a_event: read_u32(data, 56),
+            pm1b_event: read_u32(data, 60),
+            pm1a_control: read_u32(data, 64),
+            pm1b_control: read_u32(data, 68),
+            pm_timer: read_u32(data, 76),
+            century: data[108],
+            boot_architecture: read_u16(data, 109),
+            flags: read_u32(data, 112),
+            reset_register,
+            reset_value,
+        })
+    }
+
+    pub fn supports_reset(&self) -> bool {
+        self.flags & RESET_REG_SUPPORTED != 0 && self.reset_register.is_some()
+    }
+}
+
+/// Finds the SLP_TYPa and SLP_TYPb values of the `\_S5` sleep state in the
+/// AML of the DSDT. It only understands the package a plain `Name` compiles
+/// to, which is what firmware emits in practice.
+pub fn parse_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0a;
+    const ZERO_OP: u8 = 0x00;
+    const ONE_OP: u8 = 0x01;
+
+    let pos = aml.windows(4).position(|w| w == b"_S5_")?;
+    let named = match pos {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => aml[pos - 1] == NAME_OP || (aml[pos - 2] == NAME_OP && aml[pos - 1] == b'\\'),
+    };
+    if !named || *aml.get(pos + 4)? != PACKAGE_OP {
+        return None;
+    }
+    // the two high bits of the lead byte count the extra length bytes
+    let lead = *aml.get(pos + 5)?;
+    let mut rest = aml.get(pos + 6 + (lead >> 6) as usize + 1..)?.iter();
+    let mut integer = || match *rest.next()? {
+        BYTE_PREFIX => rest.next().map(|&b| b as u16),
+        ZERO_OP => Some(0),
+        ONE_OP => Some(1),
+        _ => None,
+    };
+    Some((integer()?, integer()?))
+}
+
+#[test_case]
+fn test_parse_s5() {
+    // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero })
+    let aml = [
+        0x10, 0x08, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0a, 0x05, 0x00, 0x00,
+        0x00,
+    ];
+    assert_eq!(parse_s5(&aml), Some((5, 0)));
+    assert_eq!(parse_s5(&aml[..8]), None);
+    assert_eq!(parse_s5(b"_S5_"), None);
+}
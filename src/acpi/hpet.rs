@@ -0,0 +1,35 @@
+use super::{read_u16, read_u32, GenericAddress, Sdt};
+
+/// High Precision Event Timer description.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    pub address: GenericAddress,
+    pub number: u8,
+    /// Smallest period in main counter ticks that periodic mode supports.
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub(super) fn parse(sdt: &Sdt) -> Option<Self> {
+        let data = sdt.data();
+        if data.len() < 56 {
+            return None;
+        }
+        let id = read_u32(data, 36);
+        Some(Hpet {
+            hardware_revision: id as u8,
+            comparators: ((id >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            pci_vendor_id: (id >> 16) as u16,
+            address: GenericAddress::parse(&data[40..52])?,
+            number: data[52],
+            minimum_tick: read_u16(data, 53),
+        })
+    }
+}
@@ -0,0 +1,144 @@
+use super::{read_u16, read_u32, read_u64, Sdt};
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// Decodes the MPS INTI flags shared by several MADT entries. Conforming
+/// to the bus means active high and edge triggered for ISA.
+fn inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::Edge,
+    };
+    (polarity, trigger)
+}
+
+/// Maps a legacy ISA IRQ to a global system interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// A local APIC LINT pin wired to NMI. `processor_id` 0xff means all
+/// processors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    pub processor_id: u8,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// Multiple APIC Description Table.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+impl Madt {
+    pub(super) fn parse(sdt: &Sdt) -> Self {
+        let body = sdt.body();
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(read_u32(body, 0) as u64),
+            has_legacy_pics: read_u32(body, 4) & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+        let mut offset = 8;
+        while offset + 2 <= body.len() {
+            let kind = body[offset];
+            let len = body[offset + 1] as usize;
+            if len < 2 || offset + len > body.len() {
+                break;
+            }
+            let entry = &body[offset..offset + len];
+            match (kind, len) {
+                (0, 8) => madt.processors.push(Processor {
+                    processor_id: entry[2],
+                    apic_id: entry[3],
+                    enabled: read_u32(entry, 4) & 0b11 != 0,
+                }),
+                (1, 12) => madt.io_apics.push(IoApicInfo {
+                    id: entry[2],
+                    address: PhysAddr::new(read_u32(entry, 4) as u64),
+                    gsi_base: read_u32(entry, 8),
+                }),
+                (2, 10) => {
+                    let (polarity, trigger) = inti_flags(read_u16(entry, 8));
+                    madt.overrides.push(InterruptOverride {
+                        irq: entry[3],
+                        gsi: read_u32(entry, 4),
+                        polarity,
+                        trigger,
+                    });
+                }
+                (4, 6) => {
+                    let (polarity, trigger) = inti_flags(read_u16(entry, 3));
+                    madt.nmis.push(LocalApicNmi {
+                        processor_id: entry[2],
+                        lint: entry[5],
+                        polarity,
+                        trigger,
+                    });
+                }
+                (5, 12) => madt.local_apic_address = PhysAddr::new(read_u64(entry, 4)),
+                _ => {}
+            }
+            offset += len;
+        }
+        madt
+    }
+
+    /// Returns the global system interrupt that the ISA `irq` is wired to.
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride {
+        self.overrides
+            .iter()
+            .find(|o| o.irq == irq)
+            .copied()
+            .unwrap_or(InterruptOverride {
+                irq,
+                gsi: irq as u32,
+                polarity: Polarity::ActiveHigh,
+                trigger: TriggerMode::Edge,
+            })
+    }
+}
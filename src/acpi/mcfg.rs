@@ -0,0 +1,38 @@
+use super::{read_u16, read_u64, Sdt};
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+const ENTRY_SIZE: usize = 16;
+
+/// Memory mapped configuration space of the PCI buses `start_bus` to
+/// `end_bus` in a segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigRegion {
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// PCI Express memory mapped configuration table.
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub regions: Vec<ConfigRegion>,
+}
+
+impl Mcfg {
+    pub(super) fn parse(sdt: &Sdt) -> Self {
+        // the entries follow eight reserved bytes
+        let entries = sdt.body().get(8..).unwrap_or(&[]);
+        let regions = entries
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| ConfigRegion {
+                base: PhysAddr::new(read_u64(entry, 0)),
+                segment: read_u16(entry, 8),
+                start_bus: entry[10],
+                end_bus: entry[11],
+            })
+            .collect();
+        Mcfg { regions }
+    }
+}
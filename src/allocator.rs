@@ -2,7 +2,10 @@ pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
 
+use crate::memory::{self, Region, RegionKind};
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
 use x86_64::{
     structures::paging::{
         mapper::{MapToError, PageTableFrameMapping},
@@ -14,6 +17,10 @@ use x86_64::{
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
+pub const HEAP_GROW_SIZE: usize = 64 * 1024;
+pub const DEFAULT_HEAP_LIMIT: usize = 64 * 1024 * 1024;
+
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_HEAP_LIMIT);
 
 pub struct DummyAllocator;
 #[allow(dead_code)]
@@ -69,15 +76,34 @@ pub fn align_up(addr: usize, align: usize) -> usize {
     }
 }
 
-pub fn init_heap(
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.max(HEAP_SIZE), Ordering::Relaxed);
+}
+
+/// Bytes reserved for the heap, including pages that were not touched yet.
+pub fn heap_size() -> usize {
+    let start = VirtAddr::new(HEAP_START as u64);
+    interrupts::without_interrupts(|| memory::region::KERNEL_REGIONS.lock().find(start))
+        .map_or(0, |region| (region.end - region.start) as usize)
+}
+
+fn page_range(start: usize, size: usize) -> impl Iterator<Item = Page<Size4KiB>> {
+    (start..start + size)
+        .step_by(4096)
+        .map(|vaddr| Page::<Size4KiB>::containing_address(VirtAddr::new(vaddr as u64)))
+}
+
+fn map_heap_pages(
+    start: usize,
+    size: usize,
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
-    let vaddrs = (HEAP_START..HEAP_START + HEAP_SIZE).step_by(4096);
-    let pages =
-        vaddrs.map(|vaddr| Page::<Size4KiB>::containing_address(VirtAddr::new(vaddr as u64)));
-
-    for page in pages {
+    for page in page_range(start, size) {
         let flags = PageTableFlags::WRITABLE | PageTableFlags::PRESENT;
         let frame = frame_allocator
             .allocate_frame()
@@ -86,26 +112,52 @@ pub fn init_heap(
             mapper.map_to(page, frame, flags, frame_allocator)?.flush();
         }
     }
-    /*
-        let page_range = {
-            let heap_start = VirtAddr::new(HEAP_START as u64);
-            let heap_end = heap_start + HEAP_SIZE - 1u64;
-            let heap_start_page = Page::containing_address(heap_start);
-            let heap_end_page = Page::containing_address(heap_end);
-            Page::range_inclusive(heap_start_page, heap_end_page)
-        };
-
-        for page in page_range {
-            let frame = frame_allocator
-                .allocate_frame()
-                .ok_or(MapToError::FrameAllocationFailed)?;
-            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-        }
-    */
+    Ok(())
+}
+
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        map_heap_pages(HEAP_START, HEAP_SIZE, mapper, frame_allocator)
+    })
+    .unwrap_or(Err(MapToError::FrameAllocationFailed))?;
+    memory::add_kernel_region(heap_region(HEAP_START + HEAP_SIZE))
+        .expect("heap region overlaps another kernel region");
+
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
 }
+
+fn heap_region(heap_top: usize) -> Region {
+    Region::new(
+        VirtAddr::new(HEAP_START as u64),
+        (heap_top - HEAP_START) as u64,
+        PageTableFlags::WRITABLE,
+        RegionKind::Heap,
+    )
+}
+
+/// Extends the heap region directly above `heap_top` by at least `min_size`
+/// bytes and returns how many bytes were added. The new pages are mapped by
+/// the page fault handler when they are first touched, so this only checks
+/// that enough free frames are left to back them.
+pub(crate) fn grow_heap(heap_top: usize, min_size: usize) -> Option<usize> {
+    let grow_size = align_up(min_size.max(1), HEAP_GROW_SIZE);
+    let new_top = heap_top.checked_add(grow_size)?;
+    if new_top - HEAP_START > heap_limit() {
+        return None;
+    }
+
+    let free_frames =
+        memory::with_kernel_memory(|_, frame_allocator| frame_allocator.free_frames())?;
+    if free_frames < grow_size / 4096 {
+        return None;
+    }
+    let start = VirtAddr::new(HEAP_START as u64);
+    let end = VirtAddr::new(new_top as u64);
+    interrupts::without_interrupts(|| memory::region::KERNEL_REGIONS.lock().resize(start, end))
+        .ok()?;
+    Some(grow_size)
+}
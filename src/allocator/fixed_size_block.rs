@@ -3,7 +3,8 @@ use core::{
     ptr::{self, NonNull},
 };
 
-use super::Locked;
+use super::{grow_heap, Locked};
+use x86_64::instructions::interrupts;
 
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
@@ -41,44 +42,59 @@ impl FixedSizeAllocator {
     }
 
     pub fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(mut ptr) => unsafe { ptr.as_mut() },
-            _ => ptr::null_mut(),
+        if let Ok(mut ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return unsafe { ptr.as_mut() };
+        }
+
+        let heap_top = self.fallback_allocator.top();
+        match grow_heap(heap_top, layout.size() + layout.align()) {
+            Some(grown) => {
+                unsafe { self.fallback_allocator.extend(grown) };
+                match self.fallback_allocator.allocate_first_fit(layout) {
+                    Ok(mut ptr) => unsafe { ptr.as_mut() },
+                    _ => ptr::null_mut(),
+                }
+            }
+            None => ptr::null_mut(),
         }
     }
 }
 
 unsafe impl GlobalAlloc for Locked<FixedSizeAllocator> {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(idx) => match allocator.lists_allocator[idx].take() {
-                Some(head) => {
-                    allocator.lists_allocator[idx] = head.next.take();
-                    head as *mut ListNode as *mut u8
-                }
-                None => {
-                    let block_size = BLOCK_SIZES[idx];
-                    let layout = Layout::from_size_align(block_size, block_size).unwrap();
-                    allocator.fallback_alloc(layout)
-                }
-            },
-            None => allocator.fallback_alloc(layout),
-        }
+        interrupts::without_interrupts(|| {
+            let mut allocator = self.lock();
+            match list_index(&layout) {
+                Some(idx) => match allocator.lists_allocator[idx].take() {
+                    Some(head) => {
+                        allocator.lists_allocator[idx] = head.next.take();
+                        head as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        let block_size = BLOCK_SIZES[idx];
+                        let layout = Layout::from_size_align(block_size, block_size).unwrap();
+                        allocator.fallback_alloc(layout)
+                    }
+                },
+                None => allocator.fallback_alloc(layout),
+            }
+        })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(idx) => {
-                let new_head = ListNode::new(allocator.lists_allocator[idx].take());
-                let new_head_ptr = ptr as *mut ListNode;
-                new_head_ptr.write(new_head);
-                allocator.lists_allocator[idx] = Some(&mut *new_head_ptr);
-            }
-            None => allocator
-                .fallback_allocator
-                .deallocate(NonNull::new_unchecked(ptr), layout),
-        };
+        interrupts::without_interrupts(|| {
+            let mut allocator = self.lock();
+            match list_index(&layout) {
+                Some(idx) => {
+                    let new_head = ListNode::new(allocator.lists_allocator[idx].take());
+                    let new_head_ptr = ptr as *mut ListNode;
+                    new_head_ptr.write(new_head);
+                    allocator.lists_allocator[idx] = Some(&mut *new_head_ptr);
+                }
+                None => allocator
+                    .fallback_allocator
+                    .deallocate(NonNull::new_unchecked(ptr), layout),
+            };
+        })
     }
 }
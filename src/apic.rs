@@ -0,0 +1,378 @@
+use crate::acpi::{self, AcpiError, Polarity, TriggerMode};
+use crate::interrupts::InterruptIndex;
+use crate::time::PIT_FREQUENCY;
+use crate::{memory, percpu};
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::VirtAddr;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_PENDING: u32 = 1 << 12;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+/// Divide the bus clock by 16.
+const TIMER_DIVIDE_16: u32 = 0b0011;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+pub const TIMER_FREQUENCY: u32 = 100;
+
+#[derive(Debug)]
+pub enum ApicError {
+    NotSupported,
+    Acpi(AcpiError),
+    NoMadt,
+    NoIoApic,
+    NoRoute(u8),
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<AcpiError> for ApicError {
+    fn from(err: AcpiError) -> Self {
+        ApicError::Acpi(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for ApicError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        ApicError::Map(err)
+    }
+}
+
+/// Virtual address of the local APIC registers, 0 while the 8259 PIC is in
+/// use. Interrupt handlers read it to decide where to send the EOI.
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Initial count of the local APIC timer for `TIMER_FREQUENCY`, measured
+/// on the bootstrap processor and reused on the others.
+static TIMER_INITIAL: AtomicU32 = AtomicU32::new(0);
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+/// PIT channel 2 is shared by all CPUs, so it is only programmed and polled
+/// while holding this.
+static PIT_CHANNEL_2: Mutex<()> = Mutex::new(());
+
+struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { (self.base + reg).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { (self.base + reg).as_mut_ptr::<u32>().write_volatile(value) }
+    }
+
+    fn enable(&self) {
+        unsafe {
+            let mut base_msr = Msr::new(IA32_APIC_BASE);
+            let value = base_msr.read();
+            base_msr.write(value | APIC_BASE_ENABLE);
+        }
+        self.write(LAPIC_TPR, 0);
+        self.write(
+            LAPIC_SVR,
+            SVR_ENABLE | InterruptIndex::ApicSpurious.as_u8() as u32,
+        );
+    }
+
+    /// Interrupt handlers send IPIs too, so the two ICR writes must not be
+    /// interleaved with theirs.
+    fn send(&self, apic_id: u8, command: u32) {
+        interrupts::without_interrupts(|| {
+            self.write(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+            self.write(LAPIC_ICR_LOW, command);
+            while self.read(LAPIC_ICR_LOW) & ICR_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        })
+    }
+}
+
+fn local_apic() -> Option<LocalApic> {
+    match LAPIC_BASE.load(Ordering::Acquire) {
+        0 => None,
+        base => Some(LocalApic {
+            base: VirtAddr::new(base),
+        }),
+    }
+}
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            (self.base + IOREGSEL)
+                .as_mut_ptr::<u32>()
+                .write_volatile(reg);
+            (self.base + IOWIN).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            (self.base + IOREGSEL)
+                .as_mut_ptr::<u32>()
+                .write_volatile(reg);
+            (self.base + IOWIN)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn mask_all(&self) {
+        for gsi in self.gsi_base..self.gsi_base + self.entries {
+            self.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION + (gsi - self.gsi_base) * 2;
+        self.write(reg, entry as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+    }
+}
+
+// `__cpuid` is only safe to call on newer compilers
+#[allow(unused_unsafe)]
+pub fn is_supported() -> bool {
+    unsafe { __cpuid(1).edx & (1 << 9) != 0 }
+}
+
+/// Returns whether interrupts are delivered through the APIC instead of the
+/// 8259 PIC.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+pub fn local_apic_id() -> Option<u8> {
+    local_apic().map(|lapic| (lapic.read(LAPIC_ID) >> 24) as u8)
+}
+
+pub fn end_of_interrupt() {
+    if let Some(lapic) = local_apic() {
+        lapic.write(LAPIC_EOI, 0);
+    }
+}
+
+/// Number of local APIC timer interrupts the current CPU has taken.
+pub fn timer_ticks() -> u64 {
+    percpu::current().timer_ticks.load(Ordering::Relaxed)
+}
+
+pub(crate) fn on_timer_tick() {
+    percpu::current()
+        .timer_ticks
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Sends `vector` to the CPU with the given local APIC id.
+pub fn send_ipi(apic_id: u8, vector: u8) {
+    if let Some(lapic) = local_apic() {
+        lapic.send(apic_id, vector as u32 | ICR_ASSERT);
+    }
+}
+
+/// Sends the INIT-SIPI-SIPI sequence that starts an application processor
+/// in real mode at physical address `page << 12`.
+pub(crate) fn start_ap(apic_id: u8, page: u8) -> Result<(), ApicError> {
+    let lapic = local_apic().ok_or(ApicError::NotSupported)?;
+    lapic.send(apic_id, ICR_INIT | ICR_ASSERT);
+    busy_wait(10_000);
+    for _ in 0..2 {
+        lapic.send(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32);
+        busy_wait(200);
+    }
+    Ok(())
+}
+
+/// Enables the local APIC of an application processor and starts its
+/// timer. `init` must have run on the bootstrap processor.
+pub(crate) fn init_ap() -> Result<(), ApicError> {
+    let lapic = local_apic().ok_or(ApicError::NotSupported)?;
+    lapic.enable();
+    arm_timer(&lapic);
+    Ok(())
+}
+
+/// Switches interrupt delivery from the 8259 PIC to the local APIC and the
+/// IO-APICs described by the MADT. The legacy timer and keyboard IRQs keep
+/// their vectors, and the local APIC timer is started at `TIMER_FREQUENCY`.
+/// On error nothing has been changed and the PIC stays in use.
+pub fn init() -> Result<(), ApicError> {
+    if !is_supported() {
+        return Err(ApicError::NotSupported);
+    }
+    let acpi = match acpi::get() {
+        Some(acpi) => acpi,
+        None => acpi::init()?,
+    };
+    let madt = acpi.madt().ok_or(ApicError::NoMadt)?;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let lapic = LocalApic {
+        base: memory::map_mmio(madt.local_apic_address, 0x1000)?,
+    };
+    let mut io_apics = Vec::new();
+    for info in &madt.io_apics {
+        let base = memory::map_mmio(info.address, 0x20)?;
+        let mut io_apic = IoApic {
+            base,
+            gsi_base: info.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        io_apic.mask_all();
+        io_apics.push(io_apic);
+    }
+
+    interrupts::without_interrupts(|| {
+        lapic.enable();
+        *IO_APICS.lock() = io_apics;
+        LAPIC_BASE.store(lapic.base.as_u64(), Ordering::Release);
+
+        let routed = route_irq(0, InterruptIndex::Timer.as_u8())
+            .and_then(|()| route_irq(1, InterruptIndex::Keyboard.as_u8()));
+        if let Err(err) = routed {
+            LAPIC_BASE.store(0, Ordering::Release);
+            for io_apic in IO_APICS.lock().drain(..) {
+                io_apic.mask_all();
+            }
+            return Err(err);
+        }
+        let pics = crate::interrupts::PICS;
+        unsafe { pics.lock().disable() };
+        ENABLED.store(true, Ordering::Release);
+
+        start_timer(&lapic);
+        Ok(())
+    })
+}
+
+/// Routes the legacy ISA `irq` to `vector` on the current CPU, following
+/// the interrupt source overrides of the MADT.
+pub fn route_irq(irq: u8, vector: u8) -> Result<(), ApicError> {
+    let madt = acpi::get()
+        .and_then(|acpi| acpi.madt())
+        .ok_or(ApicError::NoMadt)?;
+    let route = madt.isa_irq(irq);
+    let mut entry = vector as u64 | (local_apic_id().unwrap_or(0) as u64) << 56;
+    if route.polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if route.trigger == TriggerMode::Level {
+        entry |= REDIRECTION_LEVEL;
+    }
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics
+        .iter()
+        .find(|io_apic| io_apic.handles(route.gsi))
+        .ok_or(ApicError::NoRoute(irq))?;
+    io_apic.set_redirection(route.gsi, entry);
+    Ok(())
+}
+
+/// Starts PIT channel 2 counting down `count` ticks with its output gated
+/// to port 0x61 instead of the speaker.
+fn pit_start(count: u16) -> Port<u8> {
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+    unsafe {
+        let value = gate.read();
+        gate.write((value & !0b10) | 0b01);
+        // channel 2, lobyte/hibyte, interrupt on terminal count
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+    }
+    gate
+}
+
+fn pit_expired(gate: &mut Port<u8>) -> bool {
+    unsafe { gate.read() & 0x20 != 0 }
+}
+
+/// Spins for at least `micros` microseconds using PIT channel 2.
+pub fn busy_wait(micros: u64) {
+    let _channel = PIT_CHANNEL_2.lock();
+    let mut ticks = micros * PIT_FREQUENCY as u64 / 1_000_000 + 1;
+    while ticks > 0 {
+        let count = ticks.min(u16::MAX as u64);
+        let mut gate = pit_start(count as u16);
+        while !pit_expired(&mut gate) {
+            core::hint::spin_loop();
+        }
+        ticks -= count;
+    }
+}
+
+/// Measures the local APIC timer against 10ms of PIT channel 2 and starts
+/// it in periodic mode.
+fn start_timer(lapic: &LocalApic) {
+    lapic.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    lapic.write(LAPIC_LVT_TIMER, LVT_MASKED);
+    let elapsed = {
+        let _channel = PIT_CHANNEL_2.lock();
+        let mut gate = pit_start((PIT_FREQUENCY / 100) as u16);
+        lapic.write(LAPIC_TIMER_INITIAL, u32::MAX);
+        while !pit_expired(&mut gate) {}
+        u32::MAX - lapic.read(LAPIC_TIMER_CURRENT)
+    };
+
+    let initial = elapsed as u64 * 100 / TIMER_FREQUENCY as u64;
+    TIMER_INITIAL.store(initial.clamp(1, u32::MAX as u64) as u32, Ordering::Relaxed);
+    arm_timer(lapic);
+}
+
+fn arm_timer(lapic: &LocalApic) {
+    lapic.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_16);
+    lapic.write(
+        LAPIC_LVT_TIMER,
+        LVT_PERIODIC | InterruptIndex::ApicTimer.as_u8() as u32,
+    );
+    lapic.write(LAPIC_TIMER_INITIAL, TIMER_INITIAL.load(Ordering::Relaxed));
+}
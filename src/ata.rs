@@ -0,0 +1,560 @@
+//! ATA disks on the primary and secondary IDE channels at their legacy
+//! ports, moved a sector at a time by PIO. Each sector is signalled by
+//! IRQ 14 or 15, whose handler wakes the task waiting for it.
+
+use crate::apic::ApicError;
+use crate::block::{self, BlockDevice, BlockError, BlockFuture, SECTOR_SIZE};
+use crate::interrupts;
+use crate::task::timer;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::task::{Poll, Waker};
+use core::time::Duration;
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+pub const PRIMARY: usize = 0;
+pub const SECONDARY: usize = 1;
+
+// registers from the I/O base of a channel
+const DATA: u16 = 0;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE_SELECT: u16 = 6;
+/// Status on reads, commands on writes. Reading it acknowledges the
+/// interrupt, so polling goes through the alternate status.
+const STATUS: u16 = 7;
+
+/// Set in the control register, resets both drives of a channel.
+const CONTROL_RESET: u8 = 1 << 2;
+/// Alternate status reads that hold the reset for the 5us it needs.
+const RESET_HOLD_READS: usize = 50;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DRIVE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+/// Read from a channel without a controller.
+const FLOATING_BUS: u8 = 0xff;
+
+const DRIVE_LBA: u8 = 1 << 6;
+const DRIVE_SLAVE: u8 = 1 << 4;
+/// Obsolete bits that old drives expect to be set.
+const DRIVE_ALWAYS_SET: u8 = 0xa0;
+
+const IDENTIFY: u8 = 0xec;
+const READ_SECTORS: u8 = 0x20;
+const READ_SECTORS_EXT: u8 = 0x24;
+const WRITE_SECTORS: u8 = 0x30;
+const WRITE_SECTORS_EXT: u8 = 0x34;
+const FLUSH_CACHE: u8 = 0xe7;
+const FLUSH_CACHE_EXT: u8 = 0xea;
+
+const WORDS_PER_SECTOR: usize = SECTOR_SIZE / 2;
+/// A sector count of 0 in a command means 256.
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+const LBA28_SECTORS: u64 = 1 << 28;
+const TIMEOUT_POLLS: usize = 1_000_000;
+/// How long a sector or flush may take before the command is given up.
+const INTERRUPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// words of the IDENTIFY data
+const IDENTIFY_MODEL: usize = 27;
+const IDENTIFY_MODEL_WORDS: usize = 20;
+const IDENTIFY_CAPABILITIES: usize = 49;
+const CAPABILITY_LBA: u16 = 1 << 9;
+const IDENTIFY_LBA28_SECTORS: usize = 60;
+const IDENTIFY_COMMAND_SETS: usize = 83;
+const COMMAND_SET_LBA48: u16 = 1 << 10;
+const IDENTIFY_LBA48_SECTORS: usize = 100;
+
+const NAMES: [&str; 4] = ["hda", "hdb", "hdc", "hdd"];
+
+#[derive(Debug)]
+pub enum AtaError {
+    AlreadyInitialized,
+    Irq(ApicError),
+}
+
+/// What a drive reports about itself in answer to IDENTIFY.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    pub sectors: u64,
+    pub lba48: bool,
+    model: [u8; IDENTIFY_MODEL_WORDS * 2],
+}
+
+impl Identity {
+    /// Returns `None` for drives that can't be addressed by LBA.
+    pub fn parse(words: &[u16; WORDS_PER_SECTOR]) -> Option<Self> {
+        if words[IDENTIFY_CAPABILITIES] & CAPABILITY_LBA == 0 {
+            return None;
+        }
+        let lba48 = words[IDENTIFY_COMMAND_SETS] & COMMAND_SET_LBA48 != 0;
+        let sectors = if lba48 {
+            words[IDENTIFY_LBA48_SECTORS..IDENTIFY_LBA48_SECTORS + 4]
+                .iter()
+                .rev()
+                .fold(0, |sectors, word| sectors << 16 | *word as u64)
+        } else {
+            (words[IDENTIFY_LBA28_SECTORS + 1] as u64) << 16 | words[IDENTIFY_LBA28_SECTORS] as u64
+        };
+        // each word holds two characters, the first in the high byte
+        let mut model = [0; IDENTIFY_MODEL_WORDS * 2];
+        for (chars, word) in model
+            .chunks_mut(2)
+            .zip(&words[IDENTIFY_MODEL..IDENTIFY_MODEL + IDENTIFY_MODEL_WORDS])
+        {
+            chars.copy_from_slice(&word.to_be_bytes());
+        }
+        Some(Self {
+            sectors,
+            lba48,
+            model,
+        })
+    }
+
+    pub fn model(&self) -> &str {
+        core::str::from_utf8(&self.model)
+            .unwrap_or("")
+            .trim_end_matches(&[' ', '\0'][..])
+    }
+}
+
+#[derive(Debug)]
+struct ChannelState {
+    busy: bool,
+    /// Tasks waiting for the channel.
+    waiting: Vec<Waker>,
+}
+
+/// An IDE channel, which runs one command at a time for its two drives.
+#[derive(Debug)]
+struct Channel {
+    io: u16,
+    control: u16,
+    irq: u8,
+    interrupted: AtomicBool,
+    /// The status read by the interrupt handler.
+    status: AtomicU8,
+    waker: AtomicWaker,
+    state: Mutex<ChannelState>,
+}
+
+static CHANNELS: [Channel; 2] = [
+    Channel::new(0x1f0, 0x3f6, 14),
+    Channel::new(0x170, 0x376, 15),
+];
+static DRIVES: Mutex<Vec<Arc<AtaDrive>>> = Mutex::new(Vec::new());
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Gives the channel to the next waiting task when dropped, after
+/// resetting it if a command is still running.
+struct ChannelGuard {
+    channel: &'static Channel,
+    running: bool,
+}
+
+impl Drop for ChannelGuard {
+    fn drop(&mut self) {
+        if self.running {
+            self.channel.reset();
+        }
+        without_interrupts(|| {
+            let mut state = self.channel.state.lock();
+            state.busy = false;
+            for waker in state.waiting.drain(..) {
+                waker.wake();
+            }
+        });
+    }
+}
+
+impl Channel {
+    const fn new(io: u16, control: u16, irq: u8) -> Self {
+        Self {
+            io,
+            control,
+            irq,
+            interrupted: AtomicBool::new(false),
+            status: AtomicU8::new(0),
+            waker: AtomicWaker::new(),
+            state: Mutex::new(ChannelState {
+                busy: false,
+                waiting: Vec::new(),
+            }),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + register).write(value) }
+    }
+
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control).read() }
+    }
+
+    /// Gives a drive the 400ns it needs to put its status on the bus.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    /// Aborts whatever command the drives are running.
+    fn reset(&self) {
+        let mut control = Port::<u8>::new(self.control);
+        unsafe { control.write(CONTROL_RESET) };
+        for _ in 0..RESET_HOLD_READS {
+            self.alternate_status();
+        }
+        // clearing the control register again lets the drives interrupt
+        unsafe { control.write(0) };
+        self.delay();
+        let _ = self.wait_idle(STATUS_BUSY);
+        self.interrupted.store(false, Ordering::SeqCst);
+    }
+
+    fn select(&self, drive: u8) {
+        self.write(DRIVE_SELECT, drive);
+        self.delay();
+    }
+
+    /// Waits for `mask` to be clear in the status.
+    fn wait_idle(&self, mask: u8) -> Result<u8, BlockError> {
+        for _ in 0..TIMEOUT_POLLS {
+            let status = self.alternate_status();
+            if status & mask == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Waits for the drive to ask for or offer data after a command.
+    fn wait_data(&self) -> Result<(), BlockError> {
+        let status = self.wait_idle(STATUS_BUSY)?;
+        if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 || status & STATUS_DATA_REQUEST == 0 {
+            return Err(BlockError::Io);
+        }
+        Ok(())
+    }
+
+    fn read_words(&self, words: &mut [u16]) {
+        let mut data = Port::<u16>::new(self.io + DATA);
+        for word in words {
+            *word = unsafe { data.read() };
+        }
+    }
+
+    fn write_words(&self, bytes: &[u8]) {
+        let mut data = Port::<u16>::new(self.io + DATA);
+        for word in bytes.chunks(2) {
+            unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    async fn claim(&'static self) -> ChannelGuard {
+        poll_fn(|cx| {
+            without_interrupts(|| {
+                let mut state = self.state.lock();
+                if state.busy {
+                    // a task polled again is already waiting
+                    if !state
+                        .waiting
+                        .iter()
+                        .any(|waker| waker.will_wake(cx.waker()))
+                    {
+                        state.waiting.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                } else {
+                    state.busy = true;
+                    Poll::Ready(())
+                }
+            })
+        })
+        .await;
+        ChannelGuard {
+            channel: self,
+            running: false,
+        }
+    }
+
+    /// Waits for the interrupt of the running command and returns the
+    /// status the handler read.
+    async fn interrupt(&self) -> Result<u8, BlockError> {
+        let mut timeout = timer::sleep(INTERRUPT_TIMEOUT);
+        poll_fn(|cx| {
+            if self.interrupted.swap(false, Ordering::SeqCst) {
+                return Poll::Ready(Ok(()));
+            }
+            self.waker.register(cx.waker());
+            if self.interrupted.swap(false, Ordering::SeqCst) {
+                self.waker.take();
+                Poll::Ready(Ok(()))
+            } else if Pin::new(&mut timeout).poll(cx).is_ready() {
+                self.waker.take();
+                Poll::Ready(Err(BlockError::Timeout))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await?;
+        let status = self.status.load(Ordering::SeqCst);
+        if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 {
+            Err(BlockError::Io)
+        } else {
+            Ok(status)
+        }
+    }
+
+    /// Sends an IDENTIFY to `drive` and polls for the answer.
+    fn identify(&self, drive: u8) -> Option<Identity> {
+        self.select(drive);
+        for register in &[SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+            self.write(*register, 0);
+        }
+        self.write(STATUS, IDENTIFY);
+        if self.alternate_status() == 0 {
+            return None;
+        }
+        self.wait_idle(STATUS_BUSY).ok()?;
+        // ATAPI and SATA devices identify themselves through these
+        if self.read(LBA_MID) != 0 || self.read(LBA_HIGH) != 0 {
+            return None;
+        }
+        self.wait_data().ok()?;
+        let mut words = [0; WORDS_PER_SECTOR];
+        self.read_words(&mut words);
+        Identity::parse(&words)
+    }
+}
+
+/// Records the interrupt of `channel` and wakes the task waiting for it.
+/// Called by the IRQ 14 and 15 handlers.
+pub(crate) fn on_interrupt(channel: usize) {
+    let channel = &CHANNELS[channel];
+    // reading the status lowers the interrupt
+    channel.status.store(channel.read(STATUS), Ordering::SeqCst);
+    channel.interrupted.store(true, Ordering::SeqCst);
+    channel.waker.wake();
+}
+
+#[derive(Debug)]
+pub struct AtaDrive {
+    name: &'static str,
+    channel: &'static Channel,
+    slave: bool,
+    identity: Identity,
+}
+
+impl AtaDrive {
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// Selects the drive and loads the address and count of a command.
+    fn setup(&self, sector: u64, count: usize) -> bool {
+        let channel = self.channel;
+        let slave = if self.slave { DRIVE_SLAVE } else { 0 };
+        let lba48 = self.identity.lba48 && sector + count as u64 > LBA28_SECTORS;
+        // a count of 256 is written as 0
+        let count = count as u16;
+        if lba48 {
+            channel.select(DRIVE_ALWAYS_SET | DRIVE_LBA | slave);
+            // the high bytes go first, each register keeps two
+            channel.write(SECTOR_COUNT, (count >> 8) as u8);
+            channel.write(LBA_LOW, (sector >> 24) as u8);
+            channel.write(LBA_MID, (sector >> 32) as u8);
+            channel.write(LBA_HIGH, (sector >> 40) as u8);
+        } else {
+            let high = (sector >> 24) as u8 & 0x0f;
+            channel.select(DRIVE_ALWAYS_SET | DRIVE_LBA | slave | high);
+        }
+        channel.write(SECTOR_COUNT, count as u8);
+        channel.write(LBA_LOW, sector as u8);
+        channel.write(LBA_MID, (sector >> 8) as u8);
+        channel.write(LBA_HIGH, (sector >> 16) as u8);
+        lba48
+    }
+
+    /// Issues `command`, which runs until the guard is told it is done.
+    fn command(&self, guard: &mut ChannelGuard, command: u8) {
+        guard.running = true;
+        self.channel.interrupted.store(false, Ordering::SeqCst);
+        self.channel.write(STATUS, command);
+        // the status isn't valid before then
+        self.channel.delay();
+    }
+
+    async fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_transfer(self, sector, buffer.len())?;
+        let mut guard = self.channel.claim().await;
+        let chunk_size = MAX_SECTORS_PER_COMMAND * SECTOR_SIZE;
+        for (index, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+            let start = sector + (index * MAX_SECTORS_PER_COMMAND) as u64;
+            self.channel.wait_idle(STATUS_BUSY)?;
+            let lba48 = self.setup(start, chunk.len() / SECTOR_SIZE);
+            let command = if lba48 {
+                READ_SECTORS_EXT
+            } else {
+                READ_SECTORS
+            };
+            self.command(&mut guard, command);
+            for data in chunk.chunks_mut(SECTOR_SIZE) {
+                self.channel.interrupt().await?;
+                self.channel.wait_data()?;
+                let mut words = [0; WORDS_PER_SECTOR];
+                self.channel.read_words(&mut words);
+                for (bytes, word) in data.chunks_mut(2).zip(words.iter()) {
+                    bytes.copy_from_slice(&word.to_le_bytes());
+                }
+            }
+            guard.running = false;
+        }
+        Ok(())
+    }
+
+    async fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_transfer(self, sector, buffer.len())?;
+        let mut guard = self.channel.claim().await;
+        let chunk_size = MAX_SECTORS_PER_COMMAND * SECTOR_SIZE;
+        let mut lba48 = false;
+        for (index, chunk) in buffer.chunks(chunk_size).enumerate() {
+            let start = sector + (index * MAX_SECTORS_PER_COMMAND) as u64;
+            self.channel.wait_idle(STATUS_BUSY)?;
+            let ext = self.setup(start, chunk.len() / SECTOR_SIZE);
+            lba48 |= ext;
+            let command = if ext {
+                WRITE_SECTORS_EXT
+            } else {
+                WRITE_SECTORS
+            };
+            self.command(&mut guard, command);
+            for data in chunk.chunks(SECTOR_SIZE) {
+                // the drive asks for every sector, then interrupts once it
+                // has taken it
+                self.channel.wait_data()?;
+                self.channel.write_words(data);
+                self.channel.interrupt().await?;
+            }
+            guard.running = false;
+        }
+        let command = if lba48 { FLUSH_CACHE_EXT } else { FLUSH_CACHE };
+        self.command(&mut guard, command);
+        self.channel.interrupt().await?;
+        guard.running = false;
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.identity.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(self.read_sectors(sector, buffer))
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(self.write_sectors(sector, buffer))
+    }
+}
+
+/// Identifies the drives on both channels, turns on their interrupts and
+/// registers them as `hda` to `hdd`. Returns how many were found.
+pub fn init() -> Result<usize, AtaError> {
+    if INITIALIZED.swap(true, Ordering::SeqCst) {
+        return Err(AtaError::AlreadyInitialized);
+    }
+    let mut count = 0;
+    for (index, channel) in CHANNELS.iter().enumerate() {
+        if channel.alternate_status() == FLOATING_BUS {
+            continue;
+        }
+        // clearing the control register lets the drives interrupt
+        unsafe { Port::<u8>::new(channel.control).write(0) };
+        let mut found = Vec::new();
+        for (position, slave) in [false, true].iter().enumerate() {
+            let select = DRIVE_ALWAYS_SET | if *slave { DRIVE_SLAVE } else { 0 };
+            let identity = match channel.identify(select) {
+                Some(identity) => identity,
+                None => continue,
+            };
+            let drive = Arc::new(AtaDrive {
+                name: NAMES[index * 2 + position],
+                channel,
+                slave: *slave,
+                identity,
+            });
+            log::info!("{}: {}", drive.name, identity.model());
+            found.push(drive);
+        }
+        if found.is_empty() {
+            continue;
+        }
+        // acknowledges the interrupts of the IDENTIFY commands
+        channel.read(STATUS);
+        interrupts::enable_irq(channel.irq).map_err(AtaError::Irq)?;
+        // the drives can only be used once their interrupts arrive
+        for drive in found {
+            without_interrupts(|| DRIVES.lock().push(drive.clone()));
+            block::register(drive);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// The drives `init` found.
+pub fn drives() -> Vec<Arc<AtaDrive>> {
+    without_interrupts(|| DRIVES.lock().clone())
+}
+
+#[test_case]
+fn test_identity() {
+    let mut words = [0; WORDS_PER_SECTOR];
+    words[IDENTIFY_CAPABILITIES] = CAPABILITY_LBA;
+    words[IDENTIFY_LBA28_SECTORS] = 0x0800;
+    words[IDENTIFY_LBA28_SECTORS + 1] = 0x0001;
+    for (word, chars) in words[IDENTIFY_MODEL..]
+        .iter_mut()
+        .zip(b"QEMU HARDDISK   ".chunks(2))
+    {
+        *word = u16::from_be_bytes([chars[0], chars[1]]);
+    }
+    let identity = Identity::parse(&words).unwrap();
+    assert_eq!((identity.sectors, identity.lba48), (0x1_0800, false));
+    assert_eq!(identity.model(), "QEMU HARDDISK");
+
+    words[IDENTIFY_COMMAND_SETS] = COMMAND_SET_LBA48;
+    words[IDENTIFY_LBA48_SECTORS + 2] = 1;
+    assert_eq!(Identity::parse(&words).unwrap().sectors, 1 << 32);
+
+    words[IDENTIFY_CAPABILITIES] = 0;
+    assert_eq!(Identity::parse(&words), None);
+}
@@ -0,0 +1,85 @@
+//! Block devices, read and written a sector at a time through futures the
+//! drivers complete from their interrupt handlers.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The buffer isn't a whole number of sectors.
+    BadBufferSize(usize),
+    /// The transfer reaches past the last sector.
+    OutOfRange(u64),
+    ReadOnly,
+    Unsupported,
+    /// The device reported an error.
+    Io,
+    Timeout,
+}
+
+pub type BlockFuture<'a> = Pin<Box<dyn Future<Output = Result<(), BlockError>> + 'a>>;
+
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// The size of the device in sectors of `SECTOR_SIZE` bytes.
+    fn sector_count(&self) -> u64;
+
+    fn read_only(&self) -> bool;
+
+    /// Reads the sectors from `sector` on into `buffer`.
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a>;
+
+    /// Writes `buffer` to the sectors from `sector` on.
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a>;
+}
+
+/// Checks that `len` bytes from `sector` on fit `device` and returns the
+/// number of sectors.
+pub fn check_transfer(
+    device: &dyn BlockDevice,
+    sector: u64,
+    len: usize,
+) -> Result<u64, BlockError> {
+    let count = (len / SECTOR_SIZE) as u64;
+    if count as usize * SECTOR_SIZE != len {
+        return Err(BlockError::BadBufferSize(len));
+    }
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange(sector)),
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+/// Makes `device` available to the rest of the kernel.
+pub fn register(device: Arc<dyn BlockDevice>) {
+    log::info!(
+        "block device {}: {} sectors{}",
+        device.name(),
+        device.sector_count(),
+        if device.read_only() {
+            ", read-only"
+        } else {
+            ""
+        }
+    );
+    interrupts::without_interrupts(|| DEVICES.lock().push(device));
+}
+
+/// The registered devices, in the order the drivers found them.
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    interrupts::without_interrupts(|| DEVICES.lock().clone())
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    devices().into_iter().find(|device| device.name() == name)
+}
@@ -0,0 +1,183 @@
+//! The kernel message buffer. Every line printed to the screen and every
+//! log record ends up here with a sequence number and a timestamp, so
+//! output that scrolled away can still be read back or dumped on a panic.
+//!
+//! The buffer is a fixed array of slots guarded by per-slot sequence
+//! counters and never blocks, so it can be written from any context. A
+//! record that gets overwritten while it is read is skipped.
+
+use crate::{serial, time};
+use core::fmt::{self, Write};
+use core::sync::atomic::{fence, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+pub const CAPACITY: usize = 512;
+/// Longer lines are cut off.
+pub const LINE_LEN: usize = 160;
+
+struct Slot {
+    /// `2 * seq + 2` once the record `seq` is complete, odd while a record
+    /// is being written and zero if the slot was never used.
+    state: AtomicU64,
+    timestamp: AtomicU64,
+    len: AtomicUsize,
+    text: [AtomicU8; LINE_LEN],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = {
+    const ZERO: AtomicU8 = AtomicU8::new(0);
+    Slot {
+        state: AtomicU64::new(0),
+        timestamp: AtomicU64::new(0),
+        len: AtomicUsize::new(0),
+        text: [ZERO; LINE_LEN],
+    }
+};
+
+static SLOTS: [Slot; CAPACITY] = [EMPTY_SLOT; CAPACITY];
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// Copies formatted text into a slot, cutting it off at `LINE_LEN`.
+struct SlotWriter<'a> {
+    slot: &'a Slot,
+    len: usize,
+}
+
+impl Write for SlotWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(LINE_LEN - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        for (i, byte) in s.as_bytes()[..end].iter().enumerate() {
+            self.slot.text[self.len + i].store(*byte, Ordering::Relaxed);
+        }
+        self.len += end;
+        Ok(())
+    }
+}
+
+/// Appends a line and returns its sequence number. The text should not
+/// contain a newline.
+pub fn push(text: &str) -> u64 {
+    push_fmt(format_args!("{}", text))
+}
+
+pub fn push_fmt(args: fmt::Arguments) -> u64 {
+    push_at(time::uptime(), args)
+}
+
+/// Appends a line with the given time since boot.
+pub fn push_at(timestamp: Duration, args: fmt::Arguments) -> u64 {
+    let seq = NEXT.fetch_add(1, Ordering::Relaxed);
+    let slot = &SLOTS[seq as usize % CAPACITY];
+    slot.state.store(2 * seq + 1, Ordering::Relaxed);
+    fence(Ordering::Release);
+    let mut writer = SlotWriter { slot, len: 0 };
+    let _ = writer.write_fmt(args);
+    slot.len.store(writer.len, Ordering::Relaxed);
+    slot.timestamp
+        .store(timestamp.as_nanos() as u64, Ordering::Relaxed);
+    slot.state.store(2 * seq + 2, Ordering::Release);
+    seq
+}
+
+/// The sequence number the next line will get.
+pub fn next_seq() -> u64 {
+    NEXT.load(Ordering::Relaxed)
+}
+
+pub struct Record {
+    pub seq: u64,
+    /// Time since boot.
+    pub timestamp: Duration,
+    text: [u8; LINE_LEN],
+    len: usize,
+}
+
+impl Record {
+    pub fn text(&self) -> &str {
+        let bytes = &self.text[..self.len];
+        match core::str::from_utf8(bytes) {
+            Ok(text) => text,
+            // a torn write can leave a partial character
+            Err(err) => core::str::from_utf8(&bytes[..err.valid_up_to()]).unwrap(),
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:5}.{:06}] {}",
+            self.timestamp.as_secs(),
+            self.timestamp.subsec_micros(),
+            self.text()
+        )
+    }
+}
+
+/// Returns the record `seq` unless it was overwritten or is still being
+/// written.
+pub fn read(seq: u64) -> Option<Record> {
+    let slot = &SLOTS[seq as usize % CAPACITY];
+    let state = slot.state.load(Ordering::Acquire);
+    if state != 2 * seq + 2 {
+        return None;
+    }
+    let mut record = Record {
+        seq,
+        timestamp: Duration::from_nanos(slot.timestamp.load(Ordering::Relaxed)),
+        text: [0; LINE_LEN],
+        len: slot.len.load(Ordering::Relaxed).min(LINE_LEN),
+    };
+    for (byte, stored) in record.text.iter_mut().zip(slot.text.iter()) {
+        *byte = stored.load(Ordering::Relaxed);
+    }
+    fence(Ordering::Acquire);
+    if slot.state.load(Ordering::Relaxed) != state {
+        return None;
+    }
+    Some(record)
+}
+
+/// Iterates over the records from `seq` on that are still buffered.
+pub fn since(seq: u64) -> impl Iterator<Item = Record> {
+    let end = next_seq();
+    let start = seq.max(end.saturating_sub(CAPACITY as u64));
+    (start..end).filter_map(read)
+}
+
+/// Iterates over all buffered records, oldest first.
+pub fn records() -> impl Iterator<Item = Record> {
+    since(0)
+}
+
+pub fn dump(out: &mut impl Write) -> fmt::Result {
+    for record in records() {
+        writeln!(out, "{}", record)?;
+    }
+    Ok(())
+}
+
+pub fn dump_to_serial() {
+    interrupts::without_interrupts(|| {
+        let _ = dump(&mut *serial::SERIAL1.lock());
+    });
+}
+
+/// Dumps the buffer even if the panicking code held the serial port. Only
+/// for panic handlers: the lock is forced open, which is sound for a holder
+/// on the panicking CPU since it never resumes, but another CPU still
+/// writing through its guard can interleave its output with the dump.
+pub fn dump_on_panic() {
+    interrupts::disable();
+    if serial::SERIAL1.try_lock().is_none() {
+        unsafe { serial::SERIAL1.force_unlock() };
+    }
+    serial::_print(format_args!("--- kernel messages ---\n"));
+    dump_to_serial();
+}
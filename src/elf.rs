@@ -0,0 +1,183 @@
+pub mod loader;
+pub mod programs;
+
+pub use loader::{load, spawn, LoadError, LoadedProgram};
+
+use core::convert::TryInto;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_VERSION_CURRENT: u8 = 1;
+const ELF_TYPE_EXECUTABLE: u16 = 2;
+const ELF_MACHINE_X86_64: u16 = 0x3e;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    BadVersion,
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeaderSize,
+    ProgramHeadersOutOfBounds,
+    SegmentOutOfBounds,
+    SegmentFileSizeTooLarge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.p_type == PT_LOAD
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+/// A validated view of an ELF64 executable for x86_64.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: u64,
+    ph_offset: usize,
+    ph_entry_size: usize,
+    ph_count: usize,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELF_CLASS_64 {
+            return Err(ElfError::NotElf64);
+        }
+        if data[5] != ELF_DATA_LITTLE_ENDIAN {
+            return Err(ElfError::NotLittleEndian);
+        }
+        if data[6] != ELF_VERSION_CURRENT || read_u32(data, 20) != ELF_VERSION_CURRENT as u32 {
+            return Err(ElfError::BadVersion);
+        }
+        if read_u16(data, 16) != ELF_TYPE_EXECUTABLE {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18) != ELF_MACHINE_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let ph_offset = read_u64(data, 32) as usize;
+        let ph_entry_size = read_u16(data, 54) as usize;
+        let ph_count = read_u16(data, 56) as usize;
+        if ph_count > 0 && ph_entry_size < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeaderSize);
+        }
+        let ph_end = ph_entry_size
+            .checked_mul(ph_count)
+            .and_then(|size| size.checked_add(ph_offset))
+            .ok_or(ElfError::ProgramHeadersOutOfBounds)?;
+        if ph_end > data.len() {
+            return Err(ElfError::ProgramHeadersOutOfBounds);
+        }
+
+        let elf = Self {
+            data,
+            entry: read_u64(data, 24),
+            ph_offset,
+            ph_entry_size,
+            ph_count,
+        };
+        for header in elf.program_headers() {
+            if header.file_size > header.mem_size {
+                return Err(ElfError::SegmentFileSizeTooLarge);
+            }
+            let end = header
+                .offset
+                .checked_add(header.file_size)
+                .ok_or(ElfError::SegmentOutOfBounds)?;
+            if end > data.len() as u64 {
+                return Err(ElfError::SegmentOutOfBounds);
+            }
+        }
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.ph_count).map(move |idx| {
+            let base = self.ph_offset + idx * self.ph_entry_size;
+            ProgramHeader {
+                p_type: read_u32(self.data, base),
+                flags: read_u32(self.data, base + 4),
+                offset: read_u64(self.data, base + 8),
+                vaddr: read_u64(self.data, base + 16),
+                file_size: read_u64(self.data, base + 32),
+                mem_size: read_u64(self.data, base + 40),
+                align: read_u64(self.data, base + 48),
+            }
+        })
+    }
+
+    /// Returns the bytes of a segment stored in the file.
+    pub fn segment_data(&self, header: &ProgramHeader) -> &'a [u8] {
+        let start = header.offset as usize;
+        &self.data[start..start + header.file_size as usize]
+    }
+}
+
+#[test_case]
+fn test_parse_rejects_garbage() {
+    assert_eq!(ElfFile::parse(&[0; 16]).err(), Some(ElfError::TooShort));
+    assert_eq!(ElfFile::parse(&[0; 64]).err(), Some(ElfError::BadMagic));
+}
+
+#[test_case]
+fn test_parse_bundled_program() {
+    let elf = ElfFile::parse(programs::HELLO).expect("bundled program should parse");
+    assert!(elf
+        .program_headers()
+        .any(|h| h.is_load() && h.is_executable()));
+}
@@ -0,0 +1,161 @@
+use super::{ElfError, ElfFile, ProgramHeader};
+use crate::memory::region::RegionError;
+use crate::memory::stack::StackError;
+use crate::memory::{AddressSpace, Region, RegionKind, USER_SPACE_END, USER_SPACE_START};
+use crate::{thread, usermode};
+use alloc::sync::Arc;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: u64 = Page::<Size4KiB>::SIZE;
+
+/// The user stack ends one unmapped page below the end of user space.
+pub const USER_STACK_TOP: u64 = USER_SPACE_END - PAGE_SIZE;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Elf(ElfError),
+    SegmentOutsideUserSpace,
+    EntryNotExecutable,
+    Map(MapToError<Size4KiB>),
+    Region(RegionError),
+    Stack(StackError),
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> Self {
+        LoadError::Elf(err)
+    }
+}
+
+impl From<RegionError> for LoadError {
+    fn from(err: RegionError) -> Self {
+        LoadError::Region(err)
+    }
+}
+
+impl From<StackError> for LoadError {
+    fn from(err: StackError) -> Self {
+        LoadError::Stack(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for LoadError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        LoadError::Map(err)
+    }
+}
+
+/// A program mapped into its own address space, ready to be entered.
+pub struct LoadedProgram {
+    pub entry: VirtAddr,
+    pub stack_top: VirtAddr,
+    pub address_space: AddressSpace,
+}
+
+fn check_segment(header: &ProgramHeader) -> Result<(), LoadError> {
+    let end = header
+        .vaddr
+        .checked_add(header.mem_size)
+        .ok_or(LoadError::SegmentOutsideUserSpace)?;
+    if header.vaddr < USER_SPACE_START
+        || end > USER_STACK_TOP - usermode::USER_STACK_PAGES * PAGE_SIZE
+    {
+        return Err(LoadError::SegmentOutsideUserSpace);
+    }
+    Ok(())
+}
+
+fn segment_flags(header: &ProgramHeader) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if header.is_writable() {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !header.is_executable() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn load_segment(
+    elf: &ElfFile,
+    header: &ProgramHeader,
+    address_space: &AddressSpace,
+) -> Result<(), LoadError> {
+    check_segment(header)?;
+    if header.mem_size == 0 {
+        return Ok(());
+    }
+    let flags = segment_flags(header);
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(header.vaddr));
+    let last =
+        Page::<Size4KiB>::containing_address(VirtAddr::new(header.vaddr + header.mem_size - 1));
+    for page in Page::range_inclusive(first, last) {
+        // segments sharing a page get the union of their permissions
+        match address_space.flags(page) {
+            Some(existing) => {
+                let mut merged = existing | flags;
+                if !(existing & flags).contains(PageTableFlags::NO_EXECUTE) {
+                    merged.remove(PageTableFlags::NO_EXECUTE);
+                }
+                address_space
+                    .protect(Page::range(page, page + 1), merged)
+                    .expect("mapped page has no level 1 entry");
+            }
+            None => address_space.map(Page::range(page, page + 1), flags)?,
+        }
+    }
+    // the rest of the segment stays zeroed
+    let copied = address_space.write(VirtAddr::new(header.vaddr), elf.segment_data(header));
+    assert!(copied, "segment was not mapped");
+    Ok(())
+}
+
+/// Parses `image` and maps its loadable segments and a user stack into a
+/// fresh address space.
+pub fn load(image: &[u8]) -> Result<LoadedProgram, LoadError> {
+    let elf = ElfFile::parse(image)?;
+    let entry_in_code = elf.program_headers().any(|h| {
+        h.is_load()
+            && h.is_executable()
+            && h.vaddr <= elf.entry()
+            && elf.entry() < h.vaddr.saturating_add(h.mem_size)
+    });
+    if !entry_in_code {
+        return Err(LoadError::EntryNotExecutable);
+    }
+
+    let address_space = AddressSpace::new()?;
+    for header in elf.program_headers().filter(ProgramHeader::is_load) {
+        load_segment(&elf, &header, &address_space)?;
+    }
+    // stack pages are mapped as the program touches them
+    let stack_size = usermode::USER_STACK_PAGES * PAGE_SIZE;
+    address_space.add_region(Region::new(
+        VirtAddr::new(USER_STACK_TOP - stack_size),
+        stack_size,
+        PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        RegionKind::Stack,
+    ))?;
+
+    Ok(LoadedProgram {
+        entry: VirtAddr::new(elf.entry()),
+        stack_top: VirtAddr::new(USER_STACK_TOP),
+        address_space,
+    })
+}
+
+/// Loads `image` and runs it in ring 3 on a new thread. Joining the
+/// returned handle yields the program's exit code.
+pub fn spawn(image: &[u8]) -> Result<thread::JoinHandle<u64>, LoadError> {
+    let LoadedProgram {
+        entry,
+        stack_top,
+        address_space,
+    } = load(image)?;
+    let handle = thread::spawn_in(Arc::new(address_space), move || {
+        usermode::run_user(entry, stack_top)
+    })?;
+    Ok(handle)
+}
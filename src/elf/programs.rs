@@ -0,0 +1,10 @@
+//! Test programs assembled from `user/` and bundled into the kernel image.
+
+/// Prints a greeting and exits with code 0.
+pub static HELLO: &[u8] = include_bytes!("../../user/bin/hello.elf");
+
+/// Fills a `.bss` array with 1..=512 and exits with its sum, 131328.
+pub static COUNTER: &[u8] = include_bytes!("../../user/bin/counter.elf");
+
+/// Touches lazily mapped stack and mmap pages and exits with 12.
+pub static LAZY: &[u8] = include_bytes!("../../user/bin/lazy.elf");
@@ -0,0 +1,293 @@
+//! A linear framebuffer on the Bochs graphics adapter (BGA), which QEMU's
+//! standard VGA and VirtualBox provide, with simple drawing primitives. The
+//! text console can be drawn on it with `vga_buffer::use_framebuffer`.
+
+pub mod font;
+
+use crate::memory;
+use crate::pci::{self, Bar, DeviceMatch};
+use crate::vga_buffer::{self, Color};
+use conquer_once::spin::OnceCell;
+use core::convert::TryFrom;
+use font::{GLYPH_HEIGHT, GLYPH_WIDTH};
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const DEFAULT_WIDTH: usize = 640;
+pub const DEFAULT_HEIGHT: usize = 480;
+
+const BGA_INDEX: u16 = 0x01ce;
+const BGA_DATA: u16 = 0x01cf;
+const BGA_ID: u16 = 0;
+const BGA_WIDTH: u16 = 1;
+const BGA_HEIGHT: u16 = 2;
+const BGA_BPP: u16 = 3;
+const BGA_ENABLE: u16 = 4;
+const BGA_ENABLED: u16 = 0x01;
+const BGA_LINEAR_FRAMEBUFFER: u16 = 0x40;
+/// Interface versions from the first one with 32 bits per pixel.
+const BGA_VERSIONS: core::ops::RangeInclusive<u16> = 0xb0c2..=0xb0cf;
+const BITS_PER_PIXEL: u16 = 32;
+const BYTES_PER_PIXEL: usize = 4;
+
+/// Vendor and device IDs of the QEMU and VirtualBox adapters.
+const BGA_PCI_IDS: [(u16, u16); 2] = [(0x1234, 0x1111), (0x80ee, 0xbeef)];
+
+#[derive(Debug)]
+pub enum FramebufferError {
+    AlreadyInitialized,
+    NoAdapter,
+    NoLinearFramebuffer,
+    UnsupportedMode { width: usize, height: usize },
+    Map(MapToError<Size4KiB>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    fn to_pixel(self) -> u32 {
+        (self.r as u32) << 16 | (self.g as u32) << 8 | self.b as u32
+    }
+
+    fn from_pixel(pixel: u32) -> Self {
+        Self::new((pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8)
+    }
+}
+
+/// The colors of the text mode attributes.
+pub const PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00),
+    Rgb::new(0x00, 0x00, 0xaa),
+    Rgb::new(0x00, 0xaa, 0x00),
+    Rgb::new(0x00, 0xaa, 0xaa),
+    Rgb::new(0xaa, 0x00, 0x00),
+    Rgb::new(0xaa, 0x00, 0xaa),
+    Rgb::new(0xaa, 0x55, 0x00),
+    Rgb::new(0xaa, 0xaa, 0xaa),
+    Rgb::new(0x55, 0x55, 0x55),
+    Rgb::new(0x55, 0x55, 0xff),
+    Rgb::new(0x55, 0xff, 0x55),
+    Rgb::new(0x55, 0xff, 0xff),
+    Rgb::new(0xff, 0x55, 0x55),
+    Rgb::new(0xff, 0x55, 0xff),
+    Rgb::new(0xff, 0xff, 0x55),
+    Rgb::new(0xff, 0xff, 0xff),
+];
+
+impl From<Color> for Rgb {
+    fn from(color: Color) -> Self {
+        PALETTE[color as usize]
+    }
+}
+
+/// A mapped 32 bits per pixel framebuffer. Drawing is clipped to its size.
+#[derive(Debug, Clone, Copy)]
+pub struct Framebuffer {
+    base: VirtAddr,
+    width: usize,
+    height: usize,
+}
+
+impl Framebuffer {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn pixel_ptr(&self, x: usize, y: usize) -> *mut u32 {
+        (self.base + (y * self.width + x) * BYTES_PER_PIXEL).as_mut_ptr()
+    }
+
+    pub fn put_pixel(&self, x: usize, y: usize, color: Rgb) {
+        if x < self.width && y < self.height {
+            unsafe { self.pixel_ptr(x, y).write_volatile(color.to_pixel()) };
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        if x < self.width && y < self.height {
+            Some(Rgb::from_pixel(unsafe {
+                self.pixel_ptr(x, y).read_volatile()
+            }))
+        } else {
+            None
+        }
+    }
+
+    pub fn fill_rect(&self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let pixel = color.to_pixel();
+        let right = x.saturating_add(width).min(self.width);
+        let bottom = y.saturating_add(height).min(self.height);
+        for y in y..bottom {
+            for x in x..right {
+                unsafe { self.pixel_ptr(x, y).write_volatile(pixel) };
+            }
+        }
+    }
+
+    pub fn clear(&self, color: Rgb) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    /// Draws a line between two points, both included.
+    pub fn draw_line(&self, from: (usize, usize), to: (usize, usize), color: Rgb) {
+        let (mut x, mut y) = (from.0 as isize, from.1 as isize);
+        let (x1, y1) = (to.0 as isize, to.1 as isize);
+        let dx = (x1 - x).abs();
+        let dy = -(y1 - y).abs();
+        let step_x = if x < x1 { 1 } else { -1 };
+        let step_y = if y < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        loop {
+            self.put_pixel(x as usize, y as usize, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    /// Copies an image of rows `width` pixels long to `x`, `y`.
+    pub fn blit(&self, x: usize, y: usize, width: usize, pixels: &[Rgb]) {
+        if width == 0 {
+            return;
+        }
+        for (row, line) in pixels.chunks(width).enumerate() {
+            for (col, color) in line.iter().enumerate() {
+                self.put_pixel(x + col, y + row, *color);
+            }
+        }
+    }
+
+    /// Draws the code page 437 character `byte` with its top left corner
+    /// at `x`, `y`.
+    pub fn draw_glyph(&self, x: usize, y: usize, byte: u8, foreground: Rgb, background: Rgb) {
+        for (row, bits) in font::glyph(byte).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                let color = if bits & (1 << col) != 0 {
+                    foreground
+                } else {
+                    background
+                };
+                self.put_pixel(x + col, y + row, color);
+            }
+        }
+    }
+
+    /// Draws `text` in a single line without interpreting control
+    /// characters.
+    pub fn draw_text(&self, x: usize, y: usize, text: &str, foreground: Rgb, background: Rgb) {
+        for (i, ch) in text.chars().enumerate() {
+            let byte = vga_buffer::to_cp437(ch);
+            self.draw_glyph(x + i * GLYPH_WIDTH, y, byte, foreground, background);
+        }
+    }
+
+    /// The top left pixel of the character cell at `column`, `row`.
+    pub const fn cell_position(column: usize, row: usize) -> (usize, usize) {
+        (column * GLYPH_WIDTH, row * GLYPH_HEIGHT)
+    }
+}
+
+static FRAMEBUFFER: OnceCell<Framebuffer> = OnceCell::uninit();
+
+fn bga_read(register: u16) -> u16 {
+    let mut index = Port::<u16>::new(BGA_INDEX);
+    let mut data = Port::<u16>::new(BGA_DATA);
+    unsafe {
+        index.write(register);
+        data.read()
+    }
+}
+
+fn bga_write(register: u16, value: u16) {
+    let mut index = Port::<u16>::new(BGA_INDEX);
+    let mut data = Port::<u16>::new(BGA_DATA);
+    unsafe {
+        index.write(register);
+        data.write(value);
+    }
+}
+
+/// The physical address of the adapter's framebuffer from the first
+/// memory BAR of its PCI function.
+fn find_linear_framebuffer() -> Option<PhysAddr> {
+    BGA_PCI_IDS
+        .iter()
+        .flat_map(|(vendor, device)| pci::find(DeviceMatch::id(*vendor, *device)))
+        .find_map(|device| match device.bars[0] {
+            Some(Bar::Memory { address, .. }) => Some(address),
+            _ => None,
+        })
+}
+
+/// Switches the adapter to a `width` by `height` mode with 32 bits per
+/// pixel and maps its framebuffer. The adapter is looked up among the
+/// devices found by `pci::init`.
+pub fn init(width: usize, height: usize) -> Result<Framebuffer, FramebufferError> {
+    if FRAMEBUFFER.is_initialized() {
+        return Err(FramebufferError::AlreadyInitialized);
+    }
+    if !BGA_VERSIONS.contains(&bga_read(BGA_ID)) {
+        return Err(FramebufferError::NoAdapter);
+    }
+    let phys = find_linear_framebuffer().ok_or(FramebufferError::NoLinearFramebuffer)?;
+    let unsupported = FramebufferError::UnsupportedMode { width, height };
+    let (mode_width, mode_height) = match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(mode_width), Ok(mode_height)) if width > 0 && height > 0 => (mode_width, mode_height),
+        _ => return Err(unsupported),
+    };
+
+    bga_write(BGA_ENABLE, 0);
+    bga_write(BGA_WIDTH, mode_width);
+    bga_write(BGA_HEIGHT, mode_height);
+    bga_write(BGA_BPP, BITS_PER_PIXEL);
+    bga_write(BGA_ENABLE, BGA_ENABLED | BGA_LINEAR_FRAMEBUFFER);
+    // the adapter ignores modes it doesn't support
+    if bga_read(BGA_WIDTH) != mode_width
+        || bga_read(BGA_HEIGHT) != mode_height
+        || bga_read(BGA_BPP) != BITS_PER_PIXEL
+    {
+        bga_write(BGA_ENABLE, 0);
+        return Err(unsupported);
+    }
+
+    let size = (width * height * BYTES_PER_PIXEL) as u64;
+    let base = memory::map_mmio(phys, size).map_err(FramebufferError::Map)?;
+    let framebuffer = Framebuffer {
+        base,
+        width,
+        height,
+    };
+    FRAMEBUFFER
+        .try_init_once(|| framebuffer)
+        .map_err(|_| FramebufferError::AlreadyInitialized)?;
+    framebuffer.clear(PALETTE[Color::Black as usize]);
+    Ok(framebuffer)
+}
+
+pub fn get() -> Option<Framebuffer> {
+    FRAMEBUFFER.try_get().ok().copied()
+}
@@ -1,3 +1,7 @@
+use crate::memory::stack::{KernelStack, StackError, StackKind};
+use crate::percpu;
+use alloc::boxed::Box;
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::tss::TaskStateSegment;
@@ -5,47 +9,107 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_STACK_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_STACK_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            stack_start + STACK_SIZE
-        };
-        tss
-    };
-}
+const STACK_SIZE: usize = 4096 * 5;
+
+// The static stacks are only used until `install_guarded_stacks` replaces
+// them with stacks that have guard pages.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+static mut DOUBLE_FAULT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+static mut PRIVILEGE_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = build(unsafe { &*addr_of!(TSS) });
+}
+
+// Every CPU gets a GDT with the same layout, so the selectors of the
+// bootstrap processor are valid everywhere.
+fn build(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_code_selector,
+            user_data_selector,
+            tss_selector,
+        },
+    )
 }
 
 pub struct Selectors {
     code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
-    GDT.0.load();
+    gdt.0.load();
+    unsafe {
+        CS::set_reg(gdt.1.code_selector);
+        SS::set_reg(gdt.1.data_selector);
+        DS::set_reg(gdt.1.data_selector);
+        ES::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
+    }
+}
+
+/// Loads the GDT and TSS of the bootstrap processor and sets up its
+/// per-CPU data.
+pub fn init() {
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        TSS.interrupt_stack_table[DOUBLE_FAULT_STACK_INDEX as usize] =
+            VirtAddr::from_ptr(addr_of!(DOUBLE_FAULT_STACK)) + STACK_SIZE;
+        TSS.privilege_stack_table[0] = VirtAddr::from_ptr(addr_of!(PRIVILEGE_STACK)) + STACK_SIZE;
     }
+    load(&GDT);
+    percpu::install_bsp().set_tss(addr_of_mut!(TSS));
+}
+
+/// Gives an application processor its own GDT and a TSS with guarded
+/// interrupt stacks. Must run on that processor after `percpu::install`.
+pub fn init_ap() -> Result<(), StackError> {
+    let tss: *mut TaskStateSegment = Box::leak(Box::new(TaskStateSegment::new()));
+    unsafe { allocate_stacks(&mut *tss)? };
+    percpu::current().set_tss(tss);
+    load(Box::leak(Box::new(build(unsafe { &*tss }))));
+    Ok(())
+}
+
+/// Returns the ring 3 code and data selectors.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
+/// Sets the stack the current CPU switches to when an interrupt or system
+/// call arrives while running in ring 3.
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe { (*percpu::current().tss()).privilege_stack_table[0] = stack_top };
+}
+
+fn allocate_stacks(tss: &mut TaskStateSegment) -> Result<(), StackError> {
+    let pages = (STACK_SIZE / 4096) as u64;
+    let double_fault = KernelStack::allocate(pages, StackKind::DoubleFault)?;
+    let privilege = KernelStack::allocate(pages, StackKind::Privilege)?;
+    tss.interrupt_stack_table[DOUBLE_FAULT_STACK_INDEX as usize] = double_fault.leak();
+    tss.privilege_stack_table[0] = privilege.leak();
+    Ok(())
+}
+
+/// Moves the interrupt and privilege stacks of the bootstrap processor into
+/// guarded kernel stacks. Must be called once memory management has been
+/// installed.
+pub fn install_guarded_stacks() -> Result<(), StackError> {
+    unsafe { allocate_stacks(&mut *addr_of_mut!(TSS)) }
 }
@@ -1,13 +1,16 @@
 #![allow(clippy::borrow_interior_mutable_const)]
 
 use crate::gdt::DOUBLE_FAULT_STACK_INDEX;
+use crate::memory::fault::DecodedErrorCode;
+use crate::println;
+use crate::ps2::{self, Ps2Port};
 use crate::task::keyboard::push_scancode;
-use crate::{hlt_loop, print, println};
+use crate::{apic, ata, memory, percpu, rtc, serial, syscall, time};
 use core::panic;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin::Mutex;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::PrivilegeLevel;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -26,7 +29,28 @@ lazy_static! {
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Com1.as_usize()].set_handler_fn(com1_interrupt_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()]
+            .set_handler_fn(secondary_ata_interrupt_handler);
+        idt[InterruptIndex::Irq5.as_usize()].set_handler_fn(irq5_interrupt_handler);
+        idt[InterruptIndex::Irq9.as_usize()].set_handler_fn(irq9_interrupt_handler);
+        idt[InterruptIndex::Irq10.as_usize()].set_handler_fn(irq10_interrupt_handler);
+        idt[InterruptIndex::Irq11.as_usize()].set_handler_fn(irq11_interrupt_handler);
+        idt[InterruptIndex::ApicTimer.as_usize()].set_handler_fn(apic_timer_interrupt_handler);
+        idt[InterruptIndex::Wakeup.as_usize()].set_handler_fn(wakeup_interrupt_handler);
+        idt[InterruptIndex::ApicSpurious.as_usize()].set_handler_fn(spurious_interrupt_handler);
+        // Page faults stay on the current stack so that they can nest. One
+        // that can't be delivered there because the stack overflowed into its
+        // guard page becomes a double fault.
         idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt[syscall::SYSCALL_VECTOR]
+                .set_handler_addr(syscall::entry_address())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
         idt
     };
 }
@@ -36,6 +60,7 @@ pub fn init_idt() {
 }
 
 pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
     println!("Exception Breakpoint\n{:#?}", stack_frame);
 }
 
@@ -43,6 +68,18 @@ pub extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    percpu::restore_gs(stack_frame.code_segment);
+    let addr = Cr2::read();
+    if let Some(stack) = memory::stack::guard_hit(addr) {
+        panic!(
+            "STACK OVERFLOW\ndouble fault at {:#x} in the guard page of the {}\n{:#?}",
+            addr.as_u64(),
+            stack,
+            stack_frame
+        );
+    }
     panic!("DOUBLE FAULT EXCEPTION\n{:#?}", stack_frame);
 }
 
@@ -51,53 +88,190 @@ pub extern "x86-interrupt" fn double_fault_handler(
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// IRQ 4, the first serial port.
+    Com1 = PIC_1_OFFSET + 4,
+    /// IRQ 5, 9, 10 and 11, which the firmware routes PCI interrupts to.
+    Irq5,
+    /// IRQ 8, the first line of the slave PIC.
+    Rtc = PIC_2_OFFSET,
+    Irq9,
+    Irq10,
+    Irq11,
+    /// IRQ 12, the second PS/2 port.
+    Mouse = PIC_2_OFFSET + 4,
+    /// IRQ 14 and 15, the primary and secondary IDE channels.
+    PrimaryAta = PIC_2_OFFSET + 6,
+    SecondaryAta,
+    /// Local APIC timer, past the vectors of the remapped PICs.
+    ApicTimer = PIC_2_OFFSET + 8,
+    /// Sent between CPUs to wake an idle executor.
+    Wakeup,
+    ApicSpurious = 0xff,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub const fn as_u8(self) -> u8 {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
+    pub const fn as_usize(self) -> usize {
         self as usize
     }
 }
 
-pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+/// Acknowledges `index` at whichever interrupt controller is in use.
+pub fn end_of_interrupt(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
     }
 }
 
-pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use x86_64::instructions::port::Port;
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-            Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-        );
+/// Unmasks the legacy ISA `irq` and delivers it to vector `PIC_1_OFFSET + irq`.
+pub fn enable_irq(irq: u8) -> Result<(), apic::ApicError> {
+    if apic::is_enabled() {
+        return apic::route_irq(irq, PIC_1_OFFSET + irq);
     }
+    let pics = PICS;
+    let mut pics = pics.lock();
+    let [mut mask_1, mut mask_2] = unsafe { pics.read_masks() };
+    if irq < 8 {
+        mask_1 &= !(1 << irq);
+    } else {
+        mask_1 &= !(1 << 2);
+        mask_2 &= !(1 << (irq - 8));
+    }
+    unsafe { pics.write_masks(mask_1, mask_2) };
+    Ok(())
+}
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    push_scancode(scancode);
-    /*
-        let mut keyboard = KEYBOARD.lock();
-        if let Ok(Some(keyevent)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(keyevent) {
-                match key {
-                    DecodedKey::Unicode(ch) => print!("{}", ch),
-                    DecodedKey::RawKey(k) => print!("{:?}", k),
-                }
-            }
-        }
-    */
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+/// The IRQs PCI devices can be wired to. Devices share them, so every
+/// handler of a line runs and checks its own device.
+pub const SHARED_IRQS: [u8; 4] = [5, 9, 10, 11];
+const MAX_SHARED_HANDLERS: usize = 4;
+
+#[derive(Debug)]
+pub enum IrqError {
+    /// The IRQ isn't one of `SHARED_IRQS`.
+    NotShared(u8),
+    TooManyHandlers(u8),
+    Route(apic::ApicError),
+}
+
+type SharedHandlers = [Option<fn()>; MAX_SHARED_HANDLERS];
+
+/// Only locked with interrupts disabled.
+static SHARED_HANDLERS: [spin::Mutex<SharedHandlers>; SHARED_IRQS.len()] = [
+    spin::Mutex::new([None; MAX_SHARED_HANDLERS]),
+    spin::Mutex::new([None; MAX_SHARED_HANDLERS]),
+    spin::Mutex::new([None; MAX_SHARED_HANDLERS]),
+    spin::Mutex::new([None; MAX_SHARED_HANDLERS]),
+];
+
+/// Runs `handler` on every interrupt of the shared `irq` and enables the
+/// IRQ.
+pub fn add_shared_handler(irq: u8, handler: fn()) -> Result<(), IrqError> {
+    let line = SHARED_IRQS
+        .iter()
+        .position(|shared| *shared == irq)
+        .ok_or(IrqError::NotShared(irq))?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = SHARED_HANDLERS[line].lock();
+        let free = handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::TooManyHandlers(irq))?;
+        *free = Some(handler);
+        Ok(())
+    })?;
+    enable_irq(irq).map_err(IrqError::Route)
+}
+
+fn shared_interrupt(line: usize, index: InterruptIndex) {
+    let handlers = *SHARED_HANDLERS[line].lock();
+    for handler in handlers.iter().flatten() {
+        handler();
     }
+    end_of_interrupt(index);
+}
+
+pub extern "x86-interrupt" fn irq5_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    shared_interrupt(0, InterruptIndex::Irq5);
+}
+
+pub extern "x86-interrupt" fn irq9_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    shared_interrupt(1, InterruptIndex::Irq9);
+}
+
+pub extern "x86-interrupt" fn irq10_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    shared_interrupt(2, InterruptIndex::Irq10);
+}
+
+pub extern "x86-interrupt" fn irq11_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    shared_interrupt(3, InterruptIndex::Irq11);
+}
+
+pub extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    end_of_interrupt(InterruptIndex::Timer);
+    time::on_tick();
+}
+
+pub extern "x86-interrupt" fn apic_timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    apic::on_timer_tick();
+    end_of_interrupt(InterruptIndex::ApicTimer);
+}
+
+pub extern "x86-interrupt" fn rtc_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    rtc::on_interrupt();
+    end_of_interrupt(InterruptIndex::Rtc);
+}
+
+pub extern "x86-interrupt" fn com1_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    serial::on_interrupt();
+    end_of_interrupt(InterruptIndex::Com1);
+}
+
+pub extern "x86-interrupt" fn wakeup_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    end_of_interrupt(InterruptIndex::Wakeup);
+}
+
+/// Spurious interrupts from the local APIC must not be acknowledged.
+pub extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+pub extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    if let Some(scancode) = ps2::read_from(Ps2Port::First) {
+        push_scancode(scancode);
+    }
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+pub extern "x86-interrupt" fn mouse_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    ps2::mouse::on_interrupt();
+    end_of_interrupt(InterruptIndex::Mouse);
+}
+
+pub extern "x86-interrupt" fn primary_ata_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    ata::on_interrupt(ata::PRIMARY);
+    end_of_interrupt(InterruptIndex::PrimaryAta);
+}
+
+pub extern "x86-interrupt" fn secondary_ata_interrupt_handler(stack_frame: InterruptStackFrame) {
+    percpu::restore_gs(stack_frame.code_segment);
+    ata::on_interrupt(ata::SECONDARY);
+    end_of_interrupt(InterruptIndex::SecondaryAta);
 }
 
 pub extern "x86-interrupt" fn page_fault_handler(
@@ -105,11 +279,31 @@ pub extern "x86-interrupt" fn page_fault_handler(
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
-    println!("Page Fault!");
-    println!("Accessed address: {:?}", Cr2::read());
-    println!("Error code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+
+    percpu::restore_gs(stack_frame.code_segment);
+    let addr = Cr2::read();
+    if memory::fault::sync_kernel_entry(addr) {
+        return;
+    }
+    if let Some(stack) = memory::stack::guard_hit(addr) {
+        panic!(
+            "STACK OVERFLOW\n{} at {:#x} hit the guard page of the {}\n{:#?}",
+            DecodedErrorCode(error_code),
+            addr.as_u64(),
+            stack,
+            stack_frame
+        );
+    }
+    if let Err(err) = memory::fault::resolve(addr, error_code) {
+        panic!(
+            "PAGE FAULT\n{} at {:#x}: {}\nerror code: {:?}\n{:#?}",
+            DecodedErrorCode(error_code),
+            addr.as_u64(),
+            err,
+            error_code,
+            stack_frame
+        );
+    }
 }
 
 #[test_case]
@@ -10,13 +10,32 @@ extern crate alloc;
 #[allow(unused_imports)]
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod ata;
+pub mod block;
+pub mod dmesg;
+pub mod elf;
+pub mod framebuffer;
 pub mod gdt;
 pub mod interrupts;
+pub mod logger;
 pub mod memory;
+pub mod pci;
+pub mod percpu;
+pub mod ps2;
+pub mod rtc;
 pub mod serial;
+pub mod shell;
+pub mod smp;
+pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod time;
+pub mod usermode;
 pub mod vga_buffer;
+pub mod virtio;
 
 pub trait Testable {
     fn run(&self) {}
@@ -48,6 +67,26 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     hlt_loop();
 }
 
+/// Sets up the kernel with `init`, then memory management and the heap,
+/// which most integration tests need before they run.
+pub fn test_init(boot_info: &'static BootInfo) {
+    init();
+    let phys_mem_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { memory::BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::install(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+}
+
+/// Runs `executor` until `done` returns true, halting while it is idle.
+pub fn test_run_until(executor: &mut task::executor::Executor, done: impl Fn() -> bool) {
+    while !done() {
+        executor.run_ready_tasks();
+        x86_64::instructions::hlt();
+    }
+}
+
 #[cfg(test)]
 entry_point!(test_kernel_main);
 
@@ -83,6 +122,8 @@ pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
+    logger::init().expect("logger initialization failed");
     x86_64::instructions::interrupts::enable();
 }
 
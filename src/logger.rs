@@ -0,0 +1,327 @@
+//! Backend for the `log` crate. Records pass a global level or the most
+//! specific per-module level, are formatted once with a timestamp and then
+//! handed to every sink whose own level admits them.
+
+use crate::{dmesg, serial, time, vga_buffer};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::time::Duration;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Longer messages are cut off.
+pub const MAX_LINE: usize = 256;
+pub const MAX_SINKS: usize = 8;
+const MEMORY_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoggerError {
+    AlreadyInitialized,
+    TooManySinks,
+}
+
+/// A formatted log record.
+pub struct Line<'a> {
+    pub level: Level,
+    /// Time since boot.
+    pub timestamp: Duration,
+    pub target: &'a str,
+    pub message: &'a str,
+}
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:5}.{:06}] {:<5} {}: {}",
+            self.timestamp.as_secs(),
+            self.timestamp.subsec_micros(),
+            self.level,
+            self.target,
+            self.message
+        )
+    }
+}
+
+/// Somewhere log lines go. Sinks are called with interrupts disabled and
+/// must not log themselves.
+pub trait Sink: Sync {
+    fn write(&self, line: &Line);
+}
+
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn write(&self, line: &Line) {
+        serial::_print(format_args!("{}\n", line));
+    }
+}
+
+/// Prints lines on a virtual terminal.
+pub struct VgaSink {
+    pub terminal: usize,
+}
+
+impl Sink for VgaSink {
+    fn write(&self, line: &Line) {
+        // the message buffer has its own copy of the line
+        vga_buffer::print_unrecorded(self.terminal, format_args!("{}\n", line));
+    }
+}
+
+/// Keeps the most recent lines in a fixed buffer so they can be inspected
+/// after they scrolled off the screen.
+pub struct MemorySink {
+    ring: Mutex<Ring>,
+}
+
+struct Ring {
+    bytes: [u8; MEMORY_SIZE],
+    /// Total number of bytes ever written.
+    written: usize,
+}
+
+impl MemorySink {
+    pub const fn new() -> Self {
+        Self {
+            ring: Mutex::new(Ring {
+                bytes: [0; MEMORY_SIZE],
+                written: 0,
+            }),
+        }
+    }
+
+    /// Returns the buffered lines, oldest first. A line that was partly
+    /// overwritten is left out.
+    pub fn contents(&self) -> String {
+        interrupts::without_interrupts(|| {
+            let ring = self.ring.lock();
+            let start = ring.written.saturating_sub(MEMORY_SIZE);
+            let mut bytes = Vec::with_capacity(ring.written - start);
+            bytes.extend((start..ring.written).map(|i| ring.bytes[i % MEMORY_SIZE]));
+            if start > 0 {
+                let first_line = bytes.iter().position(|b| *b == b'\n').map_or(0, |i| i + 1);
+                bytes.drain(..first_line);
+            }
+            String::from_utf8_lossy(&bytes).into_owned()
+        })
+    }
+
+    pub fn clear(&self) {
+        interrupts::without_interrupts(|| self.ring.lock().written = 0);
+    }
+}
+
+impl Default for MemorySink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sink for MemorySink {
+    fn write(&self, line: &Line) {
+        let mut ring = self.ring.lock();
+        let _ = writeln!(ring, "{}", line);
+    }
+}
+
+impl fmt::Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.bytes[self.written % MEMORY_SIZE] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Records every line in the kernel message buffer.
+pub struct DmesgSink;
+
+impl Sink for DmesgSink {
+    fn write(&self, line: &Line) {
+        dmesg::push_at(
+            line.timestamp,
+            format_args!("{:<5} {}: {}", line.level, line.target, line.message),
+        );
+    }
+}
+
+pub static SERIAL: SerialSink = SerialSink;
+pub static VGA: VgaSink = VgaSink {
+    terminal: vga_buffer::CONSOLE,
+};
+/// Every record, on a terminal of its own.
+pub static VGA_LOG: VgaSink = VgaSink {
+    terminal: vga_buffer::LOG_TERMINAL,
+};
+pub static MEMORY: MemorySink = MemorySink::new();
+pub static DMESG: DmesgSink = DmesgSink;
+
+/// A message formatted on the stack, so logging works without the heap.
+struct LineBuffer {
+    bytes: [u8; MAX_LINE],
+    len: usize,
+}
+
+impl LineBuffer {
+    fn as_str(&self) -> &str {
+        // only whole characters are ever copied in
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for LineBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut end = s.len().min(MAX_LINE - self.len);
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.bytes[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        Ok(())
+    }
+}
+
+struct Filters {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filters {
+    /// The level of the longest module path that `target` is in.
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target.starts_with(module.as_str())
+                    && (target.len() == module.len() || target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, core::cmp::Ord::max)
+    }
+}
+
+type SinkSlot = Option<(&'static dyn Sink, LevelFilter)>;
+
+struct Logger {
+    filters: Mutex<Filters>,
+    sinks: Mutex<[SinkSlot; MAX_SINKS]>,
+}
+
+static LOGGER: Logger = Logger {
+    filters: Mutex::new(Filters {
+        default: LevelFilter::Info,
+        modules: Vec::new(),
+    }),
+    sinks: Mutex::new([None; MAX_SINKS]),
+};
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        interrupts::without_interrupts(|| {
+            metadata.level() <= self.filters.lock().level_for(metadata.target())
+        })
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut message = LineBuffer {
+            bytes: [0; MAX_LINE],
+            len: 0,
+        };
+        let _ = message.write_fmt(*record.args());
+        let line = Line {
+            level: record.level(),
+            timestamp: time::uptime(),
+            target: record.target(),
+            message: message.as_str(),
+        };
+        interrupts::without_interrupts(|| {
+            for (sink, level) in self.sinks.lock().iter().flatten() {
+                if line.level <= *level {
+                    sink.write(&line);
+                }
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+fn update_max_level(filters: &Filters) {
+    log::set_max_level(filters.max_level());
+}
+
+/// Installs the logger with the serial port, the log terminal and the memory
+/// and message buffers taking every line and the console only warnings and
+/// errors.
+pub fn init() -> Result<(), LoggerError> {
+    log::set_logger(&LOGGER).map_err(|_| LoggerError::AlreadyInitialized)?;
+    interrupts::without_interrupts(|| update_max_level(&LOGGER.filters.lock()));
+    add_sink(&SERIAL, LevelFilter::Trace)?;
+    add_sink(&VGA, LevelFilter::Warn)?;
+    add_sink(&VGA_LOG, LevelFilter::Trace)?;
+    add_sink(&MEMORY, LevelFilter::Trace)?;
+    add_sink(&DMESG, LevelFilter::Trace)
+}
+
+pub fn add_sink(sink: &'static dyn Sink, level: LevelFilter) -> Result<(), LoggerError> {
+    interrupts::without_interrupts(|| {
+        let mut sinks = LOGGER.sinks.lock();
+        let slot = sinks
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(LoggerError::TooManySinks)?;
+        *slot = Some((sink, level));
+        Ok(())
+    })
+}
+
+/// Changes the level of an installed sink, identified by address.
+pub fn set_sink_level(sink: &'static dyn Sink, level: LevelFilter) {
+    interrupts::without_interrupts(|| {
+        for (installed, installed_level) in LOGGER.sinks.lock().iter_mut().flatten() {
+            if core::ptr::eq(
+                *installed as *const dyn Sink as *const u8,
+                sink as *const dyn Sink as *const u8,
+            ) {
+                *installed_level = level;
+            }
+        }
+    });
+}
+
+/// Sets the level of modules without a level of their own.
+pub fn set_level(level: LevelFilter) {
+    interrupts::without_interrupts(|| {
+        let mut filters = LOGGER.filters.lock();
+        filters.default = level;
+        update_max_level(&filters);
+    });
+}
+
+/// Sets the level of `module` and the modules below it, given by its full
+/// path like `blog_os_yawqi::apic`.
+pub fn set_module_level(module: &str, level: LevelFilter) {
+    let module = String::from(module);
+    interrupts::without_interrupts(|| {
+        let mut filters = LOGGER.filters.lock();
+        match filters.modules.iter_mut().find(|(m, _)| *m == module) {
+            Some((_, existing)) => *existing = level,
+            None => filters.modules.push((module, level)),
+        }
+        update_max_level(&filters);
+    });
+}
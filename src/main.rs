@@ -6,9 +6,12 @@
 #![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
+use blog_os_yawqi::memory::stack::{KernelStack, StackKind, MAX_STACK_PAGES};
 use blog_os_yawqi::{
-    allocator, hlt_loop, memory, println,
-    task::{executor::Executor, keyboard::print_keypresses, Task},
+    acpi, allocator, apic, ata, framebuffer, gdt, hlt_loop, memory, pci, println, ps2, rtc, shell,
+    smp,
+    task::{executor::Executor, keyboard, Task},
+    thread, vga_buffer, virtio,
 };
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
@@ -19,10 +22,12 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello, world!");
     blog_os_yawqi::init();
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut page_frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::new(&boot_info.memory_map) };
+    let page_frame_allocator = unsafe {
+        memory::BitmapFrameAllocator::init(&boot_info.memory_map, physical_memory_offset)
+    };
 
-    let mut mapper = unsafe { blog_os_yawqi::memory::init(physical_memory_offset) };
+    let mapper = unsafe { blog_os_yawqi::memory::init(physical_memory_offset) };
+    memory::install(mapper, page_frame_allocator);
     /*
         let page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
         unsafe { create_example_mapping(page, &mut mapper, &mut page_frame_allocator) }
@@ -33,12 +38,71 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
         }
     */
 
-    allocator::init_heap(&mut mapper, &mut page_frame_allocator)
-        .expect("Create heap memory failed");
+    allocator::init_heap().expect("Create heap memory failed");
+    gdt::install_guarded_stacks().expect("Create interrupt stacks failed");
+    match acpi::init() {
+        Ok(acpi) => log::info!(
+            "ACPI revision {}, {} tables",
+            acpi.revision,
+            acpi.tables().count()
+        ),
+        Err(err) => log::warn!("No ACPI tables: {:?}", err),
+    }
+    log::info!("Booted at {} UTC", rtc::init());
+    match apic::init() {
+        Ok(()) => log::info!("Interrupts routed through the APIC"),
+        Err(err) => log::warn!("No usable APIC ({:?}), staying on the 8259 PIC", err),
+    }
+    if apic::is_enabled() {
+        match smp::start_aps() {
+            Ok(count) => log::info!("Started {} application processors", count),
+            Err(err) => log::warn!("Starting application processors failed: {:?}", err),
+        }
+    }
+    match pci::init() {
+        Ok(devices) => log::info!("Found {} PCI devices", devices.len()),
+        Err(err) => log::warn!("PCI enumeration failed: {:?}", err),
+    }
+    match virtio::block::init() {
+        Ok(count) => log::info!("Found {} virtio block devices", count),
+        Err(err) => log::warn!("Virtio block initialization failed: {:?}", err),
+    }
+    match ata::init() {
+        Ok(count) => log::info!("Found {} ATA drives", count),
+        Err(err) => log::warn!("ATA initialization failed: {:?}", err),
+    }
+    match ps2::init() {
+        Ok(ports) => log::info!("PS/2 devices: {:?}", ports),
+        Err(err) => log::warn!("No PS/2 devices: {:?}", err),
+    }
+    // the console moves to a framebuffer whenever the adapter is there
+    match framebuffer::init(framebuffer::DEFAULT_WIDTH, framebuffer::DEFAULT_HEIGHT) {
+        Ok(framebuffer) => {
+            vga_buffer::use_framebuffer(framebuffer);
+            log::info!(
+                "Console on a {}x{} framebuffer",
+                framebuffer.width(),
+                framebuffer.height()
+            );
+        }
+        Err(framebuffer::FramebufferError::NoAdapter) => {
+            log::info!("No framebuffer, staying in text mode")
+        }
+        Err(err) => log::warn!("No framebuffer ({:?}), staying in text mode", err),
+    }
+
+    let boot_stack =
+        KernelStack::allocate(MAX_STACK_PAGES, StackKind::Boot).expect("Create boot stack failed");
+    unsafe { memory::stack::run_on(boot_stack.leak(), kernel_run) }
+}
+
+extern "C" fn kernel_run() -> ! {
+    thread::init();
 
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(print_keypresses()));
+    executor.spawn(Task::new(keyboard::run()));
+    executor.spawn(Task::new(shell::run()));
     executor.run();
 
     #[cfg(test)]
@@ -50,6 +114,7 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    blog_os_yawqi::dmesg::dump_on_panic();
     hlt_loop();
 }
 
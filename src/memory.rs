@@ -1,8 +1,20 @@
-use bootloader::bootinfo::MemoryMap;
-use bootloader::bootinfo::MemoryRegionType;
+pub mod address_space;
+pub mod bitmap;
+pub mod fault;
+pub mod region;
+pub mod stack;
+
+pub use address_space::AddressSpace;
+pub use bitmap::BitmapFrameAllocator;
+pub use region::{Region, RegionKind};
+
+use crate::percpu::CpuMutex;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
 use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
 use x86_64::structures::paging::page::Page;
-use x86_64::structures::paging::page_table::FrameError;
+use x86_64::structures::paging::page_table::{FrameError, PageTableEntry};
 use x86_64::structures::paging::FrameAllocator;
 use x86_64::structures::paging::Mapper;
 use x86_64::structures::paging::OffsetPageTable;
@@ -12,47 +24,27 @@ use x86_64::structures::paging::PhysFrame;
 use x86_64::structures::paging::Size4KiB;
 use x86_64::{PhysAddr, VirtAddr};
 
-pub struct EmptyFrameAllocator;
+pub const USER_SPACE_START: u64 = 0x0000_2000_0000_0000;
+pub const USER_SPACE_END: u64 = 0x0000_4000_0000_0000;
 
-unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        None
-    }
-}
+/// Device registers are mapped uncached into this part of the kernel half.
+pub const MMIO_START: u64 = 0x0000_4800_0000_0000;
+pub const MMIO_END: u64 = MMIO_START + 0x10_0000_0000;
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
-}
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+static KERNEL_LEVEL_4_FRAME: AtomicU64 = AtomicU64::new(0);
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
-        Self {
-            memory_map,
-            next: 0,
-        }
-    }
+// Lock order is always KERNEL_MAPPER before FRAME_ALLOCATOR. The heap grows
+// through these, so nothing may allocate on the heap while holding them.
+pub static KERNEL_MAPPER: CpuMutex<Option<OffsetPageTable<'static>>> = CpuMutex::new(None);
+pub static FRAME_ALLOCATOR: CpuMutex<Option<BitmapFrameAllocator>> = CpuMutex::new(None);
 
-    fn usable_frame(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions_iter = self
-            .memory_map
-            .iter()
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
-
-        regions_iter
-            .flat_map(|r| {
-                let range = r.range.start_addr()..r.range.end_addr();
-                range.step_by(4096)
-            })
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
+pub struct EmptyFrameAllocator;
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frame().nth(self.next);
-        self.next += 1;
-        frame
+        None
     }
 }
 
@@ -67,10 +59,138 @@ unsafe fn active_level_4_page_table(offset: VirtAddr) -> &'static mut PageTable
 }
 
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_LEVEL_4_FRAME.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_page_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+pub fn physical_memory_offset() -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+/// Returns the level 4 table set up by the bootloader, which kernel threads
+/// run on.
+pub fn kernel_level_4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LEVEL_4_FRAME.load(Ordering::Relaxed)))
+}
+
+/// Returns a mapper for the address space currently loaded in CR3.
+pub unsafe fn active_mapper() -> OffsetPageTable<'static> {
+    let offset = physical_memory_offset();
+    OffsetPageTable::new(active_level_4_page_table(offset), offset)
+}
+
+pub fn install(mapper: OffsetPageTable<'static>, frame_allocator: BitmapFrameAllocator) {
+    interrupts::without_interrupts(|| {
+        *KERNEL_MAPPER.lock() = Some(mapper);
+        *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+    });
+}
+
+pub fn with_kernel_memory<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+{
+    interrupts::without_interrupts(|| {
+        let mut mapper = KERNEL_MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        Some(f(mapper.as_mut()?, frame_allocator.as_mut()?))
+    })
+}
+
+/// Registers a kernel region whose pages are mapped on first access.
+pub fn add_kernel_region(region: Region) -> Result<(), region::RegionError> {
+    interrupts::without_interrupts(|| region::KERNEL_REGIONS.lock().insert(region))
+}
+
+/// Maps `size` bytes of device memory starting at `phys` uncached into the
+/// kernel half and returns the virtual address of `phys`.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let offset = phys - first.start_address();
+    let pages = PhysFrame::range_inclusive(first, last).count() as u64;
+    let len = pages * Page::<Size4KiB>::SIZE;
+    // a window that doesn't fit leaves the rest for smaller ones
+    let start = MMIO_NEXT
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |start| {
+            Some(start + len).filter(|end| *end <= MMIO_END)
+        })
+        .map_err(|_| MapToError::FrameAllocationFailed)?;
+    let first_page = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    with_kernel_memory(|mapper, frame_allocator| {
+        for i in 0..pages {
+            unsafe {
+                mapper
+                    .map_to(first_page + i, first + i, flags, frame_allocator)?
+                    .flush()
+            };
+        }
+        Ok(())
+    })
+    .unwrap_or(Err(MapToError::FrameAllocationFailed))?;
+    Ok(VirtAddr::new(start + offset))
+}
+
+/// Unmaps a window of `size` bytes that `map_mmio` returned. Its addresses
+/// aren't handed out again.
+pub fn unmap_mmio(virt: VirtAddr, size: u64) {
+    let first = Page::<Size4KiB>::containing_address(virt);
+    let last = Page::<Size4KiB>::containing_address(virt + size.max(1) - 1u64);
+    with_kernel_memory(|mapper, _| {
+        for page in Page::range_inclusive(first, last) {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+    });
+}
+
+/// Zeroed, physically contiguous frames for devices to read and write,
+/// accessed through the mapping of all physical memory. Freed on drop.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    start: PhysFrame,
+    frames: usize,
+}
+
+impl DmaBuffer {
+    pub fn allocate(frames: usize) -> Option<Self> {
+        let start = with_kernel_memory(|_, frame_allocator| {
+            frame_allocator.allocate_contiguous(frames, 1)
+        })??;
+        let buffer = Self { start, frames };
+        unsafe { core::ptr::write_bytes(buffer.virt().as_mut_ptr::<u8>(), 0, buffer.size()) };
+        Some(buffer)
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.start.start_address()
+    }
+
+    pub fn virt(&self) -> VirtAddr {
+        physical_memory_offset() + self.phys().as_u64()
+    }
+
+    pub fn size(&self) -> usize {
+        self.frames * Page::<Size4KiB>::SIZE as usize
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        with_kernel_memory(|_, frame_allocator| unsafe {
+            frame_allocator.deallocate_contiguous(self.start, self.frames)
+        });
+    }
+}
+
 pub unsafe fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
@@ -115,3 +235,77 @@ fn translate_virtaddr_inner(
 
     Some(page_table_frame.start_address() + u64::from(virt_addr.page_offset()))
 }
+
+fn page_table_entries(
+    virt_addr: VirtAddr,
+) -> impl Iterator<Item = &'static mut x86_64::structures::paging::page_table::PageTableEntry> {
+    let offset = physical_memory_offset();
+    let indexes = [
+        virt_addr.p4_index(),
+        virt_addr.p3_index(),
+        virt_addr.p2_index(),
+        virt_addr.p1_index(),
+    ];
+    let mut table_frame = Some(Cr3::read().0);
+    IntoIterator::into_iter(indexes).map_while(move |idx| {
+        let table_addr = offset + table_frame?.start_address().as_u64();
+        let table = unsafe { &mut *table_addr.as_mut_ptr::<PageTable>() };
+        let entry = &mut table[idx];
+        table_frame = match entry.frame() {
+            Ok(frame) => Some(frame),
+            Err(FrameError::FrameNotPresent) => return None,
+            Err(FrameError::HugeFrame) => None,
+        };
+        Some(entry)
+    })
+}
+
+/// Returns the entries of the active page table on the way to `virt_addr`,
+/// from level 4 down to the first one that is not present or maps a huge
+/// page.
+pub fn walk(virt_addr: VirtAddr) -> [Option<PageTableEntry>; 4] {
+    let indexes = [
+        virt_addr.p4_index(),
+        virt_addr.p3_index(),
+        virt_addr.p2_index(),
+        virt_addr.p1_index(),
+    ];
+    let mut entries = [None, None, None, None];
+    let mut table_frame = Cr3::read().0;
+    for (slot, idx) in entries.iter_mut().zip(IntoIterator::into_iter(indexes)) {
+        let table_addr = physical_memory_offset() + table_frame.start_address().as_u64();
+        let table = unsafe { &*table_addr.as_ptr::<PageTable>() };
+        let entry = &table[idx];
+        *slot = Some(entry.clone());
+        table_frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(_) => break,
+        };
+    }
+    entries
+}
+
+/// Frame allocator statistics, once memory management is set up.
+pub fn frame_stats() -> Option<bitmap::FrameStats> {
+    with_kernel_memory(|_, frame_allocator| frame_allocator.stats())
+}
+
+/// Checks that every level of the active page table allows ring 3 to
+/// access `virt_addr`, and to write it if `write` is set.
+pub fn is_user_accessible(virt_addr: VirtAddr, write: bool) -> bool {
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let mut levels = 0;
+    for entry in page_table_entries(virt_addr) {
+        if !entry.flags().contains(required) {
+            return false;
+        }
+        levels += 1;
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+    }
+    levels == 4
+}
@@ -0,0 +1,307 @@
+use super::region::{Region, RegionError, RegionKind, RegionTable};
+use super::{kernel_level_4_frame, physical_memory_offset, with_kernel_memory, FRAME_ALLOCATOR};
+use super::{USER_SPACE_END, USER_SPACE_START};
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::mapper::FlagUpdateError;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::mapper::MappedFrame;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::page::PageRange;
+use x86_64::structures::paging::FrameAllocator;
+use x86_64::structures::paging::FrameDeallocator;
+use x86_64::structures::paging::Mapper;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::structures::paging::Page;
+use x86_64::structures::paging::PageTable;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::structures::paging::Translate;
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: u64 = Page::<Size4KiB>::SIZE;
+
+/// Anonymous regions handed out by `AddressSpace::reserve` live here.
+pub const MMAP_START: u64 = USER_SPACE_START + 0x1000_0000_0000;
+pub const MMAP_END: u64 = USER_SPACE_START + 0x1800_0000_0000;
+
+// Permissions are enforced by the leaf entries alone.
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+fn user_entries() -> Range<usize> {
+    usize::from(VirtAddr::new(USER_SPACE_START).p4_index())
+        ..usize::from(VirtAddr::new(USER_SPACE_END).p4_index())
+}
+
+fn frame_ptr<T>(frame: PhysFrame) -> *mut T {
+    (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+fn is_user_page(page: Page) -> bool {
+    let addr = page.start_address().as_u64();
+    (USER_SPACE_START..USER_SPACE_END).contains(&addr)
+}
+
+// Lock order is regions, then the mapper, then FRAME_ALLOCATOR.
+
+/// A level 4 page table whose upper, kernel part is shared with the kernel
+/// mapper and whose user range belongs to this address space alone. All
+/// frames mapped in the user range, and the page tables holding them, are
+/// freed when it is dropped.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    mapper: Mutex<OffsetPageTable<'static>>,
+    regions: Mutex<RegionTable>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let level_4_frame = with_kernel_memory(|mapper, frame_allocator| {
+            let frame = frame_allocator.allocate_frame()?;
+            let table = unsafe { &mut *frame_ptr::<PageTable>(frame) };
+            table.zero();
+            let user_entries = user_entries();
+            for (idx, entry) in mapper.level_4_table().iter().enumerate() {
+                if !user_entries.contains(&idx) {
+                    table[idx] = entry.clone();
+                }
+            }
+            Some(frame)
+        })
+        .flatten()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+
+        let table = unsafe { &mut *frame_ptr::<PageTable>(level_4_frame) };
+        let mapper = unsafe { OffsetPageTable::new(table, physical_memory_offset()) };
+        Ok(Self {
+            level_4_frame,
+            mapper: Mutex::new(mapper),
+            regions: Mutex::new(RegionTable::new()),
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Loads this address space into CR3.
+    ///
+    /// # Safety
+    ///
+    /// The caller must keep the address space alive while it is active.
+    pub unsafe fn activate(&self) {
+        if !self.is_active() {
+            Cr3::write(self.level_4_frame, Cr3Flags::empty());
+        }
+    }
+
+    fn with_mapper<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut OffsetPageTable<'static>, bool) -> R,
+    {
+        interrupts::without_interrupts(|| f(&mut self.mapper.lock(), self.is_active()))
+    }
+
+    /// Maps zeroed frames to `pages`, which must lie in the user range and
+    /// must not be mapped yet.
+    pub fn map(&self, pages: PageRange, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        let flags = flags | PageTableFlags::PRESENT;
+        self.with_mapper(|mapper, active| {
+            let mut guard = FRAME_ALLOCATOR.lock();
+            let frame_allocator = guard.as_mut().ok_or(MapToError::FrameAllocationFailed)?;
+            for page in pages {
+                assert!(is_user_page(page), "{:?} is outside user space", page);
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?;
+                unsafe {
+                    frame_ptr::<u8>(frame).write_bytes(0, PAGE_SIZE as usize);
+                    match mapper.map_to_with_table_flags(
+                        page,
+                        frame,
+                        flags,
+                        TABLE_FLAGS,
+                        frame_allocator,
+                    ) {
+                        Ok(flush) if active => flush.flush(),
+                        Ok(flush) => flush.ignore(),
+                        Err(err) => {
+                            frame_allocator.deallocate_frame(frame);
+                            return Err(err);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Unmaps `pages` and frees their frames. Pages that are not mapped are
+    /// skipped.
+    pub fn unmap(&self, pages: PageRange) {
+        self.with_mapper(|mapper, active| {
+            let mut guard = FRAME_ALLOCATOR.lock();
+            for page in pages {
+                assert!(is_user_page(page), "{:?} is outside user space", page);
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    if active {
+                        flush.flush();
+                    } else {
+                        flush.ignore();
+                    }
+                    if let Some(frame_allocator) = guard.as_mut() {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+            }
+        })
+    }
+
+    /// Replaces the flags of the already mapped `pages`.
+    pub fn protect(&self, pages: PageRange, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+        let flags = flags | PageTableFlags::PRESENT;
+        self.with_mapper(|mapper, active| {
+            for page in pages {
+                assert!(is_user_page(page), "{:?} is outside user space", page);
+                let flush = unsafe { mapper.update_flags(page, flags)? };
+                if active {
+                    flush.flush();
+                } else {
+                    flush.ignore();
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns the flags `page` is mapped with, if it is mapped.
+    pub fn flags(&self, page: Page) -> Option<PageTableFlags> {
+        self.with_mapper(|mapper, _| match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => Some(flags),
+            _ => None,
+        })
+    }
+
+    /// Registers a region whose pages are mapped on first access by the
+    /// page fault handler.
+    pub fn add_region(&self, region: Region) -> Result<(), RegionError> {
+        let start = Page::<Size4KiB>::containing_address(region.start);
+        assert!(
+            is_user_page(start) && region.end.as_u64() <= USER_SPACE_END,
+            "{} is outside user space",
+            region
+        );
+        interrupts::without_interrupts(|| self.regions.lock().insert(region))
+    }
+
+    /// Registers an anonymous region of at least `len` bytes in the mmap
+    /// area and returns its start.
+    pub fn reserve(&self, len: u64, flags: PageTableFlags) -> Result<VirtAddr, RegionError> {
+        if len == 0 {
+            return Err(RegionError::NoSpace);
+        }
+        interrupts::without_interrupts(|| {
+            let mut regions = self.regions.lock();
+            let start =
+                regions.find_gap(len, VirtAddr::new(MMAP_START)..VirtAddr::new(MMAP_END))?;
+            let len = (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            regions.insert(Region::new(start, len, flags, RegionKind::Anonymous))?;
+            Ok(start)
+        })
+    }
+
+    /// Removes the region starting at `start` and frees the frames that were
+    /// mapped for it.
+    pub fn remove_region(&self, start: VirtAddr) -> Option<Region> {
+        let region = interrupts::without_interrupts(|| self.regions.lock().remove(start))?;
+        let first = Page::containing_address(region.start);
+        let end = Page::containing_address(region.end - 1u64) + 1;
+        self.unmap(Page::range(first, end));
+        Some(region)
+    }
+
+    pub fn find_region(&self, addr: VirtAddr) -> Option<Region> {
+        interrupts::without_interrupts(|| self.regions.lock().find(addr))
+    }
+
+    /// Copies `data` to `addr` through the physical memory mapping, so the
+    /// address space does not have to be active. Returns `false` if part of
+    /// the destination is not mapped.
+    pub fn write(&self, addr: VirtAddr, data: &[u8]) -> bool {
+        self.with_mapper(|mapper, _| {
+            let mut written = 0;
+            while written < data.len() {
+                let dst = addr + written;
+                let frame = match mapper.translate(dst) {
+                    TranslateResult::Mapped {
+                        frame: MappedFrame::Size4KiB(frame),
+                        ..
+                    } => frame,
+                    _ => return false,
+                };
+                let offset = u64::from(dst.page_offset()) as usize;
+                let len = (PAGE_SIZE as usize - offset).min(data.len() - written);
+                unsafe {
+                    let dst = frame_ptr::<u8>(frame).add(offset);
+                    core::ptr::copy_nonoverlapping(data[written..].as_ptr(), dst, len);
+                }
+                written += len;
+            }
+            true
+        })
+    }
+}
+
+/// Frees the frames referenced by `table` and, below level 1, the tables
+/// themselves.
+unsafe fn free_table(
+    table: &PageTable,
+    level: u8,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    for entry in table.iter() {
+        let frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+        if level > 1 {
+            free_table(&*frame_ptr::<PageTable>(frame), level - 1, frame_allocator);
+        }
+        frame_allocator.deallocate_frame(frame);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            if self.is_active() {
+                unsafe { Cr3::write(kernel_level_4_frame(), Cr3Flags::empty()) };
+            }
+            let mut guard = FRAME_ALLOCATOR.lock();
+            let frame_allocator = match guard.as_mut() {
+                Some(frame_allocator) => frame_allocator,
+                None => return,
+            };
+            let mut mapper = self.mapper.lock();
+            let table = mapper.level_4_table();
+            for idx in user_entries() {
+                if let Ok(frame) = table[idx].frame() {
+                    unsafe {
+                        free_table(&*frame_ptr::<PageTable>(frame), 3, frame_allocator);
+                        frame_allocator.deallocate_frame(frame);
+                    }
+                }
+            }
+            unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+        });
+    }
+}
@@ -0,0 +1,231 @@
+use bootloader::bootinfo::MemoryMap;
+use bootloader::bootinfo::MemoryRegionType;
+use core::slice;
+use x86_64::structures::paging::FrameAllocator;
+use x86_64::structures::paging::FrameDeallocator;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::structures::paging::Size2MiB;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_WORD: usize = 64;
+const FRAMES_PER_HUGE_FRAME: usize = 512;
+
+/// Frames below 1 MiB are handed out last, they are the only ones real
+/// mode code such as the AP startup trampoline can use.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+const LOW_MEMORY_WORDS: usize = (LOW_MEMORY_END / FRAME_SIZE) as usize / BITS_PER_WORD;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    pub used: usize,
+}
+
+/// Physical frame allocator keeping one bit per 4 KiB frame, set while the
+/// frame is in use. The bitmap itself lives in the first usable region big
+/// enough to hold it and is accessed through the physical memory mapping.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    total_frames: usize,
+    free_frames: usize,
+    next: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the allocator from the bootloader's memory map.
+    ///
+    /// # Safety
+    ///
+    /// All physical memory must be mapped at `physical_memory_offset`, and
+    /// the usable regions of `memory_map` must really be unused. Only one
+    /// allocator may be created from the map.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let max_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .expect("no usable memory region");
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let words = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_size = (words * 8) as u64;
+        let bitmap_frames = bitmap_size.div_ceil(FRAME_SIZE);
+
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_frames * FRAME_SIZE)
+            .map(|r| r.range.start_addr())
+            .expect("no usable region large enough for the frame bitmap");
+
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, words);
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = Self {
+            bitmap,
+            frame_count,
+            total_frames: 0,
+            free_frames: 0,
+            next: LOW_MEMORY_WORDS,
+        };
+
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for idx in start..end {
+                allocator.clear(idx);
+            }
+            allocator.total_frames += end - start;
+            allocator.free_frames += end - start;
+        }
+
+        let bitmap_first = (bitmap_start / FRAME_SIZE) as usize;
+        for idx in bitmap_first..bitmap_first + bitmap_frames as usize {
+            allocator.set(idx);
+            allocator.free_frames -= 1;
+        }
+
+        allocator
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total_frames,
+            free: self.free_frames,
+            used: self.total_frames - self.free_frames,
+        }
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Allocates `count` physically contiguous frames whose first frame
+    /// number is a multiple of `align` (given in frames, a power of two).
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+
+        let mut start = 0;
+        while start + count <= self.frame_count {
+            let used = (start..start + count).find(|idx| self.is_used(*idx));
+            match used {
+                Some(used) => start = (used + align) & !(align - 1),
+                None => {
+                    for idx in start..start + count {
+                        self.set(idx);
+                    }
+                    self.free_frames -= count;
+                    return Some(Self::frame_at(start));
+                }
+            }
+        }
+        None
+    }
+
+    /// Allocates a frame that starts below `limit`.
+    pub fn allocate_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        let end = ((limit.as_u64() / FRAME_SIZE) as usize).min(self.frame_count);
+        // frame 0 holds the real mode interrupt vector table
+        let idx = (1..end).find(|idx| !self.is_used(*idx))?;
+        self.set(idx);
+        self.free_frames -= 1;
+        Some(Self::frame_at(idx))
+    }
+
+    /// Returns `count` contiguous frames starting at `frame` to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// The frames must have been allocated from this allocator and may not
+    /// be used anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, frame: PhysFrame, count: usize) {
+        let start = Self::index_of(frame);
+        for idx in start..start + count {
+            self.release(idx);
+        }
+    }
+
+    fn frame_at(idx: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(idx as u64 * FRAME_SIZE))
+    }
+
+    fn index_of(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    fn is_used(&self, idx: usize) -> bool {
+        self.bitmap[idx / BITS_PER_WORD] & (1 << (idx % BITS_PER_WORD)) != 0
+    }
+
+    fn set(&mut self, idx: usize) {
+        self.bitmap[idx / BITS_PER_WORD] |= 1 << (idx % BITS_PER_WORD);
+    }
+
+    fn clear(&mut self, idx: usize) {
+        self.bitmap[idx / BITS_PER_WORD] &= !(1 << (idx % BITS_PER_WORD));
+    }
+
+    fn release(&mut self, idx: usize) {
+        assert!(idx < self.frame_count, "frame {:#x} out of range", idx);
+        assert!(self.is_used(idx), "double free of frame {:#x}", idx);
+        self.clear(idx);
+        self.free_frames += 1;
+        self.next = self.next.min(idx / BITS_PER_WORD).max(LOW_MEMORY_WORDS);
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let words = self.bitmap.len();
+        for offset in 0..words {
+            let word_idx = (self.next + offset) % words;
+            let word = self.bitmap[word_idx];
+            if word == u64::MAX {
+                continue;
+            }
+            let idx = word_idx * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+            if idx >= self.frame_count {
+                continue;
+            }
+            self.set(idx);
+            self.free_frames -= 1;
+            self.next = word_idx;
+            return Some(Self::frame_at(idx));
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.release(Self::index_of(frame));
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate_contiguous(FRAMES_PER_HUGE_FRAME, FRAMES_PER_HUGE_FRAME)?;
+        PhysFrame::from_start_address(frame.start_address()).ok()
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_contiguous(frame, FRAMES_PER_HUGE_FRAME);
+    }
+}
@@ -0,0 +1,166 @@
+use super::region::{Region, KERNEL_REGIONS};
+use super::{
+    active_level_4_page_table, FRAME_ALLOCATOR, KERNEL_MAPPER, USER_SPACE_END, USER_SPACE_START,
+};
+use crate::thread;
+use core::fmt;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::FrameAllocator;
+use x86_64::structures::paging::FrameDeallocator;
+use x86_64::structures::paging::Mapper;
+use x86_64::structures::paging::Page;
+use x86_64::structures::paging::PageTable;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// No region covers the address.
+    NoRegion,
+    /// The access is not allowed by the region's permissions.
+    AccessDenied(Region),
+    /// The page is present, so the fault is a protection violation.
+    ProtectionViolation(Region),
+    OutOfMemory(Region),
+    /// The memory structures were already locked by the faulting code on
+    /// the same CPU.
+    Busy,
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaultError::NoRegion => write!(f, "address is not in any region"),
+            FaultError::AccessDenied(region) => write!(f, "access not allowed by {}", region),
+            FaultError::ProtectionViolation(region) => {
+                write!(f, "protection violation in {}", region)
+            }
+            FaultError::OutOfMemory(region) => write!(f, "out of frames for {}", region),
+            FaultError::Busy => write!(f, "memory structures locked by the faulting code"),
+        }
+    }
+}
+
+/// Formats a page fault error code as e.g. `user write of a not-present page`.
+pub struct DecodedErrorCode(pub PageFaultErrorCode);
+
+impl fmt::Display for DecodedErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+        let page = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "present"
+        } else {
+            "not-present"
+        };
+        write!(f, "{} {} of a {} page", mode, access, page)?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", reserved bit set in a page table")?;
+        }
+        Ok(())
+    }
+}
+
+fn is_user_address(addr: VirtAddr) -> bool {
+    (USER_SPACE_START..USER_SPACE_END).contains(&addr.as_u64())
+}
+
+/// Tries to resolve a page fault at `addr` by mapping a zeroed frame if the
+/// address lies in a registered region that allows the access. Regions in
+/// the user range are looked up in the address space of the current thread.
+pub fn resolve(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<Region, FaultError> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    if is_user_address(addr) {
+        let address_space = thread::current_address_space().ok_or(FaultError::NoRegion)?;
+        let region = address_space
+            .find_region(addr)
+            .ok_or(FaultError::NoRegion)?;
+        check_access(region, error_code)?;
+        // another CPU may have resolved the same fault first
+        return match address_space.map(Page::range(page, page + 1), region.flags) {
+            Ok(()) | Err(MapToError::PageAlreadyMapped(_)) => Ok(region),
+            Err(_) => Err(FaultError::OutOfMemory(region)),
+        };
+    }
+
+    // other CPUs are waited for, but a fault inside a critical section of
+    // this CPU can't be resolved
+    let region = KERNEL_REGIONS
+        .lock_unless_held()
+        .ok_or(FaultError::Busy)?
+        .find(addr)
+        .ok_or(FaultError::NoRegion)?;
+    check_access(region, error_code)?;
+    let mut mapper = KERNEL_MAPPER.lock_unless_held().ok_or(FaultError::Busy)?;
+    let mut frame_allocator = FRAME_ALLOCATOR.lock_unless_held().ok_or(FaultError::Busy)?;
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(FaultError::OutOfMemory(region)),
+    };
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(FaultError::OutOfMemory(region))?;
+    unsafe {
+        let ptr =
+            (super::physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr::<u8>();
+        ptr.write_bytes(0, Page::<Size4KiB>::SIZE as usize);
+        let flags = region.flags | PageTableFlags::PRESENT;
+        match mapper.map_to(page, frame, flags, frame_allocator) {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(_)) => frame_allocator.deallocate_frame(frame),
+            Err(_) => {
+                frame_allocator.deallocate_frame(frame);
+                return Err(FaultError::OutOfMemory(region));
+            }
+        }
+        // address spaces only copy the level 4 entries that existed when
+        // they were created
+        let idx = page.p4_index();
+        let active = active_level_4_page_table(super::physical_memory_offset());
+        if active[idx].is_unused() {
+            active[idx] = mapper.level_4_table()[idx].clone();
+        }
+    }
+    Ok(region)
+}
+
+/// Copies a level 4 entry of the kernel half that was created after the
+/// active address space into it. Returns whether that made `addr` mapped.
+pub fn sync_kernel_entry(addr: VirtAddr) -> bool {
+    if is_user_address(addr) {
+        return false;
+    }
+    let idx = Page::<Size4KiB>::containing_address(addr).p4_index();
+    let offset = super::physical_memory_offset();
+    let kernel_table = offset + super::kernel_level_4_frame().start_address().as_u64();
+    let kernel_table = unsafe { &*kernel_table.as_ptr::<PageTable>() };
+    let active = unsafe { active_level_4_page_table(offset) };
+    if !active[idx].is_unused() || kernel_table[idx].is_unused() {
+        return false;
+    }
+    active[idx] = kernel_table[idx].clone();
+    true
+}
+
+fn check_access(region: Region, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(FaultError::ProtectionViolation(region));
+    }
+    if !region.allows(error_code) {
+        return Err(FaultError::AccessDenied(region));
+    }
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use crate::percpu::CpuMutex;
+use core::fmt;
+use core::ops::Range;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+pub const MAX_REGIONS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Heap,
+    Stack,
+    Anonymous,
+}
+
+/// A range of virtual memory whose pages are backed by zeroed frames on
+/// first access, mapped with `flags`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub fn new(start: VirtAddr, len: u64, flags: PageTableFlags, kind: RegionKind) -> Self {
+        Self {
+            start,
+            end: start + len,
+            flags,
+            kind,
+        }
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    fn overlaps(&self, range: &Range<VirtAddr>) -> bool {
+        self.start < range.end && range.start < self.end
+    }
+
+    /// Checks whether the access described by `error_code` is allowed by
+    /// the permissions of the region.
+    pub fn allows(&self, error_code: PageFaultErrorCode) -> bool {
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && !self.flags.contains(PageTableFlags::WRITABLE)
+        {
+            return false;
+        }
+        if error_code.contains(PageFaultErrorCode::USER_MODE)
+            && !self.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+        {
+            return false;
+        }
+        !(error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+            && self.flags.contains(PageTableFlags::NO_EXECUTE))
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} region {:#x}..{:#x} ({:?})",
+            self.kind,
+            self.start.as_u64(),
+            self.end.as_u64(),
+            self.flags
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    Unaligned,
+    Overlap,
+    Full,
+    NoSpace,
+}
+
+/// A fixed-size set of non-overlapping regions. It never allocates, so it
+/// can be used from the page fault handler and while the heap grows.
+pub struct RegionTable {
+    regions: [Option<Region>; MAX_REGIONS],
+}
+
+impl RegionTable {
+    pub const fn new() -> Self {
+        Self {
+            regions: [None; MAX_REGIONS],
+        }
+    }
+
+    pub fn insert(&mut self, region: Region) -> Result<(), RegionError> {
+        if !region.start.is_aligned(4096u64) || !region.end.is_aligned(4096u64) {
+            return Err(RegionError::Unaligned);
+        }
+        if self.iter().any(|r| r.overlaps(&(region.start..region.end))) {
+            return Err(RegionError::Overlap);
+        }
+        let slot = self
+            .regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegionError::Full)?;
+        *slot = Some(region);
+        Ok(())
+    }
+
+    /// Removes the region starting at `start`.
+    pub fn remove(&mut self, start: VirtAddr) -> Option<Region> {
+        self.regions
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(r) if r.start == start))?
+            .take()
+    }
+
+    /// Moves the end of the region starting at `start`, as long as it does
+    /// not run into another region.
+    pub fn resize(&mut self, start: VirtAddr, end: VirtAddr) -> Result<(), RegionError> {
+        if !end.is_aligned(4096u64) || end < start {
+            return Err(RegionError::Unaligned);
+        }
+        if self
+            .iter()
+            .any(|r| r.start != start && r.overlaps(&(start..end)))
+        {
+            return Err(RegionError::Overlap);
+        }
+        let region = self
+            .regions
+            .iter_mut()
+            .flatten()
+            .find(|r| r.start == start)
+            .ok_or(RegionError::NoSpace)?;
+        region.end = end;
+        Ok(())
+    }
+
+    pub fn find(&self, addr: VirtAddr) -> Option<Region> {
+        self.iter().find(|r| r.contains(addr))
+    }
+
+    /// Returns the lowest page aligned address in `within` where `len`
+    /// bytes fit without touching another region.
+    pub fn find_gap(&self, len: u64, within: Range<VirtAddr>) -> Result<VirtAddr, RegionError> {
+        let len = (len + 4095) & !4095;
+        let candidates = core::iter::once(within.start).chain(self.iter().map(|r| r.end));
+        for start in candidates {
+            if start < within.start {
+                continue;
+            }
+            let end = match start.as_u64().checked_add(len) {
+                Some(end) if end <= within.end.as_u64() => VirtAddr::new(end),
+                _ => continue,
+            };
+            if !self.iter().any(|r| r.overlaps(&(start..end))) {
+                return Ok(start);
+            }
+        }
+        Err(RegionError::NoSpace)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Region> + '_ {
+        self.regions.iter().flatten().copied()
+    }
+}
+
+impl Default for RegionTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Regions of the kernel half, shared by every address space.
+pub static KERNEL_REGIONS: CpuMutex<RegionTable> = CpuMutex::new(RegionTable::new());
+
+#[test_case]
+fn test_region_table_rejects_overlap() {
+    let mut table = RegionTable::new();
+    let region = |start: u64, len: u64| {
+        Region::new(
+            VirtAddr::new(start),
+            len,
+            PageTableFlags::WRITABLE,
+            RegionKind::Anonymous,
+        )
+    };
+    assert_eq!(table.insert(region(0x10000, 0x2000)), Ok(()));
+    assert_eq!(
+        table.insert(region(0x11000, 0x2000)),
+        Err(RegionError::Overlap)
+    );
+    assert_eq!(table.insert(region(0x12000, 0x1000)), Ok(()));
+    let gap = table.find_gap(0x1000, VirtAddr::new(0x10000)..VirtAddr::new(0x20000));
+    assert_eq!(gap, Ok(VirtAddr::new(0x13000)));
+    assert!(table.remove(VirtAddr::new(0x10000)).is_some());
+    assert_eq!(table.find(VirtAddr::new(0x10800)), None);
+}
@@ -0,0 +1,201 @@
+use super::with_kernel_memory;
+use core::arch::global_asm;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::FrameAllocator;
+use x86_64::structures::paging::FrameDeallocator;
+use x86_64::structures::paging::Mapper;
+use x86_64::structures::paging::Page;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::VirtAddr;
+
+pub const KERNEL_STACKS_START: u64 = 0x_5000_0000_0000;
+pub const MAX_KERNEL_STACKS: usize = 256;
+
+/// Every stack gets a slot of this many pages. The stack sits at the top of
+/// its slot and everything below it stays unmapped, so there is always at
+/// least one guard page between two stacks.
+const SLOT_PAGES: u64 = 16;
+pub const MAX_STACK_PAGES: u64 = SLOT_PAGES - 1;
+
+const SLOT_SIZE: u64 = SLOT_PAGES * Page::<Size4KiB>::SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackKind {
+    Boot,
+    /// Boot stack of an application processor.
+    Ap(usize),
+    DoubleFault,
+    Privilege,
+    Thread(u64),
+}
+
+impl fmt::Display for StackKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StackKind::Boot => write!(f, "boot stack"),
+            StackKind::Ap(cpu) => write!(f, "boot stack of CPU {}", cpu),
+            StackKind::DoubleFault => write!(f, "double fault stack"),
+            StackKind::Privilege => write!(f, "privilege stack"),
+            StackKind::Thread(id) => write!(f, "stack of thread {}", id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackInfo {
+    pub kind: StackKind,
+    pub bottom: VirtAddr,
+    pub top: VirtAddr,
+}
+
+impl fmt::Display for StackInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:#x}..{:#x}",
+            self.kind,
+            self.bottom.as_u64(),
+            self.top.as_u64()
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum StackError {
+    TooLarge,
+    NoSlot,
+    Map(MapToError<Size4KiB>),
+}
+
+static STACKS: Mutex<[Option<StackInfo>; MAX_KERNEL_STACKS]> =
+    Mutex::new([None; MAX_KERNEL_STACKS]);
+
+fn slot_start(slot: usize) -> VirtAddr {
+    VirtAddr::new(KERNEL_STACKS_START + slot as u64 * SLOT_SIZE)
+}
+
+/// A kernel stack with an unmapped guard area below it. Its pages are
+/// unmapped and its slot is released when it is dropped.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+    info: StackInfo,
+}
+
+impl KernelStack {
+    pub fn allocate(pages: u64, kind: StackKind) -> Result<Self, StackError> {
+        if pages == 0 || pages > MAX_STACK_PAGES {
+            return Err(StackError::TooLarge);
+        }
+        let top = |slot| slot_start(slot) + SLOT_SIZE;
+        let info = |slot| StackInfo {
+            kind,
+            bottom: top(slot) - pages * Page::<Size4KiB>::SIZE,
+            top: top(slot),
+        };
+        let slot = interrupts::without_interrupts(|| {
+            let mut stacks = STACKS.lock();
+            let slot = stacks.iter().position(Option::is_none)?;
+            stacks[slot] = Some(info(slot));
+            Some(slot)
+        })
+        .ok_or(StackError::NoSlot)?;
+        let stack = Self {
+            slot,
+            info: info(slot),
+        };
+
+        let first = Page::containing_address(stack.info.bottom);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        with_kernel_memory(|mapper, frame_allocator| {
+            for page in Page::range(first, first + pages) {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?;
+                unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+            }
+            Ok(())
+        })
+        .unwrap_or(Err(MapToError::FrameAllocationFailed))
+        .map_err(StackError::Map)?;
+        Ok(stack)
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.info.top
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.info.bottom
+    }
+
+    pub fn info(&self) -> StackInfo {
+        self.info
+    }
+
+    /// Keeps the stack mapped forever and returns its top, for stacks that
+    /// are handed to the CPU.
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        core::mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let first = Page::<Size4KiB>::containing_address(self.info.bottom);
+        let last = Page::<Size4KiB>::containing_address(self.info.top - 1u64);
+        with_kernel_memory(|mapper, frame_allocator| {
+            for page in Page::range_inclusive(first, last) {
+                if let Ok((frame, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                }
+            }
+        });
+        interrupts::without_interrupts(|| STACKS.lock()[self.slot] = None);
+    }
+}
+
+/// Returns the stack whose guard area contains `addr`. Gives up instead of
+/// spinning if the registry is locked, since this runs in fault handlers.
+pub fn guard_hit(addr: VirtAddr) -> Option<StackInfo> {
+    let offset = addr.as_u64().checked_sub(KERNEL_STACKS_START)?;
+    let slot = (offset / SLOT_SIZE) as usize;
+    let info = (*STACKS.try_lock()?.get(slot)?)?;
+    if addr < info.bottom {
+        Some(info)
+    } else {
+        None
+    }
+}
+
+global_asm!(
+    r#"
+.global __run_on_stack
+__run_on_stack:
+    mov rsp, rdi
+    xor ebp, ebp
+    call rsi
+    ud2
+"#
+);
+
+extern "C" {
+    fn __run_on_stack(stack_top: u64, f: extern "C" fn() -> !) -> !;
+}
+
+/// Abandons the current stack and calls `f` on the stack ending at
+/// `stack_top`.
+///
+/// # Safety
+///
+/// Nothing may refer to data on the current stack anymore.
+pub unsafe fn run_on(stack_top: VirtAddr, f: extern "C" fn() -> !) -> ! {
+    __run_on_stack(stack_top.as_u64(), f)
+}
@@ -0,0 +1,619 @@
+//! PCI devices, found by walking the buses from the host bridge. The
+//! configuration space is memory mapped (ECAM) when ACPI describes it in an
+//! MCFG table, and reached through the legacy ports otherwise.
+
+use crate::{acpi, memory};
+use alloc::vec;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+const CONFIG_ENABLE: u32 = 1 << 31;
+/// The part of the configuration space the ports reach.
+const LEGACY_CONFIG_SIZE: u16 = 0x100;
+const ECAM_CONFIG_SIZE: u16 = 0x1000;
+/// 32 devices of 8 functions of 4 KiB.
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+const DEVICES_PER_BUS: u8 = 32;
+const FUNCTIONS_PER_DEVICE: u8 = 8;
+const NO_DEVICE: u16 = 0xffff;
+
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const REVISION: u16 = 0x08;
+const PROG_IF: u16 = 0x09;
+const SUBCLASS: u16 = 0x0a;
+const CLASS: u16 = 0x0b;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR0: u16 = 0x10;
+const SECONDARY_BUS: u16 = 0x19;
+const CAPABILITIES_POINTER: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3c;
+const INTERRUPT_PIN: u16 = 0x3d;
+
+pub const COMMAND_IO: u16 = 1 << 0;
+pub const COMMAND_MEMORY: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_GENERAL: u8 = 0;
+const HEADER_BRIDGE: u8 = 1;
+pub const MAX_BARS: usize = 6;
+const BRIDGE_BARS: usize = 2;
+
+pub const CLASS_STORAGE: u8 = 0x01;
+pub const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+const BAR_IO_FLAGS: u32 = 0b11;
+const BAR_MEMORY_FLAGS: u32 = 0xf;
+const UPPER_HALF: u64 = !0 << 32;
+
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR: u8 = 0x09;
+pub const CAP_PCI_EXPRESS: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+/// Longer capability lists are taken to be looping.
+const MAX_CAPABILITIES: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciError {
+    AlreadyInitialized,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// A base address register with the size of the region it decodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: PhysAddr,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Bar::Memory {
+                address,
+                size,
+                prefetchable,
+            } => write!(
+                f,
+                "memory at {:#x}, {} KiB{}",
+                address.as_u64(),
+                size / 1024,
+                if *prefetchable { ", prefetchable" } else { "" }
+            ),
+            Bar::Io { port, size } => write!(f, "I/O ports at {:#x}, {} bytes", port, size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Where the capability starts in the configuration space.
+    pub offset: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    pub bars: [Option<Bar>; MAX_BARS],
+    /// The legacy IRQ the firmware wired the device to.
+    pub interrupt_line: u8,
+    /// 1 to 4 for INTA# to INTD#, 0 for none.
+    pub interrupt_pin: u8,
+    pub capabilities: Vec<Capability>,
+}
+
+impl PciDevice {
+    /// The first capability with `id`.
+    pub fn capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities_with(id).next()
+    }
+
+    pub fn capabilities_with(&self, id: u8) -> impl Iterator<Item = Capability> + '_ {
+        self.capabilities
+            .iter()
+            .copied()
+            .filter(move |capability| capability.id == id)
+    }
+
+    pub fn read_config(&self, offset: u16) -> u32 {
+        read_u32(self.address, offset)
+    }
+
+    pub fn write_config(&self, offset: u16, value: u32) {
+        write_u32(self.address, offset, value);
+    }
+
+    pub fn command(&self) -> u16 {
+        read_u16(self.address, COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        // the status half only has bits that are cleared by writing ones
+        write_u32(self.address, COMMAND, command as u32);
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} {:02x}{:02x} {}",
+            self.address,
+            self.vendor_id,
+            self.device_id,
+            self.class,
+            self.subclass,
+            self.class_name()
+        )
+    }
+}
+
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVMe controller",
+        (0x01, _) => "storage controller",
+        (0x02, _) => "network controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus controller",
+        (0x0c, _) => "serial bus controller",
+        _ => "unknown device",
+    }
+}
+
+/// What a driver supports. Fields left `None` match every device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+}
+
+impl DeviceMatch {
+    pub const fn id(vendor_id: u16, device_id: u16) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+        }
+    }
+
+    pub const fn class(class: u8, subclass: u8) -> Self {
+        Self {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        accepts(self.vendor_id, device.vendor_id)
+            && accepts(self.device_id, device.device_id)
+            && accepts(self.class, device.class)
+            && accepts(self.subclass, device.subclass)
+    }
+}
+
+fn accepts<T: PartialEq>(wanted: Option<T>, value: T) -> bool {
+    wanted.is_none() || wanted == Some(value)
+}
+
+/// The memory mapped configuration space of a segment, mapped a bus at a
+/// time as the buses are used.
+struct Ecam {
+    /// The address of bus 0 of the segment.
+    base: PhysAddr,
+    start_bus: u8,
+    end_bus: u8,
+    /// The buses from `start_bus` on.
+    buses: Vec<EcamBus>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum EcamBus {
+    Unmapped,
+    Mapped(VirtAddr),
+    /// Mapping the bus failed, so it is reached through the I/O ports.
+    Ports,
+}
+
+/// Where a configuration space register is accessed.
+enum Register {
+    Memory(*mut u32),
+    Ports,
+    Missing,
+}
+
+impl Register {
+    fn ports(offset: u16) -> Self {
+        if offset < LEGACY_CONFIG_SIZE {
+            Register::Ports
+        } else {
+            Register::Missing
+        }
+    }
+}
+
+impl Ecam {
+    fn register(&mut self, address: PciAddress, offset: u16) -> Register {
+        if address.bus < self.start_bus || address.bus > self.end_bus || offset >= ECAM_CONFIG_SIZE
+        {
+            return Register::Missing;
+        }
+        let index = (address.bus - self.start_bus) as usize;
+        let bus = match self.buses[index] {
+            EcamBus::Mapped(bus) => bus,
+            EcamBus::Ports => return Register::ports(offset),
+            EcamBus::Unmapped => {
+                // the base is that of bus 0, even if the region starts later
+                let phys = self.base + address.bus as u64 * ECAM_BUS_SIZE;
+                match memory::map_mmio(phys, ECAM_BUS_SIZE) {
+                    Ok(bus) => {
+                        self.buses[index] = EcamBus::Mapped(bus);
+                        bus
+                    }
+                    Err(err) => {
+                        log::warn!(
+                            "PCI bus {} not mapped ({:?}), using I/O ports",
+                            address.bus,
+                            err
+                        );
+                        self.buses[index] = EcamBus::Ports;
+                        return Register::ports(offset);
+                    }
+                }
+            }
+        };
+        let offset = (address.device as u64) << 15
+            | (address.function as u64) << 12
+            | (offset & !0b11) as u64;
+        Register::Memory((bus + offset).as_mut_ptr())
+    }
+}
+
+enum ConfigSpace {
+    Ports,
+    Ecam(Ecam),
+}
+
+impl ConfigSpace {
+    fn register(&mut self, address: PciAddress, offset: u16) -> Register {
+        match self {
+            ConfigSpace::Ports => Register::ports(offset),
+            ConfigSpace::Ecam(ecam) => ecam.register(address, offset),
+        }
+    }
+
+    fn read(&mut self, address: PciAddress, offset: u16) -> u32 {
+        match self.register(address, offset) {
+            Register::Memory(register) => unsafe { register.read_volatile() },
+            Register::Ports => {
+                let mut config_address = Port::<u32>::new(CONFIG_ADDRESS);
+                let mut data = Port::<u32>::new(CONFIG_DATA);
+                unsafe {
+                    config_address.write(legacy_address(address, offset));
+                    data.read()
+                }
+            }
+            Register::Missing => !0,
+        }
+    }
+
+    fn write(&mut self, address: PciAddress, offset: u16, value: u32) {
+        match self.register(address, offset) {
+            Register::Memory(register) => unsafe { register.write_volatile(value) },
+            Register::Ports => {
+                let mut config_address = Port::<u32>::new(CONFIG_ADDRESS);
+                let mut data = Port::<u32>::new(CONFIG_DATA);
+                unsafe {
+                    config_address.write(legacy_address(address, offset));
+                    data.write(value);
+                }
+            }
+            Register::Missing => {}
+        }
+    }
+}
+
+fn legacy_address(address: PciAddress, offset: u16) -> u32 {
+    CONFIG_ENABLE
+        | (address.bus as u32) << 16
+        | (address.device as u32) << 11
+        | (address.function as u32) << 8
+        | (offset as u32 & 0xfc)
+}
+
+static CONFIG: Mutex<ConfigSpace> = Mutex::new(ConfigSpace::Ports);
+static DEVICES: OnceCell<Vec<PciDevice>> = OnceCell::uninit();
+
+/// Reads the aligned dword at `offset` in the configuration space of
+/// `address`. Registers that can't be reached read as all ones.
+pub fn read_u32(address: PciAddress, offset: u16) -> u32 {
+    interrupts::without_interrupts(|| CONFIG.lock().read(address, offset & !0b11))
+}
+
+pub fn read_u16(address: PciAddress, offset: u16) -> u16 {
+    (read_u32(address, offset) >> ((offset & 0b10) * 8)) as u16
+}
+
+pub fn read_u8(address: PciAddress, offset: u16) -> u8 {
+    (read_u32(address, offset) >> ((offset & 0b11) * 8)) as u8
+}
+
+pub fn write_u32(address: PciAddress, offset: u16, value: u32) {
+    interrupts::without_interrupts(|| CONFIG.lock().write(address, offset & !0b11, value));
+}
+
+/// Writes all ones to a BAR to learn which address bits it decodes.
+fn size_mask(address: PciAddress, offset: u16, value: u32) -> u32 {
+    write_u32(address, offset, !0);
+    let mask = read_u32(address, offset);
+    write_u32(address, offset, value);
+    mask
+}
+
+fn read_bars(address: PciAddress, count: usize) -> [Option<Bar>; MAX_BARS] {
+    let mut bars = [None; MAX_BARS];
+    // the BARs hold the size masks for a moment, so nothing may be decoded
+    let command = read_u16(address, COMMAND);
+    write_u32(
+        address,
+        COMMAND,
+        (command & !(COMMAND_IO | COMMAND_MEMORY)) as u32,
+    );
+    let mut index = 0;
+    while index < count {
+        let offset = BAR0 + index as u16 * 4;
+        let low = read_u32(address, offset);
+        let mask = size_mask(address, offset, low);
+        if low & BAR_IO != 0 {
+            let mask = mask & !BAR_IO_FLAGS;
+            if mask != 0 {
+                bars[index] = Some(Bar::Io {
+                    port: (low & !BAR_IO_FLAGS) as u16,
+                    size: (!mask).wrapping_add(1) as u16,
+                });
+            }
+            index += 1;
+            continue;
+        }
+        let prefetchable = low & BAR_PREFETCHABLE != 0;
+        let (base, mask, used) = if low & BAR_TYPE_MASK == BAR_TYPE_64 && index + 1 < count {
+            let high = read_u32(address, offset + 4);
+            let high_mask = size_mask(address, offset + 4, high);
+            (
+                (high as u64) << 32 | (low & !BAR_MEMORY_FLAGS) as u64,
+                (high_mask as u64) << 32 | (mask & !BAR_MEMORY_FLAGS) as u64,
+                2,
+            )
+        } else {
+            // all the upper bits are decoded by 32 bit BARs
+            (
+                (low & !BAR_MEMORY_FLAGS) as u64,
+                (mask & !BAR_MEMORY_FLAGS) as u64 | UPPER_HALF,
+                1,
+            )
+        };
+        if mask != UPPER_HALF && mask != 0 {
+            bars[index] = Some(Bar::Memory {
+                address: PhysAddr::new_truncate(base),
+                size: (!mask).wrapping_add(1),
+                prefetchable,
+            });
+        }
+        index += used;
+    }
+    write_u32(address, COMMAND, command as u32);
+    bars
+}
+
+fn read_capabilities(address: PciAddress) -> Vec<Capability> {
+    let mut capabilities = Vec::new();
+    if read_u16(address, STATUS) & STATUS_CAPABILITIES == 0 {
+        return capabilities;
+    }
+    let mut offset = read_u8(address, CAPABILITIES_POINTER) & !0b11;
+    while offset != 0 && capabilities.len() < MAX_CAPABILITIES {
+        let header = read_u16(address, offset as u16);
+        capabilities.push(Capability {
+            id: header as u8,
+            offset,
+        });
+        offset = (header >> 8) as u8 & !0b11;
+    }
+    capabilities
+}
+
+fn read_device(address: PciAddress) -> PciDevice {
+    let header_type = read_u8(address, HEADER_TYPE) & HEADER_TYPE_MASK;
+    let bar_count = match header_type {
+        HEADER_GENERAL => MAX_BARS,
+        HEADER_BRIDGE => BRIDGE_BARS,
+        _ => 0,
+    };
+    PciDevice {
+        address,
+        vendor_id: read_u16(address, VENDOR_ID),
+        device_id: read_u16(address, DEVICE_ID),
+        class: read_u8(address, CLASS),
+        subclass: read_u8(address, SUBCLASS),
+        prog_if: read_u8(address, PROG_IF),
+        revision: read_u8(address, REVISION),
+        header_type,
+        bars: read_bars(address, bar_count),
+        interrupt_line: read_u8(address, INTERRUPT_LINE),
+        interrupt_pin: read_u8(address, INTERRUPT_PIN),
+        capabilities: read_capabilities(address),
+    }
+}
+
+fn exists(address: PciAddress) -> bool {
+    read_u16(address, VENDOR_ID) != NO_DEVICE
+}
+
+fn scan_bus(bus: u8, devices: &mut Vec<PciDevice>) {
+    for device in 0..DEVICES_PER_BUS {
+        let first = PciAddress::new(bus, device, 0);
+        if !exists(first) {
+            continue;
+        }
+        let functions = if read_u8(first, HEADER_TYPE) & MULTI_FUNCTION != 0 {
+            FUNCTIONS_PER_DEVICE
+        } else {
+            1
+        };
+        for function in 0..functions {
+            let address = PciAddress::new(bus, device, function);
+            if !exists(address) {
+                continue;
+            }
+            let found = read_device(address);
+            let bridge = found.header_type == HEADER_BRIDGE
+                && (found.class, found.subclass) == (CLASS_BRIDGE, SUBCLASS_PCI_BRIDGE);
+            devices.push(found);
+            if bridge {
+                let secondary = read_u8(address, SECONDARY_BUS);
+                // buses behind a bridge are numbered after the bridge's own
+                if secondary > bus {
+                    scan_bus(secondary, devices);
+                }
+            }
+        }
+    }
+}
+
+fn scan() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    let host = PciAddress::new(0, 0, 0);
+    if read_u8(host, HEADER_TYPE) & MULTI_FUNCTION == 0 {
+        scan_bus(0, &mut devices);
+    } else {
+        // each function of the host bridge is the controller of a bus
+        for function in 0..FUNCTIONS_PER_DEVICE {
+            if exists(PciAddress::new(0, 0, function)) {
+                scan_bus(function, &mut devices);
+            }
+        }
+    }
+    devices
+}
+
+/// Finds the PCI devices. Uses the ECAM region of segment 0 if the ACPI
+/// tables were loaded before and describe one, and the I/O ports for buses
+/// it can't map. Needs the heap.
+pub fn init() -> Result<&'static [PciDevice], PciError> {
+    if DEVICES.is_initialized() {
+        return Err(PciError::AlreadyInitialized);
+    }
+    let region = acpi::get()
+        .and_then(|acpi| acpi.mcfg())
+        .and_then(|mcfg| mcfg.regions.iter().find(|region| region.segment == 0))
+        .copied();
+    if let Some(region) = region {
+        let ecam = Ecam {
+            base: region.base,
+            start_bus: region.start_bus,
+            end_bus: region.end_bus,
+            buses: vec![EcamBus::Unmapped; region.end_bus as usize - region.start_bus as usize + 1],
+        };
+        interrupts::without_interrupts(|| *CONFIG.lock() = ConfigSpace::Ecam(ecam));
+        log::info!(
+            "PCI configuration space mapped at {:#x}",
+            region.base.as_u64()
+        );
+    }
+    let devices = scan();
+    DEVICES
+        .try_init_once(|| devices)
+        .map_err(|_| PciError::AlreadyInitialized)?;
+    Ok(self::devices())
+}
+
+/// The devices found by `init`, bridges followed by the devices behind
+/// them.
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES
+        .try_get()
+        .map(|devices| devices.as_slice())
+        .unwrap_or(&[])
+}
+
+pub fn find(pattern: DeviceMatch) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| pattern.matches(device))
+}
@@ -0,0 +1,199 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::future::Future;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use spin::{Mutex, MutexGuard};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+pub const MAX_CPUS: usize = 16;
+
+pub type RemoteTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// State owned by one CPU. The GS base of every CPU points at its own
+/// `PerCpu`, whose first field points back at it so `current` needs a
+/// single load. Ring 3 can change the GS base, so the kernel GS base keeps
+/// a copy that `restore_gs` puts back on the way into the kernel.
+#[repr(C)]
+pub struct PerCpu {
+    this: AtomicPtr<PerCpu>,
+    pub index: usize,
+    apic_id: AtomicU8,
+    pub online: AtomicBool,
+    pub timer_ticks: AtomicU64,
+    /// Number of tasks on this CPU's executor.
+    pub tasks: AtomicUsize,
+    tss: AtomicPtr<TaskStateSegment>,
+    /// Futures spawned onto this CPU by others, picked up by its executor.
+    pub(crate) inbox: Mutex<VecDeque<RemoteTask>>,
+}
+
+impl PerCpu {
+    pub const fn new(index: usize) -> Self {
+        Self {
+            this: AtomicPtr::new(ptr::null_mut()),
+            index,
+            apic_id: AtomicU8::new(0),
+            online: AtomicBool::new(false),
+            timer_ticks: AtomicU64::new(0),
+            tasks: AtomicUsize::new(0),
+            tss: AtomicPtr::new(ptr::null_mut()),
+            inbox: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn is_bsp(&self) -> bool {
+        self.index == 0
+    }
+
+    pub(crate) fn set_tss(&self, tss: *mut TaskStateSegment) {
+        self.tss.store(tss, Ordering::Release);
+    }
+
+    pub(crate) fn tss(&self) -> *mut TaskStateSegment {
+        self.tss.load(Ordering::Acquire)
+    }
+}
+
+// The bootstrap processor has no heap yet when it sets up its GS base.
+static BSP: PerCpu = PerCpu::new(0);
+static CPUS: [AtomicPtr<PerCpu>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicPtr<PerCpu> = AtomicPtr::new(ptr::null_mut());
+    [NONE; MAX_CPUS]
+};
+
+/// Returns the initial local APIC id of the executing CPU.
+fn initial_apic_id() -> u8 {
+    #[allow(unused_unsafe)]
+    let ebx = unsafe { __cpuid(1).ebx };
+    (ebx >> 24) as u8
+}
+
+/// Points GS at `cpu` and registers it. Called once on every CPU before
+/// anything uses `current`.
+pub fn install(cpu: &'static PerCpu) {
+    let ptr = cpu as *const PerCpu as *mut PerCpu;
+    cpu.apic_id.store(initial_apic_id(), Ordering::Relaxed);
+    cpu.this.store(ptr, Ordering::Release);
+    CPUS[cpu.index].store(ptr, Ordering::Release);
+    KernelGsBase::write(VirtAddr::from_ptr(ptr));
+    GsBase::write(VirtAddr::from_ptr(ptr));
+}
+
+/// Points GS back at the current CPU after an interrupt or system call
+/// from ring 3, whose code may have loaded a null or user selector into
+/// GS. Must run before anything calls `current`.
+pub fn restore_gs(code_segment: u64) {
+    if code_segment & 3 != 0 {
+        GsBase::write(KernelGsBase::read());
+    }
+}
+
+pub(crate) fn install_bsp() -> &'static PerCpu {
+    install(&BSP);
+    &BSP
+}
+
+pub fn current() -> &'static PerCpu {
+    let ptr: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, preserves_flags, readonly));
+        &*ptr
+    }
+}
+
+pub fn get(index: usize) -> Option<&'static PerCpu> {
+    let ptr = CPUS.get(index)?.load(Ordering::Acquire);
+    unsafe { ptr.as_ref() }
+}
+
+/// Iterates over all registered CPUs, the bootstrap processor first.
+pub fn cpus() -> impl Iterator<Item = &'static PerCpu> {
+    (0..MAX_CPUS).filter_map(get)
+}
+
+const NO_OWNER: usize = usize::MAX;
+
+/// A spinlock that knows which CPU holds it, so that code which may run
+/// inside its own critical section, like the page fault handler, can wait
+/// for other CPUs without deadlocking on its own CPU.
+pub struct CpuMutex<T> {
+    inner: Mutex<T>,
+    owner: AtomicUsize,
+}
+
+pub struct CpuMutexGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    owner: &'a AtomicUsize,
+}
+
+impl<T> CpuMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            owner: AtomicUsize::new(NO_OWNER),
+        }
+    }
+
+    pub fn lock(&self) -> CpuMutexGuard<'_, T> {
+        self.owned(self.inner.lock())
+    }
+
+    pub fn try_lock(&self) -> Option<CpuMutexGuard<'_, T>> {
+        self.inner.try_lock().map(|guard| self.owned(guard))
+    }
+
+    /// Waits for the lock unless the current CPU holds it already, in
+    /// which case waiting would never end.
+    pub fn lock_unless_held(&self) -> Option<CpuMutexGuard<'_, T>> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return Some(guard);
+            }
+            if self.owner.load(Ordering::Acquire) == current().index {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn owned<'a>(&'a self, guard: MutexGuard<'a, T>) -> CpuMutexGuard<'a, T> {
+        self.owner.store(current().index, Ordering::Release);
+        CpuMutexGuard {
+            guard,
+            owner: &self.owner,
+        }
+    }
+}
+
+impl<T> Deref for CpuMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for CpuMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for CpuMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // the lock itself is released after this, when `guard` drops
+        self.owner.store(NO_OWNER, Ordering::Release);
+    }
+}
@@ -0,0 +1,274 @@
+//! The 8042 PS/2 controller, with the keyboard on its first port and a
+//! mouse on the second. Commands are sent by polling with interrupts
+//! disabled, device input arrives through IRQ 1 and IRQ 12.
+
+pub mod mouse;
+
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+/// Status on reads, controller commands on writes.
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte in the output buffer came from the second port.
+const STATUS_SECOND_PORT: u8 = 1 << 5;
+
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND: u8 = 0xa7;
+const ENABLE_SECOND: u8 = 0xa8;
+const TEST_SECOND: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST: u8 = 0xab;
+const DISABLE_FIRST: u8 = 0xad;
+const ENABLE_FIRST: u8 = 0xae;
+/// Sends the next data byte to the second port instead of the first.
+const WRITE_SECOND: u8 = 0xd4;
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// Replies of devices to commands.
+pub const ACK: u8 = 0xfa;
+pub const RESEND: u8 = 0xfe;
+const RESET: u8 = 0xff;
+const RESET_PASSED: u8 = 0xaa;
+/// Bytes a device gets resent after asking for it.
+const RETRIES: usize = 3;
+
+const TIMEOUT_POLLS: usize = 100_000;
+/// Devices take a lot longer for their self-test after a reset.
+const RESET_TIMEOUT_POLLS: usize = 5_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    AlreadyInitialized,
+    /// The controller didn't take or answer a command in time.
+    Timeout,
+    SelfTestFailed(u8),
+    /// No port passed its test and has a device.
+    NoDevices,
+    /// A device answered with something else than an acknowledgement.
+    Unexpected(u8),
+}
+
+/// The ports with a working device behind them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ports {
+    pub first: bool,
+    pub second: bool,
+}
+
+static PORTS: OnceCell<Ports> = OnceCell::uninit();
+/// Only held with interrupts disabled.
+static CONTROLLER: Mutex<Controller> = Mutex::new(Controller { _private: () });
+/// Set while a command runs, so that the interrupt handlers, which may run
+/// on another CPU, leave its replies to it.
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+struct Controller {
+    _private: (),
+}
+
+impl Controller {
+    fn status(&self) -> u8 {
+        unsafe { Port::<u8>::new(COMMAND_PORT).read() }
+    }
+
+    fn wait(&self, mask: u8, set: bool, polls: usize) -> Result<(), Ps2Error> {
+        for _ in 0..polls {
+            if (self.status() & mask != 0) == set {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn write(&mut self, port: u16, value: u8) -> Result<(), Ps2Error> {
+        self.wait(STATUS_INPUT_FULL, false, TIMEOUT_POLLS)?;
+        unsafe { Port::<u8>::new(port).write(value) };
+        Ok(())
+    }
+
+    fn read(&mut self, polls: usize) -> Result<u8, Ps2Error> {
+        self.wait(STATUS_OUTPUT_FULL, true, polls)?;
+        Ok(unsafe { Port::<u8>::new(DATA_PORT).read() })
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.write(COMMAND_PORT, command)
+    }
+
+    fn query(&mut self, command: u8) -> Result<u8, Ps2Error> {
+        self.command(command)?;
+        self.read(TIMEOUT_POLLS)
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.command(WRITE_CONFIG)?;
+        self.write(DATA_PORT, config)
+    }
+
+    fn flush(&mut self) {
+        while self.status() & STATUS_OUTPUT_FULL != 0 {
+            unsafe { Port::<u8>::new(DATA_PORT).read() };
+        }
+    }
+
+    /// Sends `byte` to the device on `port` until it is acknowledged.
+    fn send(&mut self, port: Ps2Port, byte: u8) -> Result<(), Ps2Error> {
+        let mut reply = RESEND;
+        for _ in 0..RETRIES {
+            if port == Ps2Port::Second {
+                self.command(WRITE_SECOND)?;
+            }
+            self.write(DATA_PORT, byte)?;
+            reply = self.read(TIMEOUT_POLLS)?;
+            if reply != RESEND {
+                break;
+            }
+        }
+        match reply {
+            ACK => Ok(()),
+            reply => Err(Ps2Error::Unexpected(reply)),
+        }
+    }
+
+    /// Resets the device on `port`, which answers once its self-test is done.
+    fn reset(&mut self, port: Ps2Port) -> Result<(), Ps2Error> {
+        self.send(port, RESET)?;
+        match self.read(RESET_TIMEOUT_POLLS)? {
+            RESET_PASSED => {}
+            reply => return Err(Ps2Error::Unexpected(reply)),
+        }
+        // mice follow up with their ID
+        self.flush();
+        Ok(())
+    }
+}
+
+fn with_controller<R>(f: impl FnOnce(&mut Controller) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let mut controller = CONTROLLER.lock();
+        BUSY.store(true, Ordering::Release);
+        let result = f(&mut controller);
+        // input skipped by the handlers would otherwise block the next byte
+        controller.flush();
+        BUSY.store(false, Ordering::Release);
+        result
+    })
+}
+
+/// Tests the controller and its ports, resets the devices found and turns
+/// on their interrupts.
+pub fn init() -> Result<Ports, Ps2Error> {
+    if PORTS.is_initialized() {
+        return Err(Ps2Error::AlreadyInitialized);
+    }
+    let ports = with_controller(|controller| {
+        controller.command(DISABLE_FIRST)?;
+        controller.command(DISABLE_SECOND)?;
+        controller.flush();
+
+        let config = controller.query(READ_CONFIG)? & !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ);
+        controller.write_config(config)?;
+        match controller.query(SELF_TEST)? {
+            SELF_TEST_PASSED => {}
+            result => return Err(Ps2Error::SelfTestFailed(result)),
+        }
+        // some controllers reset themselves for the test
+        controller.write_config(config)?;
+
+        // the second clock only starts on controllers with two ports
+        controller.command(ENABLE_SECOND)?;
+        let dual = controller.query(READ_CONFIG)? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+        controller.command(DISABLE_SECOND)?;
+
+        let mut ports = Ports {
+            first: controller.query(TEST_FIRST)? == PORT_TEST_PASSED,
+            second: dual && controller.query(TEST_SECOND)? == PORT_TEST_PASSED,
+        };
+        let mut config = config;
+        if ports.first {
+            controller.command(ENABLE_FIRST)?;
+            ports.first = controller.reset(Ps2Port::First).is_ok();
+            config |= if ports.first { CONFIG_FIRST_IRQ } else { 0 };
+        }
+        if ports.second {
+            controller.command(ENABLE_SECOND)?;
+            ports.second = controller.reset(Ps2Port::Second).is_ok();
+            config |= if ports.second { CONFIG_SECOND_IRQ } else { 0 };
+        }
+        controller.write_config(config)?;
+        Ok(ports)
+    })?;
+    if !ports.first && !ports.second {
+        return Err(Ps2Error::NoDevices);
+    }
+    PORTS
+        .try_init_once(|| ports)
+        .map_err(|_| Ps2Error::AlreadyInitialized)?;
+    Ok(ports)
+}
+
+/// The ports found by `init`.
+pub fn ports() -> Option<Ports> {
+    PORTS.try_get().ok().copied()
+}
+
+/// Sends `bytes` to the device on `port`, each acknowledged before the
+/// next. Input from the device arriving in between is lost.
+pub fn send(port: Ps2Port, bytes: &[u8]) -> Result<(), Ps2Error> {
+    with_controller(|controller| {
+        for byte in bytes {
+            controller.send(port, *byte)?;
+        }
+        Ok(())
+    })
+}
+
+/// Lets the controller translate scancode set 2 from the keyboard into
+/// set 1, or passes it on untouched.
+pub fn set_translation(enabled: bool) -> Result<(), Ps2Error> {
+    with_controller(|controller| {
+        let config = controller.query(READ_CONFIG)?;
+        let config = if enabled {
+            config | CONFIG_TRANSLATION
+        } else {
+            config & !CONFIG_TRANSLATION
+        };
+        controller.write_config(config)
+    })
+}
+
+/// Takes the waiting byte if it came from `port`. Called by the interrupt
+/// handlers, so it doesn't lock the controller.
+pub(crate) fn read_from(port: Ps2Port) -> Option<u8> {
+    if BUSY.load(Ordering::Acquire) {
+        return None;
+    }
+    let status = unsafe { Port::<u8>::new(COMMAND_PORT).read() };
+    let from_second = status & STATUS_SECOND_PORT != 0;
+    // the byte may already have been taken by a command
+    if status & STATUS_OUTPUT_FULL == 0 || from_second != (port == Ps2Port::Second) {
+        return None;
+    }
+    Some(unsafe { Port::<u8>::new(DATA_PORT).read() })
+}
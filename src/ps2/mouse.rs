@@ -0,0 +1,210 @@
+//! A standard PS/2 mouse sending three byte packets on IRQ 12.
+
+use super::{Ps2Error, Ps2Port};
+use crate::apic::ApicError;
+use crate::interrupts;
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+pub const MOUSE_IRQ: u8 = 12;
+/// Received bytes that are kept until a reader catches up.
+pub const RECEIVE_QUEUE_SIZE: usize = 256;
+
+const SET_DEFAULTS: u8 = 0xf6;
+const ENABLE_REPORTING: u8 = 0xf4;
+
+const PACKET_SIZE: usize = 3;
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+/// Set in every first byte, which finds the start of packets again after
+/// lost bytes.
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_NEGATIVE: u8 = 1 << 4;
+const Y_NEGATIVE: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+#[derive(Debug)]
+pub enum MouseError {
+    /// There is already a `MouseStream`.
+    AlreadyOpen,
+    /// `ps2::init` found no device on the second port.
+    NoMouse,
+    Ps2(Ps2Error),
+    Irq(ApicError),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Movement since the last event and the buttons held. `dy` grows
+/// downwards, like screen coordinates.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub buttons: MouseButtons,
+}
+
+/// Assembles packets from the bytes the mouse sends.
+#[derive(Debug, Default)]
+pub struct PacketDecoder {
+    bytes: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl PacketDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < PACKET_SIZE {
+            return None;
+        }
+        self.len = 0;
+
+        let [flags, x, y] = self.bytes;
+        // overflowed movement is garbage
+        let movement = |value: u8, negative: u8, overflow: u8| match flags {
+            flags if flags & overflow != 0 => 0,
+            flags if flags & negative != 0 => value as i16 - 0x100,
+            _ => value as i16,
+        };
+        Some(MouseEvent {
+            dx: movement(x, X_NEGATIVE, X_OVERFLOW),
+            dy: -movement(y, Y_NEGATIVE, Y_OVERFLOW),
+            buttons: MouseButtons {
+                left: flags & LEFT_BUTTON != 0,
+                right: flags & RIGHT_BUTTON != 0,
+                middle: flags & MIDDLE_BUTTON != 0,
+            },
+        })
+    }
+}
+
+static RECEIVE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static OPEN: AtomicBool = AtomicBool::new(false);
+
+/// Queues a byte from the mouse. Called by the IRQ 12 handler.
+pub(crate) fn on_interrupt() {
+    let byte = match super::read_from(Ps2Port::Second) {
+        Some(byte) => byte,
+        None => return,
+    };
+    match RECEIVE_QUEUE.try_get() {
+        Ok(queue) => {
+            if queue.push(byte).is_err() {
+                log::warn!("mouse queue full, dropping input");
+            } else {
+                WAKER.wake();
+            }
+        }
+        Err(_) => log::warn!("mouse queue uninitialized"),
+    }
+}
+
+/// Events of the mouse on the second PS/2 port. Only one stream can exist,
+/// it turns on reporting and the interrupt when created.
+pub struct MouseStream {
+    decoder: PacketDecoder,
+}
+
+impl MouseStream {
+    pub fn new() -> Result<Self, MouseError> {
+        if !matches!(super::ports(), Some(ports) if ports.second) {
+            return Err(MouseError::NoMouse);
+        }
+        if OPEN.swap(true, Ordering::AcqRel) {
+            return Err(MouseError::AlreadyOpen);
+        }
+        // a failed attempt leaves the stream free to be opened again
+        if let Err(err) = Self::enable() {
+            OPEN.store(false, Ordering::Release);
+            return Err(err);
+        }
+        Ok(Self {
+            decoder: PacketDecoder::new(),
+        })
+    }
+
+    fn enable() -> Result<(), MouseError> {
+        RECEIVE_QUEUE.get_or_init(|| ArrayQueue::new(RECEIVE_QUEUE_SIZE));
+        super::send(Ps2Port::Second, &[SET_DEFAULTS, ENABLE_REPORTING]).map_err(MouseError::Ps2)?;
+        interrupts::enable_irq(MOUSE_IRQ).map_err(MouseError::Irq)
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let queue = RECEIVE_QUEUE
+            .try_get()
+            .expect("The queue hasn't been initialized yet");
+
+        let mut registered = false;
+        loop {
+            match queue.pop() {
+                Ok(byte) => {
+                    if let Some(event) = self.decoder.add_byte(byte) {
+                        if registered {
+                            WAKER.take();
+                        }
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                Err(_) if registered => return Poll::Pending,
+                Err(_) => {
+                    WAKER.register(cx.waker());
+                    registered = true;
+                }
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_packets() {
+    let mut decoder = PacketDecoder::new();
+    assert_eq!(decoder.add_byte(ALWAYS_ONE | LEFT_BUTTON), None);
+    assert_eq!(decoder.add_byte(5), None);
+    let event = decoder.add_byte(3).unwrap();
+    assert_eq!((event.dx, event.dy), (5, -3));
+    assert!(event.buttons.left && !event.buttons.right);
+
+    let flags = ALWAYS_ONE | X_NEGATIVE | Y_NEGATIVE | MIDDLE_BUTTON;
+    decoder.add_byte(flags);
+    decoder.add_byte(0xff);
+    let event = decoder.add_byte(0xfe).unwrap();
+    assert_eq!((event.dx, event.dy), (-1, 2));
+    assert!(event.buttons.middle);
+}
+
+#[test_case]
+fn test_packet_resync() {
+    let mut decoder = PacketDecoder::new();
+    // a byte without the always set bit can't start a packet
+    assert_eq!(decoder.add_byte(0x10), None);
+    decoder.add_byte(ALWAYS_ONE | X_OVERFLOW);
+    decoder.add_byte(0x80);
+    let event = decoder.add_byte(0).unwrap();
+    assert_eq!((event.dx, event.dy), (0, 0));
+    assert_eq!(event.buttons, MouseButtons::default());
+}
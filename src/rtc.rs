@@ -0,0 +1,298 @@
+//! Driver for the CMOS real-time clock. The hardware clock is read once at
+//! boot and wall-clock time is derived from the monotonic clock afterwards,
+//! which is cheap enough to timestamp log lines with.
+
+use crate::interrupts::{self, InterruptIndex};
+use crate::{acpi, apic, time};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::instructions::port::Port;
+
+const RTC_IRQ: u8 = InterruptIndex::Rtc.as_u8() - interrupts::PIC_1_OFFSET;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Index and data ports, which must be used as a pair.
+static CMOS: Mutex<(Port<u8>, Port<u8>)> = Mutex::new((Port::new(0x70), Port::new(0x71)));
+
+/// Unix time at which the tick count was zero, valid once `INITIALIZED`.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum RtcError {
+    /// Periodic rates are powers of two from 2 to 8192 Hz.
+    InvalidFrequency(u32),
+    Irq(apic::ApicError),
+}
+
+fn read_register(register: u8) -> u8 {
+    cpu_interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        unsafe {
+            cmos.0.write(register);
+            cmos.1.read()
+        }
+    })
+}
+
+fn write_register(register: u8, value: u8) {
+    cpu_interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        unsafe {
+            cmos.0.write(register);
+            cmos.1.write(value);
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC. Dates before that are not
+    /// representable.
+    pub fn to_unix(&self) -> u64 {
+        // days_from_civil from Howard Hinnant's date algorithms
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = self.month as i64;
+        let day_of_year =
+            (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        days.max(0) as u64 * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix(seconds: u64) -> Self {
+        // civil_from_days, the inverse of the above
+        let days = (seconds / SECONDS_PER_DAY) as i64 + 719_468;
+        let rest = seconds % SECONDS_PER_DAY;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
+        } else {
+            shifted_month - 9
+        };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rest / 3600) as u8,
+            minute: (rest / 60 % 60) as u8,
+            second: (rest % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// The registers as stored, before BCD and 12 hour decoding.
+#[derive(PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw(century_register: u8) -> RawTime {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: match century_register {
+            0 => 0,
+            register => read_register(register),
+        },
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Reads the date and time from the hardware clock, which is assumed to
+/// run on UTC. Takes up to a few milliseconds if an update is in progress.
+pub fn read() -> DateTime {
+    let century_register = acpi::get()
+        .and_then(|acpi| acpi.fadt())
+        .map_or(0, |fadt| fadt.century);
+    // an update might start between the status check and the reads, so
+    // read until two passes agree
+    let mut raw = read_raw(century_register);
+    loop {
+        let again = read_raw(century_register);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    let status = read_register(REG_STATUS_B);
+    let pm = raw.hour & HOUR_PM != 0;
+    let decode = |value: u8| {
+        if status & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+    let mut hour = decode(raw.hour & !HOUR_PM);
+    if status & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    let century = match decode(raw.century) {
+        0 => 20,
+        century => century as u16,
+    };
+    DateTime {
+        year: century * 100 + decode(raw.year) as u16,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    }
+}
+
+/// Reads the hardware clock once so `now` can work without it. Needs ACPI
+/// to be initialized first to find the century register.
+pub fn init() -> DateTime {
+    let date_time = read();
+    let boot = date_time.to_unix().saturating_sub(time::uptime().as_secs());
+    BOOT_TIME.store(boot, Ordering::Relaxed);
+    INITIALIZED.store(true, Ordering::Release);
+    date_time
+}
+
+/// The current wall-clock time in seconds since the Unix epoch, or `None`
+/// before `init`.
+pub fn unix_time() -> Option<u64> {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return None;
+    }
+    Some(BOOT_TIME.load(Ordering::Relaxed) + time::uptime().as_secs())
+}
+
+pub fn now() -> Option<DateTime> {
+    unix_time().map(DateTime::from_unix)
+}
+
+/// Starts the periodic interrupt at `frequency` Hz on IRQ 8.
+pub fn enable_periodic(frequency: u32) -> Result<(), RtcError> {
+    if !(2..=8192).contains(&frequency) || !frequency.is_power_of_two() {
+        return Err(RtcError::InvalidFrequency(frequency));
+    }
+    // the rate selects 32768 >> (rate - 1) Hz
+    let rate = 16 - frequency.trailing_zeros() as u8;
+    cpu_interrupts::without_interrupts(|| {
+        let status_a = read_register(REG_STATUS_A);
+        write_register(REG_STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b | STATUS_B_PERIODIC);
+        // no further interrupts are raised until status C has been read
+        read_register(REG_STATUS_C);
+    });
+    interrupts::enable_irq(RTC_IRQ).map_err(RtcError::Irq)
+}
+
+pub fn disable_periodic() {
+    cpu_interrupts::without_interrupts(|| {
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b & !STATUS_B_PERIODIC);
+    });
+}
+
+/// Number of periodic interrupts so far.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// Called from the RTC interrupt handler.
+pub(crate) fn on_interrupt() {
+    let status_c = read_register(REG_STATUS_C);
+    if status_c & STATUS_C_PERIODIC != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test_case]
+fn test_unix_time_round_trip() {
+    let date_time = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 37,
+        second: 59,
+    };
+    assert_eq!(date_time.to_unix(), 1_709_213_879);
+    assert_eq!(DateTime::from_unix(date_time.to_unix()), date_time);
+    assert_eq!(DateTime::from_unix(0).year, 1970);
+}
+
+#[test_case]
+fn test_bcd_decoding() {
+    assert_eq!(from_bcd(0x59), 59);
+    assert_eq!(from_bcd(0x00), 0);
+}
@@ -1,15 +1,149 @@
+use crate::apic::ApicError;
+use crate::interrupts;
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3f8;
+pub const COM1_IRQ: u8 = 4;
+/// Received bytes that are kept until a reader catches up.
+pub const RECEIVE_QUEUE_SIZE: usize = 256;
+
+const INTERRUPT_ENABLE: u16 = COM1 + 1;
+const MODEM_CONTROL: u16 = COM1 + 4;
+const LINE_STATUS: u16 = COM1 + 5;
+const IER_RECEIVED_DATA: u8 = 1 << 0;
+const MCR_DTR_RTS_OUT2: u8 = 0x0b;
+const MCR_LOOPBACK: u8 = 1 << 4;
+const LSR_DATA_READY: u8 = 1 << 0;
 
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3f8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+#[derive(Debug)]
+pub enum SerialError {
+    /// There is already a `SerialStream`.
+    AlreadyOpen,
+    Irq(ApicError),
+}
+
+static RECEIVE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static OPEN: AtomicBool = AtomicBool::new(false);
+/// Bytes the interrupt handler had no room for. They are reported by the
+/// stream, since a warning logged in the handler goes out on COM1 and may
+/// come straight back in.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Moves every byte waiting in the receive buffer to the queue. Called by
+/// the COM1 interrupt handler, so it reads the port directly instead of
+/// taking `SERIAL1`.
+pub(crate) fn on_interrupt() {
+    let mut line_status = Port::<u8>::new(LINE_STATUS);
+    let mut data = Port::<u8>::new(COM1);
+    let mut received = false;
+    while unsafe { line_status.read() } & LSR_DATA_READY != 0 {
+        let byte = unsafe { data.read() };
+        match RECEIVE_QUEUE.try_get() {
+            Ok(queue) => {
+                if queue.push(byte).is_err() {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+                received = true;
+            }
+            Err(_) => {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    if received {
+        WAKER.wake();
+    }
+}
+
+/// Echoes everything sent on COM1 back to the receiver instead of the
+/// outside world, for testing input without a terminal.
+pub fn set_loopback(enabled: bool) {
+    let mut modem_control = Port::<u8>::new(MODEM_CONTROL);
+    let value = if enabled {
+        MCR_DTR_RTS_OUT2 | MCR_LOOPBACK
+    } else {
+        MCR_DTR_RTS_OUT2
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _port = SERIAL1.lock();
+        unsafe { modem_control.write(value) };
+    });
+}
+
+/// Bytes received on COM1. Only one stream can exist, it enables the
+/// receive interrupt when created.
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> Result<Self, SerialError> {
+        if OPEN.swap(true, Ordering::AcqRel) {
+            return Err(SerialError::AlreadyOpen);
+        }
+        // a failed attempt leaves the stream free to be opened again
+        if let Err(err) = interrupts::enable_irq(COM1_IRQ) {
+            OPEN.store(false, Ordering::Release);
+            return Err(SerialError::Irq(err));
+        }
+        RECEIVE_QUEUE.get_or_init(|| ArrayQueue::new(RECEIVE_QUEUE_SIZE));
+        let mut interrupt_enable = Port::<u8>::new(INTERRUPT_ENABLE);
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let _port = SERIAL1.lock();
+            unsafe { interrupt_enable.write(IER_RECEIVED_DATA) };
+        });
+        // bytes that arrived before the interrupt was routed raised no IRQ
+        x86_64::instructions::interrupts::without_interrupts(on_interrupt);
+        Ok(Self { _private: () })
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let queue = RECEIVE_QUEUE
+            .try_get()
+            .expect("The queue hasn't been initialized yet");
+        let dropped = DROPPED.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!("serial receive queue full, dropped {} bytes", dropped);
+        }
+
+        if let Ok(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Ok(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            _ => Poll::Pending,
+        }
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
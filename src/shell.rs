@@ -0,0 +1,131 @@
+//! An interactive shell on the keyboard. The input line is edited in place
+//! on the bottom row of the screen, and submitted lines are dispatched to a
+//! registry of commands by their first word.
+
+pub mod commands;
+pub mod editor;
+
+pub use editor::LineEditor;
+
+use crate::task::keyboard;
+use crate::{println, vga_buffer};
+use alloc::string::String;
+use alloc::vec::Vec;
+use futures_util::StreamExt;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const PROMPT: &str = "> ";
+/// Lines moved by Page Up and Page Down, a screen minus the line kept.
+const SCROLL_LINES: usize = vga_buffer::BUFFER_HEIGHT - 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    /// Arguments, shown by `help`.
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: fn(&[&str]),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShellError {
+    UnknownCommand(String),
+    AlreadyRegistered(&'static str),
+}
+
+/// Commands added by other parts of the kernel at runtime.
+static COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+/// Makes `command` available in addition to the built-in ones.
+pub fn register(command: Command) -> Result<(), ShellError> {
+    let builtin = commands::BUILTINS
+        .iter()
+        .any(|other| other.name == command.name);
+    interrupts::without_interrupts(|| {
+        // checked under the same lock as the push, so a name is never added twice
+        let mut commands = COMMANDS.lock();
+        if builtin || commands.iter().any(|other| other.name == command.name) {
+            return Err(ShellError::AlreadyRegistered(command.name));
+        }
+        commands.push(command);
+        Ok(())
+    })
+}
+
+pub fn find(name: &str) -> Option<Command> {
+    commands::BUILTINS
+        .iter()
+        .copied()
+        .find(|command| command.name == name)
+        .or_else(|| {
+            interrupts::without_interrupts(|| {
+                COMMANDS
+                    .lock()
+                    .iter()
+                    .copied()
+                    .find(|command| command.name == name)
+            })
+        })
+}
+
+/// All commands sorted by name.
+pub fn commands() -> Vec<Command> {
+    let mut all: Vec<Command> = commands::BUILTINS.to_vec();
+    interrupts::without_interrupts(|| all.extend(COMMANDS.lock().iter().copied()));
+    all.sort_by_key(|command| command.name);
+    all
+}
+
+/// Runs the command named by the first word of `line` with the rest as its
+/// arguments. Blank lines do nothing.
+pub fn execute(line: &str) -> Result<(), ShellError> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    let command = find(name).ok_or_else(|| ShellError::UnknownCommand(String::from(*name)))?;
+    (command.run)(args);
+    Ok(())
+}
+
+fn redraw(line: &str, cursor: Option<usize>) {
+    let text = String::from(PROMPT) + line;
+    vga_buffer::rewrite_line(&text, cursor.map(|cursor| PROMPT.len() + cursor));
+}
+
+/// Reads commands from the keyboard forever. Needs the keyboard service
+/// running to get any input.
+pub async fn run() {
+    let mut keys = keyboard::subscribe_keys();
+    // leave room for the cursor behind the last character
+    let mut editor = LineEditor::new(vga_buffer::BUFFER_WIDTH - PROMPT.len() - 1);
+
+    println!();
+    redraw(editor.line(), Some(editor.cursor()));
+    while let Some(key) = keys.next().await {
+        let key = match key {
+            DecodedKey::RawKey(KeyCode::PageUp) => {
+                vga_buffer::scroll_back(SCROLL_LINES);
+                continue;
+            }
+            DecodedKey::RawKey(KeyCode::PageDown) => {
+                vga_buffer::scroll_forward(SCROLL_LINES);
+                continue;
+            }
+            // the input line is only on the console
+            key if vga_buffer::active_terminal() == vga_buffer::CONSOLE => key,
+            _ => continue,
+        };
+        if let Some(line) = editor.handle(key) {
+            redraw(&line, None);
+            println!();
+            if let Err(ShellError::UnknownCommand(name)) = execute(&line) {
+                println!("{}: command not found, try help", name);
+            }
+        }
+        redraw(editor.line(), Some(editor.cursor()));
+    }
+}
@@ -0,0 +1,275 @@
+use super::Command;
+use crate::memory;
+use crate::task::keyboard::{self, Layout};
+use crate::{acpi, allocator, dmesg, pci, percpu, println, rtc, thread, time, vga_buffer};
+use core::sync::atomic::Ordering;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+pub static BUILTINS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "echo",
+        usage: "[text...]",
+        help: "print the arguments",
+        run: echo,
+    },
+    Command {
+        name: "clear",
+        usage: "",
+        help: "clear the screen",
+        run: clear,
+    },
+    Command {
+        name: "mem",
+        usage: "",
+        help: "show frame and heap usage",
+        run: mem,
+    },
+    Command {
+        name: "ps",
+        usage: "",
+        help: "list CPUs, their tasks and the threads",
+        run: ps,
+    },
+    Command {
+        name: "walk",
+        usage: "<address>",
+        help: "walk the page table for a hex address",
+        run: walk,
+    },
+    Command {
+        name: "uptime",
+        usage: "",
+        help: "show the time since boot and the date",
+        run: uptime,
+    },
+    Command {
+        name: "dmesg",
+        usage: "[lines]",
+        help: "show the kernel messages",
+        run: dmesg,
+    },
+    Command {
+        name: "lspci",
+        usage: "[-v]",
+        help: "list the PCI devices, -v with BARs and capabilities",
+        run: lspci,
+    },
+    Command {
+        name: "layout",
+        usage: "[name]",
+        help: "show or set the keyboard layout",
+        run: layout,
+    },
+    Command {
+        name: "reboot",
+        usage: "",
+        help: "reset the machine",
+        run: reboot,
+    },
+    Command {
+        name: "poweroff",
+        usage: "",
+        help: "turn the machine off",
+        run: poweroff,
+    },
+];
+
+fn help(_: &[&str]) {
+    for command in super::commands() {
+        println!("{:<8} {:<10} {}", command.name, command.usage, command.help);
+    }
+}
+
+fn echo(args: &[&str]) {
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            crate::print!(" ");
+        }
+        crate::print!("{}", arg);
+    }
+    println!();
+}
+
+fn clear(_: &[&str]) {
+    vga_buffer::clear_screen();
+}
+
+fn mem(_: &[&str]) {
+    if let Some(stats) = memory::frame_stats() {
+        println!(
+            "frames: {} KiB used, {} KiB free, {} KiB total",
+            stats.used * 4,
+            stats.free * 4,
+            stats.total * 4
+        );
+    }
+    println!(
+        "heap: {} KiB reserved, limit {} KiB",
+        allocator::heap_size() / 1024,
+        allocator::heap_limit() / 1024
+    );
+}
+
+fn ps(_: &[&str]) {
+    println!("CPU APIC ONLINE TASKS");
+    for cpu in percpu::cpus() {
+        println!(
+            "{:<3} {:<4} {:<6} {}",
+            cpu.index,
+            cpu.apic_id(),
+            cpu.is_bsp() || cpu.online.load(Ordering::Acquire),
+            cpu.tasks.load(Ordering::Relaxed)
+        );
+    }
+    println!("THREAD STATE");
+    for (id, state) in thread::threads() {
+        println!("{:<6} {:?}", id.as_u64(), state);
+    }
+}
+
+fn parse_address(arg: &str) -> Option<VirtAddr> {
+    let digits = arg.trim_start_matches("0x");
+    let addr = u64::from_str_radix(digits, 16).ok()?;
+    VirtAddr::try_new(addr).ok()
+}
+
+fn walk(args: &[&str]) {
+    let addr = match args {
+        [arg] => parse_address(arg),
+        _ => None,
+    };
+    let addr = match addr {
+        Some(addr) => addr,
+        None => {
+            println!("usage: walk <hex address>");
+            return;
+        }
+    };
+    let mut mapped = None;
+    for (i, entry) in memory::walk(addr).iter().flatten().enumerate() {
+        let level = 4 - i;
+        println!(
+            "L{} {:#011x} {:?}",
+            level,
+            entry.addr().as_u64(),
+            entry.flags()
+        );
+        let flags = entry.flags();
+        if flags.contains(PageTableFlags::PRESENT)
+            && (level == 1 || flags.contains(PageTableFlags::HUGE_PAGE))
+        {
+            // each level above 1 covers 9 more bits of the address
+            let page_mask = (1u64 << (12 + 9 * (level - 1))) - 1;
+            mapped = Some(entry.addr().as_u64() + (addr.as_u64() & page_mask));
+        }
+    }
+    if let Some(phys) = mapped {
+        println!("{:#x} -> {:#x}", addr.as_u64(), phys);
+    } else {
+        println!("{:#x} is not mapped", addr.as_u64());
+    }
+}
+
+fn uptime(_: &[&str]) {
+    let uptime = time::uptime();
+    println!("up {}.{:03}s", uptime.as_secs(), uptime.subsec_millis());
+    if let Some(now) = rtc::now() {
+        println!("{} UTC", now);
+    }
+}
+
+fn dmesg(args: &[&str]) {
+    let count = match args {
+        [] => Some(dmesg::CAPACITY),
+        [arg] => arg.parse().ok(),
+        _ => None,
+    };
+    let count = match count {
+        Some(count) => count,
+        None => {
+            println!("usage: dmesg [lines]");
+            return;
+        }
+    };
+    let start = dmesg::next_seq().saturating_sub(count as u64);
+    // the records are already in the buffer, printing them again would push
+    // out ones not shown yet
+    for record in dmesg::since(start) {
+        vga_buffer::print_unrecorded(vga_buffer::CONSOLE, format_args!("{}\n", record));
+    }
+}
+
+fn lspci(args: &[&str]) {
+    let verbose = match args {
+        [] => false,
+        ["-v"] => true,
+        _ => {
+            println!("usage: lspci [-v]");
+            return;
+        }
+    };
+    for device in pci::devices() {
+        println!("{}", device);
+        if !verbose {
+            continue;
+        }
+        if device.interrupt_pin != 0 {
+            println!(
+                "    INT{}# on IRQ {}",
+                (b'A' + device.interrupt_pin - 1) as char,
+                device.interrupt_line
+            );
+        }
+        for (i, bar) in device.bars.iter().enumerate() {
+            if let Some(bar) = bar {
+                println!("    BAR{}: {}", i, bar);
+            }
+        }
+        for capability in &device.capabilities {
+            println!(
+                "    capability {:#04x} at {:#04x}",
+                capability.id, capability.offset
+            );
+        }
+    }
+}
+
+fn layout(args: &[&str]) {
+    let layout = match args {
+        [] => {
+            println!("{}", keyboard::layout().name());
+            return;
+        }
+        [name] => Layout::from_name(name),
+        _ => None,
+    };
+    match layout {
+        Some(layout) => keyboard::set_layout(layout),
+        None => {
+            crate::print!("usage: layout [name], one of");
+            for layout in Layout::ALL.iter() {
+                crate::print!(" {}", layout.name());
+            }
+            println!();
+        }
+    }
+}
+
+fn reboot(_: &[&str]) {
+    if let Err(err) = acpi::reset() {
+        println!("reboot failed: {:?}", err);
+    }
+}
+
+fn poweroff(_: &[&str]) {
+    if let Err(err) = acpi::shutdown() {
+        println!("poweroff failed: {:?}", err);
+    }
+}
@@ -0,0 +1,135 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use pc_keyboard::{DecodedKey, KeyCode};
+
+pub const MAX_HISTORY: usize = 32;
+
+/// A single line of input with a cursor and a history of submitted lines.
+/// Only printable ASCII is accepted since that is all the screen can show.
+pub struct LineEditor {
+    line: String,
+    /// Byte offset into `line`, which is also the column.
+    cursor: usize,
+    max_len: usize,
+    history: VecDeque<String>,
+    /// Position in `history` while browsing it, 0 being the latest line.
+    browsing: Option<usize>,
+    /// The line being typed before browsing started.
+    draft: String,
+}
+
+impl LineEditor {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            line: String::new(),
+            cursor: 0,
+            max_len,
+            history: VecDeque::new(),
+            browsing: None,
+            draft: String::new(),
+        }
+    }
+
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Submitted lines, the most recent first.
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    /// Applies a key press and returns the line once enter is pressed.
+    pub fn handle(&mut self, key: DecodedKey) -> Option<String> {
+        match key {
+            DecodedKey::Unicode('\n') | DecodedKey::RawKey(KeyCode::NumpadEnter) => {
+                return Some(self.submit())
+            }
+            DecodedKey::Unicode('\u{8}') => self.backspace(),
+            DecodedKey::Unicode('\u{7f}') | DecodedKey::RawKey(KeyCode::Delete) => self.delete(),
+            DecodedKey::Unicode(ch) if ch == ' ' || ch.is_ascii_graphic() => self.insert(ch),
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => self.cursor = self.cursor.saturating_sub(1),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => {
+                self.cursor = (self.cursor + 1).min(self.line.len())
+            }
+            DecodedKey::RawKey(KeyCode::Home) => self.cursor = 0,
+            DecodedKey::RawKey(KeyCode::End) => self.cursor = self.line.len(),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => self.older(),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => self.newer(),
+            _ => {}
+        }
+        None
+    }
+
+    fn insert(&mut self, ch: char) {
+        if self.line.len() < self.max_len {
+            self.line.insert(self.cursor, ch);
+            self.cursor += 1;
+        }
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.line.remove(self.cursor);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.line.len() {
+            self.line.remove(self.cursor);
+        }
+    }
+
+    fn show(&mut self, line: String) {
+        self.line = line;
+        self.cursor = self.line.len();
+    }
+
+    fn older(&mut self) {
+        let next = self.browsing.map_or(0, |i| i + 1);
+        if let Some(line) = self.history.get(next) {
+            if self.browsing.is_none() {
+                self.draft = core::mem::take(&mut self.line);
+            }
+            self.browsing = Some(next);
+            let line = line.clone();
+            self.show(line);
+        }
+    }
+
+    fn newer(&mut self) {
+        match self.browsing {
+            Some(0) => {
+                self.browsing = None;
+                let draft = core::mem::take(&mut self.draft);
+                self.show(draft);
+            }
+            Some(i) => {
+                self.browsing = Some(i - 1);
+                let line = self.history[i - 1].clone();
+                self.show(line);
+            }
+            None => {}
+        }
+    }
+
+    fn submit(&mut self) -> String {
+        let line = core::mem::take(&mut self.line);
+        self.cursor = 0;
+        self.browsing = None;
+        self.draft.clear();
+        let trimmed = line.trim();
+        if !trimmed.is_empty() && self.history.front().map(String::as_str) != Some(trimmed) {
+            if self.history.len() == MAX_HISTORY {
+                self.history.pop_back();
+            }
+            self.history.push_front(String::from(trimmed));
+        }
+        line
+    }
+}
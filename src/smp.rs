@@ -0,0 +1,289 @@
+//! Startup of the application processors. Each of them gets its own GDT,
+//! TSS and per-CPU data and then runs an `Executor`. Threads keep running
+//! on the bootstrap processor only, and since kernel mappings are never
+//! shot down on other CPUs, memory that is unmapped must not have been
+//! touched by an application processor.
+
+use crate::interrupts::InterruptIndex;
+use crate::memory::bitmap::LOW_MEMORY_END;
+use crate::memory::stack::{KernelStack, StackError, StackKind, MAX_STACK_PAGES};
+use crate::percpu::{self, PerCpu, MAX_CPUS};
+use crate::task::executor::Executor;
+use crate::{acpi, apic, gdt, interrupts, memory};
+use alloc::boxed::Box;
+use core::arch::global_asm;
+use core::future::Future;
+use core::ptr::addr_of;
+use core::sync::atomic::Ordering;
+use x86_64::registers::control::{Cr0, Cr4, Cr4Flags};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// The trampoline is copied to a page below 1 MiB and entered in real mode
+// at its first byte. It loads the control registers of the bootstrap
+// processor, jumps straight to long mode through a temporary GDT and calls
+// the entry point on the stack stored in its data area. The data area sits
+// at fixed offsets since real mode code can't address it by symbol.
+global_asm!(
+    r#"
+.pushsection .rodata.ap_trampoline, "a"
+.global __ap_trampoline_start
+.global __ap_trampoline_long_mode
+.global __ap_trampoline_end
+.code16
+__ap_trampoline_start:
+    cli
+    cld
+    mov ax, cs
+    mov ds, ax
+    mov eax, dword ptr [0x12c]
+    mov cr4, eax
+    mov eax, dword ptr [0x128]
+    mov cr3, eax
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+    lgdt [0x118]
+    mov eax, dword ptr [0x130]
+    mov cr0, eax
+    // jmp far dword ptr [0x120]
+    .byte 0x66, 0xff, 0x2e
+    .word 0x120
+.code64
+__ap_trampoline_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov rsp, qword ptr [rip + __ap_trampoline_stack]
+    mov rdi, qword ptr [rip + __ap_trampoline_arg]
+    call qword ptr [rip + __ap_trampoline_entry]
+    ud2
+.org 0x100
+    .quad 0
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+.org 0x118
+    .word 23
+    .long 0
+.org 0x120
+    .long 0
+    .word 0x08
+.org 0x128
+    .long 0
+    .long 0
+    .long 0
+.org 0x138
+__ap_trampoline_stack:
+    .quad 0
+__ap_trampoline_arg:
+    .quad 0
+__ap_trampoline_entry:
+    .quad 0
+__ap_trampoline_end:
+.popsection
+"#
+);
+
+extern "C" {
+    static __ap_trampoline_start: u8;
+    static __ap_trampoline_long_mode: u8;
+    static __ap_trampoline_end: u8;
+}
+
+const GDT_OFFSET: usize = 0x100;
+const GDT_BASE_OFFSET: usize = 0x11a;
+const FAR_JUMP_OFFSET: usize = 0x120;
+const CR3_OFFSET: usize = 0x128;
+const CR4_OFFSET: usize = 0x12c;
+const CR0_OFFSET: usize = 0x130;
+const STACK_OFFSET: usize = 0x138;
+const ARG_OFFSET: usize = 0x140;
+const ENTRY_OFFSET: usize = 0x148;
+
+/// How long to wait for an application processor to report in.
+const STARTUP_TIMEOUT_MS: u64 = 100;
+
+#[derive(Debug)]
+pub enum SmpError {
+    ApicDisabled,
+    NoMadt,
+    NoLowMemory,
+    Map(MapToError<Size4KiB>),
+    Stack(StackError),
+    /// The processor with this APIC id did not start.
+    Timeout(u8),
+    NoSuchCpu(usize),
+}
+
+impl From<MapToError<Size4KiB>> for SmpError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        SmpError::Map(err)
+    }
+}
+
+impl From<StackError> for SmpError {
+    fn from(err: StackError) -> Self {
+        SmpError::Stack(err)
+    }
+}
+
+/// Copies the trampoline to a free page below 1 MiB that is identity
+/// mapped, since paging gets enabled while executing it.
+fn install_trampoline() -> Result<PhysFrame, SmpError> {
+    let frame = memory::with_kernel_memory(|mapper, frame_allocator| {
+        let frame = frame_allocator
+            .allocate_below(PhysAddr::new(LOW_MEMORY_END))
+            .ok_or(SmpError::NoLowMemory)?;
+        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+            Err(err) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                return Err(SmpError::Map(err));
+            }
+        }
+        Ok(frame)
+    })
+    .ok_or(SmpError::NoLowMemory)??;
+
+    let base = frame.start_address().as_u64();
+    let kernel_table = memory::kernel_level_4_frame().start_address();
+    let trampoline = VirtAddr::new(base);
+    unsafe {
+        let start = addr_of!(__ap_trampoline_start);
+        let len = addr_of!(__ap_trampoline_end) as usize - start as usize;
+        let long_mode = addr_of!(__ap_trampoline_long_mode) as usize - start as usize;
+        core::ptr::copy_nonoverlapping(start, trampoline.as_mut_ptr::<u8>(), len);
+
+        let write = |offset: usize, value: u32| {
+            (trampoline + offset)
+                .as_mut_ptr::<u32>()
+                .write_unaligned(value)
+        };
+        write(GDT_BASE_OFFSET, (base as usize + GDT_OFFSET) as u32);
+        write(FAR_JUMP_OFFSET, (base as usize + long_mode) as u32);
+        write(CR3_OFFSET, kernel_table.as_u64() as u32);
+        // PCID can only be turned on from long mode
+        let cr4 = Cr4::read() - Cr4Flags::PCID;
+        write(CR4_OFFSET, cr4.bits() as u32);
+        write(CR0_OFFSET, Cr0::read_raw() as u32);
+        (trampoline + ENTRY_OFFSET)
+            .as_mut_ptr::<u64>()
+            .write(ap_entry as extern "C" fn(&'static PerCpu) -> ! as usize as u64);
+    }
+    Ok(frame)
+}
+
+fn remove_trampoline(frame: PhysFrame) {
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        let page =
+            Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    });
+}
+
+/// Starts every enabled processor listed in the MADT and returns how many
+/// came up. Needs the APIC to be set up on the bootstrap processor.
+pub fn start_aps() -> Result<usize, SmpError> {
+    if !apic::is_enabled() {
+        return Err(SmpError::ApicDisabled);
+    }
+    let madt = acpi::get()
+        .and_then(|acpi| acpi.madt())
+        .ok_or(SmpError::NoMadt)?;
+    // the trampoline loads CR3 with a 32 bit move
+    assert!(memory::kernel_level_4_frame().start_address().as_u64() < 1 << 32);
+
+    let bsp = percpu::current().apic_id();
+    let frame = install_trampoline()?;
+    let trampoline = VirtAddr::new(frame.start_address().as_u64());
+    let mut started = 0;
+    let result = madt
+        .processors
+        .iter()
+        .filter(|p| p.enabled && p.apic_id != bsp)
+        .take(MAX_CPUS - 1)
+        .enumerate()
+        .try_for_each(|(i, processor)| {
+            let cpu: &'static PerCpu = Box::leak(Box::new(PerCpu::new(i + 1)));
+            let stack = KernelStack::allocate(MAX_STACK_PAGES, StackKind::Ap(i + 1))?;
+            unsafe {
+                (trampoline + STACK_OFFSET)
+                    .as_mut_ptr::<u64>()
+                    .write_volatile(stack.leak().as_u64());
+                (trampoline + ARG_OFFSET)
+                    .as_mut_ptr::<u64>()
+                    .write_volatile(cpu as *const PerCpu as u64);
+            }
+            let page = (frame.start_address().as_u64() >> 12) as u8;
+            apic::start_ap(processor.apic_id, page).map_err(|_| SmpError::ApicDisabled)?;
+            for _ in 0..STARTUP_TIMEOUT_MS {
+                if cpu.online.load(Ordering::Acquire) {
+                    started += 1;
+                    return Ok(());
+                }
+                apic::busy_wait(1000);
+            }
+            Err(SmpError::Timeout(processor.apic_id))
+        });
+    match &result {
+        // a processor that timed out might still run the trampoline later
+        Err(SmpError::Timeout(_)) => {}
+        _ => remove_trampoline(frame),
+    }
+    result.map(|()| started)
+}
+
+extern "C" fn ap_entry(cpu: &'static PerCpu) -> ! {
+    percpu::install(cpu);
+    gdt::init_ap().expect("allocating the interrupt stacks failed");
+    interrupts::init_idt();
+    apic::init_ap().expect("local APIC unavailable");
+    cpu.online.store(true, Ordering::Release);
+    x86_64::instructions::interrupts::enable();
+
+    Executor::new().run()
+}
+
+/// Number of processors that are up, the bootstrap processor included.
+pub fn online_cpus() -> usize {
+    percpu::cpus()
+        .filter(|cpu| cpu.is_bsp() || cpu.online.load(Ordering::Acquire))
+        .count()
+}
+
+/// Wakes the CPU with the given index out of `hlt` so its executor looks at
+/// its queues again.
+pub fn notify(index: usize) {
+    if index == percpu::current().index {
+        return;
+    }
+    if let Some(cpu) = percpu::get(index) {
+        apic::send_ipi(cpu.apic_id(), InterruptIndex::Wakeup.as_u8());
+    }
+}
+
+/// Runs `future` on the executor of the CPU with the given index.
+pub fn spawn_on(
+    index: usize,
+    future: impl Future<Output = ()> + Send + 'static,
+) -> Result<(), SmpError> {
+    let cpu = percpu::get(index)
+        .filter(|cpu| cpu.is_bsp() || cpu.online.load(Ordering::Acquire))
+        .ok_or(SmpError::NoSuchCpu(index))?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        cpu.inbox.lock().push_back(Box::pin(future))
+    });
+    notify(index);
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use crate::memory::region::RegionError;
+use crate::memory::RegionKind;
+use crate::{memory, percpu, print, serial_print, thread, usermode};
+use core::arch::global_asm;
+use core::time::Duration;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+pub const SYSCALL_VECTOR: usize = 0x80;
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_YIELD: u64 = 2;
+pub const SYS_SLEEP: u64 = 3;
+pub const SYS_GETTID: u64 = 4;
+pub const SYS_MMAP: u64 = 5;
+pub const SYS_MUNMAP: u64 = 6;
+
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// Errors are returned to user space as negated values in `rax`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    BadFileDescriptor = -9,
+    NoMemory = -12,
+    Fault = -14,
+    InvalidArgument = -22,
+    NoSys = -38,
+}
+
+impl SyscallError {
+    pub fn as_u64(self) -> u64 {
+        self as i64 as u64
+    }
+}
+
+/// Registers saved by `__syscall_entry`, followed by the interrupt frame
+/// pushed by the CPU.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// The syscall number is passed in rax and up to three arguments in rdi,
+// rsi and rdx. The result is returned in rax.
+global_asm!(
+    r#"
+.global __syscall_entry
+__syscall_entry:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    call __syscall_dispatch
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    iretq
+"#
+);
+
+extern "C" {
+    fn __syscall_entry();
+}
+
+pub fn entry_address() -> VirtAddr {
+    VirtAddr::new(__syscall_entry as unsafe extern "C" fn() as usize as u64)
+}
+
+type SyscallHandler = fn(u64, u64, u64) -> Result<u64, SyscallError>;
+
+static SYSCALL_TABLE: [SyscallHandler; 7] = [
+    sys_exit, sys_write, sys_yield, sys_sleep, sys_gettid, sys_mmap, sys_munmap,
+];
+
+#[no_mangle]
+extern "C" fn __syscall_dispatch(frame: &mut SyscallFrame) {
+    percpu::restore_gs(frame.cs);
+    let result = match SYSCALL_TABLE.get(frame.rax as usize) {
+        Some(handler) => handler(frame.rdi, frame.rsi, frame.rdx),
+        None => Err(SyscallError::NoSys),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(err) => err.as_u64(),
+    };
+}
+
+fn user_slice(ptr: u64, len: u64) -> Result<&'static [u8], SyscallError> {
+    let start = VirtAddr::try_new(ptr).map_err(|_| SyscallError::Fault)?;
+    let end = ptr.checked_add(len).ok_or(SyscallError::Fault)?;
+    let mut page = start.align_down(4096u64).as_u64();
+    while page < end {
+        if !memory::is_user_accessible(VirtAddr::new(page), false) {
+            return Err(SyscallError::Fault);
+        }
+        page += 4096;
+    }
+    Ok(unsafe { core::slice::from_raw_parts(start.as_ptr(), len as usize) })
+}
+
+fn sys_exit(code: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    usermode::exit_to_kernel(code)
+}
+
+fn sys_write(fd: u64, ptr: u64, len: u64) -> Result<u64, SyscallError> {
+    let bytes = user_slice(ptr, len)?;
+    let text = core::str::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)?;
+    match fd {
+        STDOUT => print!("{}", text),
+        STDERR => serial_print!("{}", text),
+        _ => return Err(SyscallError::BadFileDescriptor),
+    }
+    Ok(len)
+}
+
+fn sys_yield(_: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    thread::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep(millis: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    thread::sleep(Duration::from_millis(millis));
+    Ok(0)
+}
+
+fn sys_gettid(_: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    Ok(thread::current().as_u64())
+}
+
+/// Reserves `len` bytes of zeroed memory that is mapped on first access.
+fn sys_mmap(len: u64, prot: u64, _: u64) -> Result<u64, SyscallError> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let mut flags = PageTableFlags::USER_ACCESSIBLE;
+    if prot & PROT_WRITE != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let address_space = thread::current_address_space().ok_or(SyscallError::NoMemory)?;
+    match address_space.reserve(len, flags) {
+        Ok(addr) => Ok(addr.as_u64()),
+        Err(RegionError::NoSpace) | Err(RegionError::Full) => Err(SyscallError::NoMemory),
+        Err(_) => Err(SyscallError::InvalidArgument),
+    }
+}
+
+/// Releases a region returned by `sys_mmap`.
+fn sys_munmap(addr: u64, _: u64, _: u64) -> Result<u64, SyscallError> {
+    let addr = VirtAddr::try_new(addr).map_err(|_| SyscallError::InvalidArgument)?;
+    let address_space = thread::current_address_space().ok_or(SyscallError::InvalidArgument)?;
+    match address_space.find_region(addr) {
+        Some(region) if region.start == addr && region.kind == RegionKind::Anonymous => {
+            address_space.remove_region(addr);
+            Ok(0)
+        }
+        _ => Err(SyscallError::InvalidArgument),
+    }
+}
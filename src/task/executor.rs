@@ -3,14 +3,18 @@ use crossbeam_queue::ArrayQueue;
 use x86_64::instructions::interrupts;
 
 use super::{Task, TaskId};
+use crate::percpu::{self, PerCpu};
+use crate::smp;
 use alloc::sync::Arc;
 use alloc::task::Wake;
+use core::sync::atomic::Ordering;
 use core::task::Waker;
 use core::task::{Context, Poll};
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,
     wakers: BTreeMap<TaskId, Waker>,
+    cpu: &'static PerCpu,
 }
 
 impl Executor {
@@ -19,6 +23,7 @@ impl Executor {
             tasks: BTreeMap::new(),
             task_queue: Arc::new(ArrayQueue::new(100)),
             wakers: BTreeMap::new(),
+            cpu: percpu::current(),
         }
     }
 
@@ -28,6 +33,7 @@ impl Executor {
             panic!("task with the same id already exists!");
         }
         self.task_queue.push(task_id).expect("Task queue full");
+        self.cpu.tasks.fetch_add(1, Ordering::Relaxed);
     }
 
     pub fn run_ready_tasks(&mut self) {
@@ -35,6 +41,7 @@ impl Executor {
             tasks,
             task_queue,
             wakers,
+            cpu,
         } = self;
         while let Ok(task_id) = task_queue.pop() {
             let task = match tasks.get_mut(&task_id) {
@@ -43,21 +50,31 @@ impl Executor {
             };
             let waker = wakers
                 .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone(), cpu.index));
 
             let mut cx = Context::from_waker(waker);
             match task.poll(&mut cx) {
                 Poll::Ready(()) => {
                     tasks.remove(&task_id);
                     wakers.remove(&task_id);
+                    cpu.tasks.fetch_sub(1, Ordering::Relaxed);
                 }
                 Poll::Pending => {}
             }
         }
     }
 
+    /// Spawns the tasks other CPUs sent through `smp::spawn_on`.
+    pub fn accept_remote_tasks(&mut self) {
+        let cpu = self.cpu;
+        while let Some(future) = interrupts::without_interrupts(|| cpu.inbox.lock().pop_front()) {
+            self.spawn(Task::new(future));
+        }
+    }
+
     pub fn run(&mut self) -> ! {
         loop {
+            self.accept_remote_tasks();
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
@@ -65,7 +82,7 @@ impl Executor {
 
     fn sleep_if_idle(&self) {
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if self.task_queue.is_empty() && self.cpu.inbox.lock().is_empty() {
             interrupts::enable_and_hlt();
         } else {
             interrupts::enable();
@@ -73,16 +90,27 @@ impl Executor {
     }
 }
 
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.cpu
+            .tasks
+            .fetch_sub(self.tasks.len(), Ordering::Relaxed);
+    }
+}
+
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    /// Index of the CPU whose executor owns the task.
+    cpu: usize,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>, cpu: usize) -> Waker {
         let task_waker = Self {
             task_id,
             task_queue,
+            cpu,
         };
 
         Waker::from(Arc::new(task_waker))
@@ -90,6 +118,7 @@ impl TaskWaker {
 
     fn wake_task(&self) {
         self.task_queue.push(self.task_id).expect("Task queue full");
+        smp::notify(self.cpu);
     }
 }
 
@@ -1,20 +1,41 @@
+//! Keyboard input. The interrupt handler queues raw scancodes, and the
+//! service in `run` decodes them with the selected layout and scancode set
+//! and hands key events and decoded keys to every subscriber.
+
+pub mod layout;
+
+pub use layout::Layout;
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
 use conquer_once::spin::OnceCell;
 use crossbeam_queue::ArrayQueue;
-use pc_keyboard::{layouts::Us104Key, HandleControl, KeyEvent, Keyboard, ScancodeSet1};
+use lazy_static::lazy_static;
+use pc_keyboard::{
+    layouts::Us104Key, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, Modifiers,
+    ScancodeSet1, ScancodeSet2,
+};
+use spin::Mutex;
 
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
-use crate::{print, println};
+use crate::ps2::{self, Ps2Error, Ps2Port};
+use crate::{print, vga_buffer};
+
+/// Keys each subscriber can fall behind by before input is dropped.
+const SUBSCRIBER_QUEUE_SIZE: usize = 100;
+
+const SET_LEDS: u8 = 0xed;
 
 pub(crate) fn push_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
         if queue.push(scancode).is_err() {
-            println!("WARNING! The scancode queue is full! Dropping keyboard events.");
+            log::warn!("scancode queue full, dropping keyboard input");
         } else {
             WAKER.wake();
         }
     } else {
-        println!("WARNING! The scancode queue hasn't been initialized!");
+        log::warn!("scancode queue uninitialized");
     }
 }
 
@@ -64,17 +85,374 @@ impl Stream for ScancodeStream {
 }
 
 pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut keys = subscribe_keys();
+    while let Some(key) = keys.next().await {
+        match key {
+            DecodedKey::RawKey(k) => print!("{:?}", k),
+            DecodedKey::Unicode(ch) => print!("{}", ch),
+        }
+    }
+}
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    pc_keyboard::DecodedKey::RawKey(k) => print!("{:?}", k),
-                    pc_keyboard::DecodedKey::Unicode(ch) => print!("{}", ch),
-                }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyboardError {
+    Ps2(Ps2Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// What the controller translates the keyboard's set 2 into.
+    Set1,
+    Set2,
+}
+
+/// Pressed modifiers and toggled locks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ModifierState {
+    pub lshift: bool,
+    pub rshift: bool,
+    pub lctrl: bool,
+    pub rctrl: bool,
+    pub lalt: bool,
+    /// Right Alt, which is AltGr on the UK and AZERTY layouts.
+    pub ralt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl ModifierState {
+    pub fn shift(&self) -> bool {
+        self.lshift || self.rshift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.lctrl || self.rctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.lalt || self.ralt
+    }
+
+    /// The argument of the set LEDs command.
+    fn leds(&self) -> u8 {
+        self.scroll_lock as u8 | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+
+    fn to_modifiers(self) -> Modifiers {
+        Modifiers {
+            lshift: self.lshift,
+            rshift: self.rshift,
+            lctrl: self.lctrl,
+            rctrl: self.rctrl,
+            numlock: self.num_lock,
+            capslock: self.caps_lock,
+            alt_gr: self.ralt,
+        }
+    }
+}
+
+enum Scancodes {
+    Set1(Keyboard<Us104Key, ScancodeSet1>),
+    Set2(Keyboard<Us104Key, ScancodeSet2>),
+}
+
+/// Turns scancodes into key events and key events into keys. `pc_keyboard`
+/// only splits up the scancodes, modifiers are tracked here.
+pub struct Decoder {
+    scancodes: Scancodes,
+    layout: Layout,
+    modifiers: ModifierState,
+}
+
+impl Decoder {
+    pub fn new(layout: Layout, set: ScancodeSet) -> Self {
+        let mut decoder = Self {
+            scancodes: Scancodes::Set1(Keyboard::new(
+                Us104Key,
+                ScancodeSet1,
+                HandleControl::Ignore,
+            )),
+            layout,
+            modifiers: ModifierState::default(),
+        };
+        decoder.set_scancode_set(set);
+        decoder
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    pub fn scancode_set(&self) -> ScancodeSet {
+        match self.scancodes {
+            Scancodes::Set1(_) => ScancodeSet::Set1,
+            Scancodes::Set2(_) => ScancodeSet::Set2,
+        }
+    }
+
+    /// Drops a partly received scancode sequence.
+    pub fn set_scancode_set(&mut self, set: ScancodeSet) {
+        self.scancodes = match set {
+            ScancodeSet::Set1 => {
+                Scancodes::Set1(Keyboard::new(Us104Key, ScancodeSet1, HandleControl::Ignore))
+            }
+            ScancodeSet::Set2 => {
+                Scancodes::Set2(Keyboard::new(Us104Key, ScancodeSet2, HandleControl::Ignore))
+            }
+        };
+    }
+
+    pub fn modifiers(&self) -> ModifierState {
+        self.modifiers
+    }
+
+    /// Returns the event once `byte` completes a scancode sequence. Unknown
+    /// sequences are dropped.
+    pub fn add_byte(&mut self, byte: u8) -> Option<KeyEvent> {
+        let event = match &mut self.scancodes {
+            Scancodes::Set1(keyboard) => keyboard.add_byte(byte),
+            Scancodes::Set2(keyboard) => keyboard.add_byte(byte),
+        };
+        event.ok().flatten()
+    }
+
+    /// Updates the modifiers and decodes key presses. Modifier and lock
+    /// keys give no key.
+    pub fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        let down = event.state == KeyState::Down;
+        let modifiers = &mut self.modifiers;
+        match event.code {
+            KeyCode::ShiftLeft => modifiers.lshift = down,
+            KeyCode::ShiftRight => modifiers.rshift = down,
+            KeyCode::ControlLeft => modifiers.lctrl = down,
+            KeyCode::ControlRight => modifiers.rctrl = down,
+            KeyCode::AltLeft => modifiers.lalt = down,
+            KeyCode::AltRight => modifiers.ralt = down,
+            KeyCode::CapsLock if down => modifiers.caps_lock = !modifiers.caps_lock,
+            KeyCode::NumpadLock if down => modifiers.num_lock = !modifiers.num_lock,
+            KeyCode::ScrollLock if down => modifiers.scroll_lock = !modifiers.scroll_lock,
+            KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock => {}
+            code if down => {
+                let modifiers = modifiers.to_modifiers();
+                return Some(
+                    self.layout
+                        .map_keycode(code, &modifiers, HandleControl::Ignore),
+                );
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+lazy_static! {
+    static ref DECODER: Mutex<Decoder> = Mutex::new(Decoder::new(Layout::Us, ScancodeSet::Set1));
+}
+
+struct Subscription<T> {
+    queue: ArrayQueue<T>,
+    waker: AtomicWaker,
+}
+
+type Subscriptions<T> = Mutex<Vec<Weak<Subscription<T>>>>;
+
+static EVENT_SUBSCRIPTIONS: Subscriptions<KeyEvent> = Mutex::new(Vec::new());
+static KEY_SUBSCRIPTIONS: Subscriptions<DecodedKey> = Mutex::new(Vec::new());
+
+/// A stream of keyboard input, which stops being fed when dropped.
+pub struct Subscriber<T> {
+    subscription: Arc<Subscription<T>>,
+}
+
+impl<T> Stream for Subscriber<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let subscription = &self.subscription;
+        if let Ok(item) = subscription.queue.pop() {
+            return Poll::Ready(Some(item));
+        }
+
+        subscription.waker.register(cx.waker());
+        match subscription.queue.pop() {
+            Ok(item) => {
+                subscription.waker.take();
+                Poll::Ready(Some(item))
             }
+            _ => Poll::Pending,
+        }
+    }
+}
+
+fn subscribe<T>(subscriptions: &Subscriptions<T>) -> Subscriber<T> {
+    let subscription = Arc::new(Subscription {
+        queue: ArrayQueue::new(SUBSCRIBER_QUEUE_SIZE),
+        waker: AtomicWaker::new(),
+    });
+    subscriptions.lock().push(Arc::downgrade(&subscription));
+    Subscriber { subscription }
+}
+
+fn publish<T: Clone>(subscriptions: &Subscriptions<T>, item: &T) {
+    subscriptions.lock().retain(|subscription| {
+        let subscription = match subscription.upgrade() {
+            Some(subscription) => subscription,
+            None => return false,
+        };
+        if subscription.queue.push(item.clone()).is_err() {
+            log::warn!("keyboard subscriber queue full, dropping input");
+        } else {
+            subscription.waker.wake();
+        }
+        true
+    });
+}
+
+/// Every key press and release, modifiers included.
+pub fn subscribe_events() -> Subscriber<KeyEvent> {
+    subscribe(&EVENT_SUBSCRIPTIONS)
+}
+
+/// Key presses decoded with the current layout and modifiers.
+pub fn subscribe_keys() -> Subscriber<DecodedKey> {
+    subscribe(&KEY_SUBSCRIPTIONS)
+}
+
+pub fn layout() -> Layout {
+    DECODER.lock().layout()
+}
+
+pub fn set_layout(layout: Layout) {
+    DECODER.lock().set_layout(layout);
+}
+
+pub fn modifiers() -> ModifierState {
+    DECODER.lock().modifiers()
+}
+
+pub fn scancode_set() -> ScancodeSet {
+    DECODER.lock().scancode_set()
+}
+
+/// Switches the scancode set the keyboard driver receives by turning the
+/// controller's translation to set 1 on or off.
+pub fn set_scancode_set(set: ScancodeSet) -> Result<(), KeyboardError> {
+    ps2::set_translation(set == ScancodeSet::Set1).map_err(KeyboardError::Ps2)?;
+    DECODER.lock().set_scancode_set(set);
+    Ok(())
+}
+
+/// The terminal Alt+F1 and up switch to.
+fn terminal_for(event: &KeyEvent, modifiers: &ModifierState) -> Option<usize> {
+    if event.state != KeyState::Down || !modifiers.alt() {
+        return None;
+    }
+    match event.code {
+        KeyCode::F1 => Some(0),
+        KeyCode::F2 => Some(1),
+        KeyCode::F3 => Some(2),
+        KeyCode::F4 => Some(3),
+        _ => None,
+    }
+}
+
+/// Decodes `scancode` and hands the result to the subscribers. Also
+/// keeps the lock LEDs in sync and switches terminals on Alt+F1 to Alt+F4.
+pub fn handle_scancode(scancode: u8) {
+    // late replies to commands, never scancodes
+    if scancode == ps2::ACK || scancode == ps2::RESEND {
+        return;
+    }
+    let (event, key, modifiers, leds_changed) = {
+        let mut decoder = DECODER.lock();
+        let event = match decoder.add_byte(scancode) {
+            Some(event) => event,
+            None => return,
+        };
+        let leds = decoder.modifiers().leds();
+        let key = decoder.process_keyevent(event.clone());
+        let modifiers = decoder.modifiers();
+        (event, key, modifiers, modifiers.leds() != leds)
+    };
+    if leds_changed {
+        if let Err(err) = ps2::send(Ps2Port::First, &[SET_LEDS, modifiers.leds()]) {
+            log::warn!("setting the keyboard LEDs failed: {:?}", err);
         }
     }
+    publish(&EVENT_SUBSCRIPTIONS, &event);
+    if let Some(terminal) = terminal_for(&event, &modifiers) {
+        // only as many keys as there are terminals are mapped
+        let _ = vga_buffer::switch_to(terminal);
+    } else if let Some(key) = key {
+        publish(&KEY_SUBSCRIPTIONS, &key);
+    }
+}
+
+/// The keyboard service. Takes over the scancode stream, so it only runs
+/// once.
+pub async fn run() {
+    let mut scancodes = ScancodeStream::new();
+    while let Some(scancode) = scancodes.next().await {
+        handle_scancode(scancode);
+    }
+}
+
+#[test_case]
+fn test_scancode_sets() {
+    let mut decoder = Decoder::new(Layout::Us, ScancodeSet::Set1);
+    let a = |state| Some(KeyEvent::new(KeyCode::A, state));
+    assert_eq!(decoder.add_byte(0x1e), a(KeyState::Down));
+    assert_eq!(decoder.add_byte(0x9e), a(KeyState::Up));
+
+    decoder.set_scancode_set(ScancodeSet::Set2);
+    assert_eq!(decoder.scancode_set(), ScancodeSet::Set2);
+    assert_eq!(decoder.add_byte(0x1c), a(KeyState::Down));
+    assert_eq!(decoder.add_byte(0xf0), None);
+    assert_eq!(decoder.add_byte(0x1c), a(KeyState::Up));
+}
+
+#[test_case]
+fn test_layouts() {
+    let expected = [
+        (Layout::Us, 'q'),
+        (Layout::Uk, 'q'),
+        (Layout::Dvorak, '\''),
+        (Layout::Azerty, 'a'),
+    ];
+    let mut decoder = Decoder::new(Layout::Us, ScancodeSet::Set1);
+    for (layout, ch) in IntoIterator::into_iter(expected) {
+        decoder.set_layout(layout);
+        let key = decoder.process_keyevent(KeyEvent::new(KeyCode::Q, KeyState::Down));
+        assert_eq!(key, Some(DecodedKey::Unicode(ch)));
+    }
+    assert_eq!(Layout::from_name("dvorak"), Some(Layout::Dvorak));
+    assert_eq!(Layout::from_name("qwertz"), None);
+}
+
+#[test_case]
+fn test_modifiers_and_locks() {
+    let mut decoder = Decoder::new(Layout::Uk, ScancodeSet::Set1);
+    let mut press = |code, state| decoder.process_keyevent(KeyEvent::new(code, state));
+    assert_eq!(press(KeyCode::ShiftLeft, KeyState::Down), None);
+    assert_eq!(
+        press(KeyCode::Key3, KeyState::Down),
+        Some(DecodedKey::Unicode('£'))
+    );
+    assert_eq!(press(KeyCode::ShiftLeft, KeyState::Up), None);
+    assert_eq!(press(KeyCode::CapsLock, KeyState::Down), None);
+    assert_eq!(press(KeyCode::CapsLock, KeyState::Up), None);
+    assert_eq!(
+        press(KeyCode::A, KeyState::Down),
+        Some(DecodedKey::Unicode('A'))
+    );
+    assert_eq!(press(KeyCode::A, KeyState::Up), None);
+
+    let modifiers = decoder.modifiers();
+    assert!(modifiers.caps_lock && !modifiers.shift());
+    assert_eq!(modifiers.leds(), 0b100);
 }
@@ -0,0 +1,42 @@
+use pc_keyboard::layouts::{Azerty, Dvorak104Key, Uk105Key, Us104Key};
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyboardLayout, Modifiers};
+
+/// Keyboard layouts that can be switched between at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    Dvorak,
+    Azerty,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 4] = [Layout::Us, Layout::Uk, Layout::Dvorak, Layout::Azerty];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::Dvorak => "dvorak",
+            Layout::Azerty => "azerty",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        IntoIterator::into_iter(Self::ALL).find(|layout| layout.name() == name)
+    }
+
+    pub(super) fn map_keycode(
+        self,
+        code: KeyCode,
+        modifiers: &Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        match self {
+            Layout::Us => Us104Key::map_keycode(code, modifiers, handle_ctrl),
+            Layout::Uk => Uk105Key::map_keycode(code, modifiers, handle_ctrl),
+            Layout::Dvorak => Dvorak104Key::map_keycode(code, modifiers, handle_ctrl),
+            Layout::Azerty => Azerty::map_keycode(code, modifiers, handle_ctrl),
+        }
+    }
+}
@@ -5,6 +5,7 @@ use core::{future::Future, pin::Pin, task::Poll};
 pub mod executor;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod timer;
 
 pub struct Task {
     id: TaskId,
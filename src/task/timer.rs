@@ -0,0 +1,170 @@
+//! Futures that complete after a delay. Pending timers are kept ordered by
+//! deadline and the timer interrupt wakes the ones that expired. Wakers are
+//! only woken by reference there; the entries are removed, and the wakers
+//! dropped, by the futures themselves so the interrupt never frees memory.
+
+use crate::time;
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+struct Timers {
+    /// Keyed by deadline and timer id.
+    pending: BTreeMap<(u64, u64), Waker>,
+    /// Every timer with a deadline up to this tick has been woken.
+    woken: u64,
+}
+
+static TIMERS: Mutex<Timers> = Mutex::new(Timers {
+    pending: BTreeMap::new(),
+    woken: 0,
+});
+
+/// Called by `time::on_tick`. If another CPU holds the lock the timers are
+/// left for the next tick.
+pub(crate) fn wake_expired(now: u64) {
+    if let Some(mut timers) = TIMERS.try_lock() {
+        let start = (timers.woken + 1, 0);
+        if start.0 <= now {
+            for waker in timers
+                .pending
+                .range(start..=(now, u64::MAX))
+                .map(|(_, w)| w)
+            {
+                waker.wake_by_ref();
+            }
+            timers.woken = now;
+        }
+    }
+}
+
+/// Completes once `duration` has passed.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(time::deadline_after(duration))
+}
+
+/// Completes once the tick count reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        registered: false,
+    }
+}
+
+pub struct Sleep {
+    deadline: u64,
+    id: u64,
+    registered: bool,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    /// Restarts the timer with a new deadline.
+    pub fn reset(&mut self, deadline: u64) {
+        self.unregister();
+        self.deadline = deadline;
+    }
+
+    fn unregister(&mut self) {
+        if self.registered {
+            let key = (self.deadline, self.id);
+            let waker = interrupts::without_interrupts(|| TIMERS.lock().pending.remove(&key));
+            drop(waker);
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if time::ticks() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        let key = (self.deadline, self.id);
+        // checking the tick count again under the lock guarantees the
+        // interrupt sees the entry before its deadline passes
+        let (expired, old) = interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            if time::ticks() >= key.0 {
+                (true, timers.pending.remove(&key))
+            } else {
+                match timers.pending.get(&key) {
+                    Some(waker) if waker.will_wake(cx.waker()) => (false, None),
+                    _ => (false, timers.pending.insert(key, cx.waker().clone())),
+                }
+            }
+        });
+        drop(old);
+        self.registered = !expired;
+        if expired {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Yields every `period`. Ticks that are missed because the task ran late
+/// are skipped rather than delivered in a burst.
+pub struct Interval {
+    period: u64,
+    sleep: Sleep,
+}
+
+impl Interval {
+    pub fn new(period: Duration) -> Self {
+        let period = time::duration_to_ticks(period).max(1);
+        Self {
+            period,
+            sleep: sleep_until(time::ticks() + period),
+        }
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context) -> Poll<()> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let now = time::ticks();
+                let mut next = self.sleep.deadline() + self.period;
+                if next <= now {
+                    next = now + self.period;
+                }
+                self.sleep.reset(next);
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Completes at the next tick of the interval.
+    pub fn tick(&mut self) -> impl Future<Output = ()> + '_ {
+        futures_util::future::poll_fn(move |cx| self.poll_tick(cx))
+    }
+}
+
+impl Stream for Interval {
+    type Item = ();
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<()>> {
+        self.get_mut().poll_tick(cx).map(Some)
+    }
+}
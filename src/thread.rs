@@ -0,0 +1,300 @@
+pub mod context;
+pub mod scheduler;
+
+use crate::memory::stack::{KernelStack, StackError, StackKind};
+use crate::memory::AddressSpace;
+use crate::time;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use scheduler::SCHEDULER;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+pub const STACK_PAGES: u64 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    /// Asleep until the given tick.
+    Sleeping(u64),
+    Finished,
+}
+
+pub struct Thread {
+    id: ThreadId,
+    state: ThreadState,
+    rsp: u64,
+    stack: Option<KernelStack>,
+    joiners: Vec<ThreadId>,
+    user_return: Option<u64>,
+    address_space: Option<Arc<AddressSpace>>,
+}
+
+impl Thread {
+    fn boot() -> Self {
+        Self {
+            id: ThreadId::new(),
+            state: ThreadState::Running,
+            rsp: 0,
+            stack: None,
+            joiners: Vec::new(),
+            user_return: None,
+            address_space: None,
+        }
+    }
+
+    fn new(
+        entry: Box<dyn FnOnce() + Send + 'static>,
+        address_space: Option<Arc<AddressSpace>>,
+    ) -> Result<Self, StackError> {
+        let id = ThreadId::new();
+        let stack = KernelStack::allocate(STACK_PAGES, StackKind::Thread(id.as_u64()))?;
+        let arg = Box::into_raw(Box::new(entry)) as u64;
+        let rsp = unsafe { context::prepare_stack(stack.top(), arg) };
+        Ok(Self {
+            id,
+            state: ThreadState::Ready,
+            rsp,
+            stack: Some(stack),
+            joiners: Vec::new(),
+            user_return: None,
+            address_space,
+        })
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    pub fn stack_top(&self) -> Option<VirtAddr> {
+        self.stack.as_ref().map(KernelStack::top)
+    }
+}
+
+#[no_mangle]
+extern "C" fn __thread_entry(arg: *mut Box<dyn FnOnce() + Send + 'static>) -> ! {
+    let entry = unsafe { Box::from_raw(arg) };
+    interrupts::enable();
+    entry();
+    exit();
+}
+
+/// Turns the running context into the boot thread and starts the idle
+/// thread. Must be called once, after the heap has been initialized.
+pub fn init() {
+    let boot = Thread::boot();
+    let idle = Thread::new(
+        Box::new(|| loop {
+            x86_64::instructions::hlt();
+        }),
+        None,
+    )
+    .expect("failed to allocate the idle thread stack");
+    scheduler::init(boot, idle);
+}
+
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn join(self) -> T {
+        loop {
+            let finished = interrupts::without_interrupts(|| {
+                let blocked = {
+                    let mut guard = SCHEDULER.lock();
+                    let scheduler = guard.as_mut().expect("scheduler not initialized");
+                    let current = scheduler.current;
+                    match scheduler.threads.get_mut(&self.id) {
+                        Some(thread) if thread.state != ThreadState::Finished => {
+                            thread.joiners.push(current);
+                            scheduler.current_mut().state = ThreadState::Blocked;
+                            true
+                        }
+                        _ => false,
+                    }
+                };
+                if blocked {
+                    scheduler::schedule();
+                }
+                !blocked
+            });
+            if finished {
+                break;
+            }
+        }
+        reap();
+        self.result
+            .lock()
+            .take()
+            .expect("joined thread produced no result")
+    }
+}
+
+/// Fails when no kernel stack is left for the thread.
+pub fn spawn<F, T>(f: F) -> Result<JoinHandle<T>, StackError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_thread(None, f)
+}
+
+/// Spawns a thread that runs with `address_space` loaded in CR3.
+pub fn spawn_in<F, T>(address_space: Arc<AddressSpace>, f: F) -> Result<JoinHandle<T>, StackError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_thread(Some(address_space), f)
+}
+
+fn spawn_thread<F, T>(
+    address_space: Option<Arc<AddressSpace>>,
+    f: F,
+) -> Result<JoinHandle<T>, StackError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    reap();
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+    let thread = Thread::new(
+        Box::new(move || {
+            let value = f();
+            *slot.lock() = Some(value);
+        }),
+        address_space,
+    )?;
+    let id = thread.id;
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .expect("scheduler not initialized")
+            .add(thread);
+    });
+    Ok(JoinHandle { id, result })
+}
+
+pub fn current() -> ThreadId {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .expect("scheduler not initialized")
+            .current
+    })
+}
+
+pub fn yield_now() {
+    interrupts::without_interrupts(scheduler::schedule);
+}
+
+/// Blocks the current thread for at least `duration`.
+pub fn sleep(duration: Duration) {
+    interrupts::without_interrupts(|| {
+        let until = time::deadline_after(duration);
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.current_mut().state = ThreadState::Sleeping(until);
+        }
+        scheduler::schedule();
+    });
+}
+
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut guard = SCHEDULER.lock();
+        let scheduler = guard.as_mut().expect("scheduler not initialized");
+        let current = scheduler.current_mut();
+        current.state = ThreadState::Finished;
+        let joiners = core::mem::take(&mut current.joiners);
+        for joiner in joiners {
+            scheduler.wake(joiner);
+        }
+    }
+    scheduler::schedule();
+    unreachable!("finished thread was scheduled again");
+}
+
+pub fn threads() -> Vec<(ThreadId, ThreadState)> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_ref()
+            .map(|s| s.threads.values().map(|t| (t.id, t.state)).collect())
+            .unwrap_or_default()
+    })
+}
+
+/// Returns the address space of the current thread, or `None` for kernel
+/// threads. Gives up if this CPU holds the scheduler lock already, since
+/// this is called from the page fault handler.
+pub fn current_address_space() -> Option<Arc<AddressSpace>> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock_unless_held()?
+            .as_mut()?
+            .current_mut()
+            .address_space
+            .clone()
+    })
+}
+
+/// Records the kernel stack pointer to return to when the current thread
+/// leaves ring 3, see `usermode::run_user`.
+pub(crate) fn set_user_return(kernel_rsp: Option<u64>) {
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.current_mut().user_return = kernel_rsp;
+        }
+    });
+}
+
+pub(crate) fn user_return() -> Option<u64> {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .and_then(|s| s.current_mut().user_return)
+    })
+}
+
+fn reap() {
+    let finished = interrupts::without_interrupts(|| match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => scheduler.reap(),
+        None => Vec::new(),
+    });
+    drop(finished);
+}
@@ -0,0 +1,77 @@
+use core::arch::global_asm;
+use x86_64::VirtAddr;
+
+// Callee-saved registers and RFLAGS are pushed on the old stack, the stack
+// pointer is saved through `old_rsp` and the same layout is popped off the
+// new stack. A thread that has never run returns into `__thread_trampoline`.
+global_asm!(
+    r#"
+.global __switch_context
+__switch_context:
+    pushfq
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    popfq
+    ret
+
+.global __thread_trampoline
+__thread_trampoline:
+    mov rdi, r12
+    call __thread_entry
+    ud2
+"#
+);
+
+extern "C" {
+    fn __switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn __thread_trampoline();
+}
+
+const INITIAL_RFLAGS: u64 = 0x2;
+
+/// Lays out a fresh stack so that switching to it enters `__thread_entry`
+/// with `arg` as its only argument. Returns the stack pointer to switch to.
+///
+/// # Safety
+///
+/// `stack_top` must be the end of a mapped, writable stack that nothing
+/// else uses.
+pub unsafe fn prepare_stack(stack_top: VirtAddr, arg: u64) -> u64 {
+    let top = stack_top.align_down(16u64).as_mut_ptr::<u64>();
+    let frame: [u64; 8] = [
+        0,                                                             // r15
+        0,                                                             // r14
+        0,                                                             // r13
+        arg,                                                           // r12
+        0,                                                             // rbx
+        0,                                                             // rbp
+        INITIAL_RFLAGS,                                                // rflags
+        __thread_trampoline as unsafe extern "C" fn() as usize as u64, // return address
+    ];
+    let rsp = top.sub(frame.len());
+    rsp.copy_from_nonoverlapping(frame.as_ptr(), frame.len());
+    rsp as u64
+}
+
+/// Saves the current context into `old_rsp` and resumes the one at `new_rsp`.
+///
+/// # Safety
+///
+/// Must be called with interrupts disabled. `old_rsp` must be valid for
+/// writes and `new_rsp` must come from `prepare_stack` or an earlier
+/// switch away from a thread that wasn't resumed since.
+pub unsafe fn switch(old_rsp: *mut u64, new_rsp: u64) {
+    __switch_context(old_rsp, new_rsp);
+}
@@ -0,0 +1,187 @@
+use super::{context, Thread, ThreadId, ThreadState};
+use crate::percpu::CpuMutex;
+use crate::{gdt, memory, time};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::VirtAddr;
+
+/// 10ms at the default tick rate.
+pub const TIME_SLICE_TICKS: u64 = time::TICK_HZ as u64 / 100;
+
+pub(super) static SCHEDULER: CpuMutex<Option<Scheduler>> = CpuMutex::new(None);
+
+pub(super) struct Scheduler {
+    pub(super) threads: BTreeMap<ThreadId, Box<Thread>>,
+    run_queue: VecDeque<ThreadId>,
+    pub(super) current: ThreadId,
+    idle: Option<ThreadId>,
+    slice_start: u64,
+}
+
+impl Scheduler {
+    fn new(boot_thread: Thread) -> Self {
+        let current = boot_thread.id;
+        let mut threads = BTreeMap::new();
+        threads.insert(current, Box::new(boot_thread));
+        Self {
+            threads,
+            run_queue: VecDeque::new(),
+            current,
+            idle: None,
+            slice_start: 0,
+        }
+    }
+
+    pub(super) fn add(&mut self, thread: Thread) {
+        let id = thread.id;
+        self.threads.insert(id, Box::new(thread));
+        self.run_queue.reserve(self.threads.len());
+        self.run_queue.push_back(id);
+    }
+
+    pub(super) fn set_idle(&mut self, thread: Thread) {
+        let id = thread.id;
+        self.threads.insert(id, Box::new(thread));
+        self.idle = Some(id);
+    }
+
+    pub(super) fn current_mut(&mut self) -> &mut Thread {
+        self.threads
+            .get_mut(&self.current)
+            .expect("current thread is not registered")
+    }
+
+    pub(super) fn wake(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            if matches!(
+                thread.state,
+                ThreadState::Blocked | ThreadState::Sleeping(_)
+            ) {
+                thread.state = ThreadState::Ready;
+                self.run_queue.push_back(id);
+            }
+        }
+    }
+
+    /// Removes finished threads other than the current one and hands them
+    /// back so they can be dropped outside the scheduler lock.
+    pub(super) fn reap(&mut self) -> Vec<Thread> {
+        let current = self.current;
+        let finished: Vec<ThreadId> = self
+            .threads
+            .values()
+            .filter(|t| t.state == ThreadState::Finished && t.id != current)
+            .map(|t| t.id)
+            .collect();
+        finished
+            .into_iter()
+            .filter_map(|id| self.threads.remove(&id))
+            .map(|thread| *thread)
+            .collect()
+    }
+
+    fn wake_sleepers(&mut self, now: u64) {
+        let Self {
+            threads, run_queue, ..
+        } = self;
+        for thread in threads.values_mut() {
+            if let ThreadState::Sleeping(until) = thread.state {
+                if until <= now {
+                    thread.state = ThreadState::Ready;
+                    run_queue.push_back(thread.id);
+                }
+            }
+        }
+    }
+
+    /// Picks the next thread to run and returns the stack pointer slot of
+    /// the current thread along with the stack pointer to resume, or `None`
+    /// if the current thread keeps running.
+    fn switch_next(&mut self) -> Option<(*mut u64, u64)> {
+        let current_runnable = self.threads[&self.current].state == ThreadState::Running;
+        let next = loop {
+            match self.run_queue.pop_front() {
+                Some(id) if self.threads.get(&id).map(|t| t.state) == Some(ThreadState::Ready) => {
+                    break id
+                }
+                Some(_) => continue,
+                None if current_runnable => return None,
+                None => break self.idle.expect("no runnable thread and no idle thread"),
+            }
+        };
+        if next == self.current {
+            return None;
+        }
+
+        let previous = self.current;
+        if current_runnable {
+            self.threads.get_mut(&previous).unwrap().state = ThreadState::Ready;
+            if Some(previous) != self.idle {
+                self.run_queue.push_back(previous);
+            }
+        }
+
+        let next_thread = self.threads.get_mut(&next).unwrap();
+        next_thread.state = ThreadState::Running;
+        let new_rsp = next_thread.rsp;
+        if let Some(kernel_rsp) = next_thread.user_return {
+            gdt::set_kernel_stack(VirtAddr::new(kernel_rsp));
+        }
+        // kernel stacks live in the shared kernel half, so the switch can
+        // happen before the stacks are exchanged
+        let page_table = match &next_thread.address_space {
+            Some(address_space) => address_space.level_4_frame(),
+            None => memory::kernel_level_4_frame(),
+        };
+        if Cr3::read().0 != page_table {
+            unsafe { Cr3::write(page_table, Cr3Flags::empty()) };
+        }
+        self.current = next;
+        self.slice_start = time::ticks();
+
+        let old_rsp = &mut self.threads.get_mut(&previous).unwrap().rsp as *mut u64;
+        Some((old_rsp, new_rsp))
+    }
+}
+
+pub(super) fn init(boot_thread: Thread, idle_thread: Thread) {
+    interrupts::without_interrupts(|| {
+        let mut scheduler = Scheduler::new(boot_thread);
+        scheduler.set_idle(idle_thread);
+        *SCHEDULER.lock() = Some(scheduler);
+    });
+}
+
+/// Switches away from the current thread if another one is ready. The
+/// caller must have updated the current thread's state beforehand and
+/// must call this with interrupts disabled.
+pub(super) fn schedule() {
+    let switch = match SCHEDULER.try_lock() {
+        Some(mut scheduler) => scheduler.as_mut().and_then(|s| s.switch_next()),
+        None => None,
+    };
+    if let Some((old_rsp, new_rsp)) = switch {
+        unsafe { context::switch(old_rsp, new_rsp) };
+    }
+}
+
+/// Wakes sleeping threads and preempts the current one once its slice is
+/// used up. Called by `time::on_tick` with the new tick count.
+pub(crate) fn on_tick(now: u64) {
+    let expired = match SCHEDULER.try_lock() {
+        Some(mut scheduler) => match scheduler.as_mut() {
+            Some(s) => {
+                s.wake_sleepers(now);
+                now - s.slice_start >= TIME_SLICE_TICKS
+            }
+            None => false,
+        },
+        None => false,
+    };
+    if expired {
+        schedule();
+    }
+}
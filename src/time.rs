@@ -0,0 +1,78 @@
+//! The monotonic clock. The PIT is programmed to interrupt `TICK_HZ` times
+//! a second and every interrupt advances the global tick counter, which
+//! drives both the thread scheduler and the async timers in `task::timer`.
+
+use crate::task;
+use crate::thread;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+pub const TICK_HZ: u32 = 1000;
+
+/// The reload value for PIT channel 0. The actual rate differs slightly
+/// from `TICK_HZ` since the divisor is an integer.
+const PIT_DIVISOR: u16 = ((PIT_FREQUENCY + TICK_HZ / 2) / TICK_HZ) as u16;
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Programs PIT channel 0 as a rate generator firing `TICK_HZ` times a
+/// second.
+pub fn init() {
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_0 = Port::<u8>::new(0x40);
+    interrupts::without_interrupts(|| unsafe {
+        // channel 0, lobyte/hibyte, mode 2
+        command.write(0b0011_0100);
+        channel_0.write(PIT_DIVISOR as u8);
+        channel_0.write((PIT_DIVISOR >> 8) as u8);
+    });
+}
+
+/// Number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * PIT_DIVISOR as u128 * NANOS_PER_SECOND / PIT_FREQUENCY as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Converts `duration` to ticks, rounding up.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let period = PIT_DIVISOR as u128 * NANOS_PER_SECOND;
+    let scaled = duration.as_nanos() * PIT_FREQUENCY as u128;
+    scaled.div_ceil(period) as u64
+}
+
+/// Returns the tick at which at least `duration` will have passed. The
+/// current tick is already partly over, so one more is added.
+pub fn deadline_after(duration: Duration) -> u64 {
+    ticks() + duration_to_ticks(duration) + 1
+}
+
+/// Time since the timer was started.
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+/// Called from the timer interrupt handler after the end of interrupt has
+/// been signalled.
+pub(crate) fn on_tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    task::timer::wake_expired(now);
+    thread::scheduler::on_tick(now);
+}
+
+#[test_case]
+fn test_duration_conversion() {
+    assert_eq!(duration_to_ticks(Duration::from_secs(0)), 0);
+    assert_eq!(duration_to_ticks(Duration::from_nanos(1)), 1);
+    let second = duration_to_ticks(Duration::from_secs(1));
+    assert!((TICK_HZ as u64 - 1..=TICK_HZ as u64 + 1).contains(&second));
+    assert!(ticks_to_duration(second) >= Duration::from_secs(1));
+}
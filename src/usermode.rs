@@ -0,0 +1,224 @@
+use crate::memory::stack::StackError;
+use crate::memory::{self, USER_SPACE_START};
+use crate::{gdt, thread};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+pub const USER_STACK_PAGES: u64 = 16;
+pub const USER_STACK_REGION_START: u64 = USER_SPACE_START + 0x0000_1000_0000_0000;
+pub const USER_CODE_REGION_START: u64 = USER_SPACE_START + 0x0000_0800_0000_0000;
+
+// `__enter_user` saves the callee-saved registers on the kernel stack and
+// records that stack pointer before dropping to ring 3 with `iretq`.
+// `__exit_user` later restores it, which makes `__enter_user` return the
+// exit code to its caller.
+global_asm!(
+    r#"
+.global __enter_user
+__enter_user:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov r12, rdi
+    mov r13, rsi
+    mov r14, rdx
+    mov r15, rcx
+    mov rdi, rsp
+    sub rsp, 8
+    call __user_entry_hook
+    add rsp, 8
+    push r15
+    push r13
+    push 0x202
+    push r14
+    push r12
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    xor r8d, r8d
+    xor r9d, r9d
+    xor r10d, r10d
+    xor r11d, r11d
+    xor r12d, r12d
+    xor r13d, r13d
+    xor r14d, r14d
+    xor r15d, r15d
+    iretq
+
+.global __exit_user
+__exit_user:
+    mov rsp, rdi
+    mov rax, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#
+);
+
+extern "C" {
+    fn __enter_user(entry: u64, user_stack: u64, code_selector: u64, data_selector: u64) -> u64;
+    fn __exit_user(kernel_rsp: u64, code: u64) -> !;
+}
+
+#[no_mangle]
+extern "C" fn __user_entry_hook(kernel_rsp: u64) {
+    thread::set_user_return(Some(kernel_rsp));
+    gdt::set_kernel_stack(VirtAddr::new(kernel_rsp));
+}
+
+/// Runs `entry` in ring 3 on the given user stack until it issues the exit
+/// system call, and returns the exit code.
+pub fn run_user(entry: VirtAddr, user_stack_top: VirtAddr) -> u64 {
+    let (code_selector, data_selector) = gdt::user_selectors();
+    interrupts::disable();
+    let code = unsafe {
+        __enter_user(
+            entry.as_u64(),
+            user_stack_top.as_u64(),
+            code_selector.0 as u64,
+            data_selector.0 as u64,
+        )
+    };
+    interrupts::enable();
+    code
+}
+
+pub fn spawn_user(
+    entry: VirtAddr,
+    user_stack_top: VirtAddr,
+) -> Result<thread::JoinHandle<u64>, StackError> {
+    thread::spawn(move || run_user(entry, user_stack_top))
+}
+
+/// Unwinds from a system call back into the `run_user` call of the current
+/// thread.
+pub fn exit_to_kernel(code: u64) -> ! {
+    let kernel_rsp = thread::user_return().expect("exit from a thread not in user mode");
+    thread::set_user_return(None);
+    unsafe { __exit_user(kernel_rsp, code) }
+}
+
+pub fn map_user_pages(
+    start: Page,
+    count: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    for page in Page::range(start, start + count) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            page.start_address()
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, Page::<Size4KiB>::SIZE as usize);
+        }
+    }
+    Ok(())
+}
+
+/// Maps a fresh user stack in the kernel address space and returns its top.
+pub fn allocate_user_stack() -> Result<VirtAddr, MapToError<Size4KiB>> {
+    static NEXT_STACK: AtomicU64 = AtomicU64::new(USER_STACK_REGION_START);
+    // leave one unmapped page below every stack
+    let slot_size = (USER_STACK_PAGES + 1) * Page::<Size4KiB>::SIZE;
+    let slot = NEXT_STACK.fetch_add(slot_size, Ordering::Relaxed);
+    let bottom = Page::containing_address(VirtAddr::new(slot + Page::<Size4KiB>::SIZE));
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        map_user_pages(
+            bottom,
+            USER_STACK_PAGES,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            mapper,
+            frame_allocator,
+        )
+    })
+    .unwrap_or(Err(MapToError::FrameAllocationFailed))?;
+    Ok((bottom + USER_STACK_PAGES).start_address())
+}
+
+/// Copies the position independent routine `code` into fresh read-only user
+/// pages in the kernel address space and returns where it starts.
+pub fn load_code(code: &[u8]) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    static NEXT_CODE: AtomicU64 = AtomicU64::new(USER_CODE_REGION_START);
+    let page_size = Page::<Size4KiB>::SIZE;
+    let pages = (code.len() as u64).div_ceil(page_size);
+    let start = NEXT_CODE.fetch_add(pages * page_size, Ordering::Relaxed);
+    let start = Page::containing_address(VirtAddr::new(start));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let table_flags = flags | PageTableFlags::WRITABLE;
+    memory::with_kernel_memory(|mapper, frame_allocator| {
+        for (page, chunk) in Page::range(start, start + pages).zip(code.chunks(page_size as usize))
+        {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            // the pages aren't writable, so fill the frames before mapping
+            unsafe {
+                let ptr = (memory::physical_memory_offset() + frame.start_address().as_u64())
+                    .as_mut_ptr::<u8>();
+                ptr.write_bytes(0, page_size as usize);
+                ptr.copy_from_nonoverlapping(chunk.as_ptr(), chunk.len());
+                mapper
+                    .map_to_with_table_flags(page, frame, flags, table_flags, frame_allocator)?
+                    .flush();
+            }
+        }
+        Ok(())
+    })
+    .unwrap_or(Err(MapToError::FrameAllocationFailed))?;
+    Ok(start.start_address())
+}
+
+global_asm!(
+    r#"
+.global __user_hello
+__user_hello:
+    mov eax, 1
+    mov edi, 1
+    lea rsi, [rip + .Lhello_message]
+    mov edx, OFFSET .Lhello_message_len
+    int 0x80
+    xor eax, eax
+    xor edi, edi
+    int 0x80
+    ud2
+.Lhello_message:
+    .ascii "Hello from ring 3!\n"
+.Lhello_message_end:
+.set .Lhello_message_len, .Lhello_message_end - .Lhello_message
+.global __user_hello_end
+__user_hello_end:
+"#
+);
+
+extern "C" {
+    fn __user_hello();
+    fn __user_hello_end();
+}
+
+/// Returns the code of a tiny ring 3 routine that prints a greeting and
+/// exits with code 0, to be run from a copy made by `load_code`.
+pub fn hello_program() -> &'static [u8] {
+    let start = __user_hello as unsafe extern "C" fn() as usize;
+    let end = __user_hello_end as unsafe extern "C" fn() as usize;
+    unsafe { core::slice::from_raw_parts(start as *const u8, end - start) }
+}
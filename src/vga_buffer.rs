@@ -1,8 +1,16 @@
 #![allow(dead_code)]
+use crate::dmesg;
+use crate::framebuffer::font::GLYPH_WIDTH;
+use crate::framebuffer::{Framebuffer, PALETTE};
+use alloc::boxed::Box;
+use core::alloc::Layout;
 use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -33,6 +41,22 @@ impl ColorCode {
     fn new(foreground: Color, background: Color) -> Self {
         Self((background as u8) << 4 | (foreground as u8))
     }
+
+    fn with_foreground(self, foreground: u8) -> Self {
+        Self(self.0 & 0xf0 | foreground & 0x0f)
+    }
+
+    fn with_background(self, background: u8) -> Self {
+        Self(self.0 & 0x0f | (background & 0x0f) << 4)
+    }
+
+    fn foreground(self) -> u8 {
+        self.0 & 0x0f
+    }
+
+    fn background(self) -> u8 {
+        self.0 >> 4
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -42,21 +66,215 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+/// Lines kept after they scroll off the top of the screen.
+pub const SCROLLBACK_LINES: usize = 200;
+/// Virtual terminals, switched with Alt+F1 and up.
+pub const TERMINALS: usize = 4;
+/// The terminal `print!` writes to.
+pub const CONSOLE: usize = 0;
+/// The terminal showing every log record.
+pub const LOG_TERMINAL: usize = 1;
+pub const TAB_WIDTH: usize = 8;
+/// Parameters of an escape sequence beyond this many are ignored.
+const MAX_ESCAPE_PARAMS: usize = 4;
+
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_END: u8 = 0x0b;
+const CRTC_CURSOR_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOW: u8 = 0x0f;
+const CURSOR_DISABLED: u8 = 1 << 5;
+/// An underline cursor in the last two of the 16 scan lines of a cell.
+const CURSOR_FIRST_LINE: u8 = 14;
+const CURSOR_LAST_LINE: u8 = 15;
+
+/// Colors of the ANSI color numbers 0 to 7.
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+/// Added to a color to get its light variant.
+const BRIGHT: u8 = 8;
+
+/// Code page 437 glyphs for the bytes 0x01 to 0x1f.
+const CP437_LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►', '◄', '↕', '‼',
+    '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+/// Code page 437 glyphs for the bytes 0x80 to 0xff.
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+/// Shown for characters code page 437 has no glyph for.
+const UNKNOWN_GLYPH: u8 = 0xfe;
+
+/// The code page 437 byte showing `ch`.
+pub(crate) fn to_cp437(ch: char) -> u8 {
+    match ch {
+        ' '..='~' => ch as u8,
+        '⌂' => 0x7f,
+        _ => {
+            if let Some(i) = CP437_LOW.iter().position(|&glyph| glyph == ch) {
+                1 + i as u8
+            } else if let Some(i) = CP437_HIGH.iter().position(|&glyph| glyph == ch) {
+                0x80 + i as u8
+            } else {
+                UNKNOWN_GLYPH
+            }
+        }
+    }
+}
+
+fn from_cp437(byte: u8) -> char {
+    match byte {
+        0 => ' ',
+        0x01..=0x1f => CP437_LOW[byte as usize - 1],
+        0x7f => '⌂',
+        0x80..=0xff => CP437_HIGH[byte as usize - 0x80],
+        _ => byte as char,
+    }
+}
 
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+type Row = [ScreenChar; BUFFER_WIDTH];
+
+const EMPTY_ROW: Row = [ScreenChar {
+    ascii_character: 0,
+    color_code: ColorCode(0),
+}; BUFFER_WIDTH];
+
+/// Lines that scrolled off the screen, and the screen itself while older
+/// lines are shown in its place.
+struct Scrollback {
+    lines: [Row; SCROLLBACK_LINES],
+    /// Index of the oldest line in `lines`.
+    start: usize,
+    len: usize,
+    live: [Row; BUFFER_HEIGHT],
+    /// How many lines the view is scrolled back, zero while it is live.
+    offset: usize,
+}
+
+impl Scrollback {
+    fn push(&mut self, row: Row) {
+        self.lines[(self.start + self.len) % SCROLLBACK_LINES] = row;
+        if self.len == SCROLLBACK_LINES {
+            self.start = (self.start + 1) % SCROLLBACK_LINES;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    /// Line `index` of the history followed by the live screen.
+    fn line(&self, index: usize) -> &Row {
+        match index.checked_sub(self.len) {
+            Some(row) => &self.live[row],
+            None => &self.lines[(self.start + index) % SCROLLBACK_LINES],
+        }
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SCROLLBACK: Mutex<Scrollback> = Mutex::new(Scrollback {
+    lines: [EMPTY_ROW; SCROLLBACK_LINES],
+    start: 0,
+    len: 0,
+    live: [EMPTY_ROW; BUFFER_HEIGHT],
+    offset: 0,
+});
+
+/// One per terminal, only locked by its writer while it holds its own lock.
+static SCROLLBACKS: [Mutex<Scrollback>; TERMINALS] = [EMPTY_SCROLLBACK; TERMINALS];
+
+/// Screens of the terminals that are not shown, swapped with the text mode
+/// memory on a switch.
+static mut OFFSCREEN: [[[u16; BUFFER_WIDTH]; BUFFER_HEIGHT]; TERMINALS - 1] =
+    [[[0; BUFFER_WIDTH]; BUFFER_HEIGHT]; TERMINALS - 1];
+
+/// Where the writer is in an escape sequence.
+#[derive(Debug, Clone, Copy)]
+enum Escape {
+    None,
+    /// After ESC.
+    Started,
+    /// After ESC [, collecting `count` numeric parameters.
+    Csi {
+        params: [u16; MAX_ESCAPE_PARAMS],
+        count: usize,
+    },
+}
+
+fn write_crtc(register: u8, value: u8) {
+    let mut index = Port::<u8>::new(CRTC_INDEX);
+    let mut data = Port::<u8>::new(CRTC_DATA);
+    unsafe {
+        index.write(register);
+        data.write(value);
+    }
+}
+
+/// Writes text to the screen. Newline, carriage return, tab and backspace
+/// move the cursor, and a subset of the ANSI escape sequences changes the
+/// colors (SGR) and moves the cursor (CUU, CUD, CUF, CUB, CHA, CUP) or
+/// erases (ED, EL).
 pub struct Writer {
+    terminal: usize,
+    /// Whether `buffer` is on the screen.
+    active: bool,
     column_position: usize,
+    row_position: usize,
     color_code: ColorCode,
+    default_color: ColorCode,
+    escape: Escape,
+    /// Column shown by the hardware cursor instead of the write position
+    /// until the next write.
+    cursor_override: Option<usize>,
     buffer: &'static mut Buffer,
+    /// Whether finished lines are copied to the kernel message buffer.
+    record_lines: bool,
+    /// Draws the text on a framebuffer instead of the text mode screen.
+    graphics: Option<GraphicsConsole>,
 }
 
 impl Writer {
+    fn new(terminal: usize, color_code: ColorCode, buffer: &'static mut Buffer) -> Self {
+        Self {
+            terminal,
+            active: false,
+            column_position: 0,
+            row_position: BUFFER_HEIGHT - 1,
+            color_code,
+            default_color: color_code,
+            escape: Escape::None,
+            cursor_override: None,
+            buffer,
+            record_lines: true,
+            graphics: None,
+        }
+    }
+
     fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
@@ -65,7 +283,7 @@ impl Writer {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
@@ -78,16 +296,156 @@ impl Writer {
         }
     }
 
-    fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+    fn write_char(&mut self, ch: char) {
+        match self.escape {
+            Escape::None => {}
+            Escape::Started => {
+                self.escape = match ch {
+                    '[' => Escape::Csi {
+                        params: [0; MAX_ESCAPE_PARAMS],
+                        count: 0,
+                    },
+                    _ => Escape::None,
+                };
+                return;
+            }
+            Escape::Csi {
+                mut params,
+                mut count,
+            } => {
+                match ch {
+                    '0'..='9' => {
+                        count = count.max(1);
+                        if let Some(param) = params.get_mut(count - 1) {
+                            let digit = ch as u16 - '0' as u16;
+                            *param = param.saturating_mul(10).saturating_add(digit);
+                        }
+                        self.escape = Escape::Csi { params, count };
+                    }
+                    ';' => {
+                        count = count.max(1) + 1;
+                        self.escape = Escape::Csi { params, count };
+                    }
+                    '@'..='~' => {
+                        self.escape = Escape::None;
+                        self.control_sequence(ch, &params[..count.min(MAX_ESCAPE_PARAMS)]);
+                    }
+                    // private markers and intermediate bytes are not supported
+                    _ => self.escape = Escape::None,
+                }
+                return;
+            }
+        }
+        match ch {
+            '\n' => self.new_line(),
+            '\r' => self.column_position = 0,
+            '\t' => {
+                let spaces = TAB_WIDTH - self.column_position % TAB_WIDTH;
+                for _ in 0..spaces {
+                    self.write_byte(b' ');
+                }
+            }
+            // erases the character before the cursor
+            '\u{8}' => {
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+                    self.clear_cells(
+                        self.row_position,
+                        self.column_position..self.column_position + 1,
+                    );
+                }
+            }
+            '\u{1b}' => self.escape = Escape::Started,
+            _ => self.write_byte(to_cp437(ch)),
+        }
+    }
+
+    fn control_sequence(&mut self, command: char, params: &[u16]) {
+        // counts and positions default to 1, also when given as 0
+        let count = |i: usize| params.get(i).map_or(1, |&param| param.max(1) as usize);
+        let mode = params.first().copied().unwrap_or(0);
+        let col = self.column_position.min(BUFFER_WIDTH - 1);
+        match command {
+            'A' => self.row_position = self.row_position.saturating_sub(count(0)),
+            'B' => self.row_position = (self.row_position + count(0)).min(BUFFER_HEIGHT - 1),
+            'C' => self.column_position = (col + count(0)).min(BUFFER_WIDTH - 1),
+            'D' => self.column_position = col.saturating_sub(count(0)),
+            'G' => self.column_position = (count(0) - 1).min(BUFFER_WIDTH - 1),
+            'H' | 'f' => {
+                self.row_position = (count(0) - 1).min(BUFFER_HEIGHT - 1);
+                self.column_position = (count(1) - 1).min(BUFFER_WIDTH - 1);
+            }
+            'J' => {
+                let rows = match mode {
+                    0 => self.row_position + 1..BUFFER_HEIGHT,
+                    1 => 0..self.row_position,
+                    _ => 0..BUFFER_HEIGHT,
+                };
+                for row in rows {
+                    self.clear_row(row);
+                }
+                if mode < 2 {
+                    self.erase_in_line(mode);
+                }
             }
+            'K' => self.erase_in_line(mode),
+            'm' => self.select_graphic_rendition(params),
+            _ => {}
+        }
+    }
+
+    /// Erases the current line after the cursor (0), before and including
+    /// it (1) or entirely (2).
+    fn erase_in_line(&mut self, mode: u16) {
+        let col = self.column_position.min(BUFFER_WIDTH - 1);
+        let cols = match mode {
+            0 => col..BUFFER_WIDTH,
+            1 => 0..col + 1,
+            _ => 0..BUFFER_WIDTH,
+        };
+        self.clear_cells(self.row_position, cols);
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            self.color_code = self.default_color;
+        }
+        for &param in params {
+            let color = self.color_code;
+            let bright = color.foreground() & BRIGHT;
+            self.color_code = match param {
+                0 => self.default_color,
+                1 => color.with_foreground(color.foreground() | BRIGHT),
+                22 => color.with_foreground(color.foreground() & !BRIGHT),
+                30..=37 => color.with_foreground(ANSI_COLORS[param as usize - 30] as u8 | bright),
+                39 => color.with_foreground(self.default_color.foreground()),
+                40..=47 => color.with_background(ANSI_COLORS[param as usize - 40] as u8),
+                49 => color.with_background(self.default_color.background()),
+                90..=97 => color.with_foreground(ANSI_COLORS[param as usize - 90] as u8 | BRIGHT),
+                _ => color,
+            };
         }
     }
 
+    fn write_string(&mut self, s: &str) {
+        self.follow_output();
+        self.cursor_override = None;
+        for ch in s.chars() {
+            self.write_char(ch);
+        }
+        self.refresh();
+    }
+
     fn new_line(&mut self) {
+        if self.record_lines {
+            self.record_line();
+        }
+        self.column_position = 0;
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            return;
+        }
+        self.scrollback().lock().push(self.read_row(0));
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let charater = self.buffer.chars[row][col].read();
@@ -95,19 +453,226 @@ impl Writer {
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
+    }
+
+    /// Copies the current line without trailing blanks to the kernel
+    /// message buffer.
+    fn record_line(&self) {
+        let row = self.read_row(self.row_position);
+        let len = row
+            .iter()
+            .rposition(|screen_char| !matches!(screen_char.ascii_character, 0 | b' '))
+            .map_or(0, |last| last + 1);
+        dmesg::push_fmt(format_args!("{}", RowText(&row[..len])));
+    }
+
+    fn read_row(&self, row: usize) -> Row {
+        let mut chars = EMPTY_ROW;
+        for (screen_char, cell) in chars.iter_mut().zip(self.buffer.chars[row].iter()) {
+            *screen_char = cell.read();
+        }
+        chars
+    }
+
+    fn write_row(&mut self, row: usize, chars: &Row) {
+        for (cell, screen_char) in self.buffer.chars[row].iter_mut().zip(chars.iter()) {
+            cell.write(*screen_char);
+        }
+    }
+
+    /// Replaces the line being written with `text` and shows the cursor at
+    /// column `cursor`, or after the text.
+    fn rewrite_line(&mut self, text: &str, cursor: Option<usize>) {
+        self.follow_output();
+        self.clear_row(self.row_position);
+        self.column_position = 0;
+        for ch in text.chars().take(BUFFER_WIDTH) {
+            self.write_byte(to_cp437(ch));
+        }
+        self.cursor_override = cursor.filter(|col| *col < BUFFER_WIDTH);
+        self.refresh();
+    }
+
+    fn clear_screen(&mut self) {
+        self.follow_output();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
         self.column_position = 0;
+        self.row_position = BUFFER_HEIGHT - 1;
+        self.refresh();
     }
 
     fn clear_row(&mut self, row: usize) {
+        self.clear_cells(row, 0..BUFFER_WIDTH);
+    }
+
+    fn clear_cells(&mut self, row: usize, cols: Range<usize>) {
         let blank = ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
         };
 
-        for col in 0..BUFFER_WIDTH {
+        for col in cols {
             self.buffer.chars[row][col].write(blank);
         }
     }
+
+    /// Shows the screen as it was `offset` lines of output ago, limited to
+    /// the lines kept in the scrollback buffer.
+    fn scroll_view(&mut self, offset: usize) {
+        let mut scrollback = self.scrollback().lock();
+        let offset = offset.min(scrollback.len);
+        if offset == scrollback.offset {
+            return;
+        }
+        if scrollback.offset == 0 {
+            for row in 0..BUFFER_HEIGHT {
+                scrollback.live[row] = self.read_row(row);
+            }
+        }
+        scrollback.offset = offset;
+        let top = scrollback.len - offset;
+        for row in 0..BUFFER_HEIGHT {
+            let line = *scrollback.line(top + row);
+            self.write_row(row, &line);
+        }
+        drop(scrollback);
+        self.refresh();
+    }
+
+    /// Returns to the live screen before it is written to.
+    fn follow_output(&mut self) {
+        if self.scrollback().lock().offset != 0 {
+            self.scroll_view(0);
+        }
+    }
+
+    fn scrollback(&self) -> &'static Mutex<Scrollback> {
+        &SCROLLBACKS[self.terminal]
+    }
+
+    fn enable_cursor(&mut self) {
+        write_crtc(CRTC_CURSOR_START, CURSOR_FIRST_LINE);
+        write_crtc(CRTC_CURSOR_END, CURSOR_LAST_LINE);
+        self.refresh();
+    }
+
+    /// Row and column of the cursor, none while the view is scrolled back.
+    fn cursor(&self) -> Option<(usize, usize)> {
+        if self.scrollback().lock().offset != 0 {
+            return None;
+        }
+        let col = self
+            .cursor_override
+            .unwrap_or(self.column_position)
+            .min(BUFFER_WIDTH - 1);
+        Some((self.row_position, col))
+    }
+
+    /// Shows the cursor at the write position and the changed cells on the
+    /// framebuffer, if one is used.
+    fn refresh(&mut self) {
+        if !self.active {
+            return;
+        }
+        let cursor = self.cursor();
+        match &mut self.graphics {
+            Some(graphics) => graphics.draw(self.buffer, cursor),
+            None => move_hardware_cursor(cursor),
+        }
+    }
+}
+
+fn move_hardware_cursor(cursor: Option<(usize, usize)>) {
+    let (row, col) = match cursor {
+        Some(cursor) => cursor,
+        None => {
+            write_crtc(CRTC_CURSOR_START, CURSOR_DISABLED);
+            return;
+        }
+    };
+    write_crtc(CRTC_CURSOR_START, CURSOR_FIRST_LINE);
+    let position = (row * BUFFER_WIDTH + col) as u16;
+    write_crtc(CRTC_CURSOR_HIGH, (position >> 8) as u8);
+    write_crtc(CRTC_CURSOR_LOW, position as u8);
+}
+
+/// The text screen drawn on a framebuffer with the built-in font.
+struct GraphicsConsole {
+    framebuffer: Framebuffer,
+    /// What each cell shows on the framebuffer, so only changes are drawn.
+    shown: Box<[[Option<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT]>,
+    cursor: Option<(usize, usize)>,
+}
+
+impl GraphicsConsole {
+    fn new(framebuffer: Framebuffer) -> Self {
+        Self {
+            framebuffer,
+            shown: Box::new([[None; BUFFER_WIDTH]; BUFFER_HEIGHT]),
+            cursor: None,
+        }
+    }
+
+    /// Makes the next `draw` redraw every cell.
+    fn invalidate(&mut self) {
+        for row in self.shown.iter_mut() {
+            row.fill(None);
+        }
+    }
+
+    fn draw(&mut self, buffer: &Buffer, cursor: Option<(usize, usize)>) {
+        // the cell under the old cursor needs to be drawn without it
+        if let Some((row, col)) = self.cursor.filter(|_| self.cursor != cursor) {
+            self.shown[row][col] = None;
+        }
+        for (row, (cells, shown)) in buffer.chars.iter().zip(self.shown.iter_mut()).enumerate() {
+            for (col, (cell, shown)) in cells.iter().zip(shown.iter_mut()).enumerate() {
+                let screen_char = cell.read();
+                if *shown == Some(screen_char) && self.cursor != Some((row, col)) {
+                    continue;
+                }
+                *shown = Some(screen_char);
+                let (x, y) = Framebuffer::cell_position(col, row);
+                let ColorCode(code) = screen_char.color_code;
+                self.framebuffer.draw_glyph(
+                    x,
+                    y,
+                    screen_char.ascii_character,
+                    PALETTE[code as usize & 0x0f],
+                    PALETTE[code as usize >> 4],
+                );
+            }
+        }
+        if let Some((row, col)) = cursor {
+            let (x, y) = Framebuffer::cell_position(col, row);
+            let ColorCode(code) = buffer.chars[row][col].read().color_code;
+            let first_line = CURSOR_FIRST_LINE as usize;
+            let lines = (CURSOR_LAST_LINE - CURSOR_FIRST_LINE + 1) as usize;
+            self.framebuffer.fill_rect(
+                x,
+                y + first_line,
+                GLYPH_WIDTH,
+                lines,
+                PALETTE[code as usize & 0x0f],
+            );
+        }
+        self.cursor = cursor;
+    }
+}
+
+/// Screen characters decoded back to Unicode.
+struct RowText<'a>(&'a [ScreenChar]);
+
+impl fmt::Display for RowText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use core::fmt::Write;
+        for screen_char in self.0 {
+            f.write_char(from_cp437(screen_char.ascii_character))?;
+        }
+        Ok(())
+    }
 }
 
 impl fmt::Write for Writer {
@@ -117,13 +682,18 @@ impl fmt::Write for Writer {
     }
 }
 
+fn text_mode_buffer() -> &'static mut Buffer {
+    unsafe { &mut *(0xb8000 as *mut Buffer) }
+}
+
 pub fn print_something() {
     use core::fmt::Write;
-    let mut writer = Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    };
+    let mut writer = Writer::new(
+        CONSOLE,
+        ColorCode::new(Color::Yellow, Color::Black),
+        text_mode_buffer(),
+    );
+    writer.record_lines = false;
 
     writer.write_byte(b'H');
     writer.write_string("ello, ");
@@ -132,11 +702,86 @@ pub fn print_something() {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+    /// The terminals, the one at `active_terminal()` writing to the screen.
+    pub static ref WRITERS: [Mutex<Writer>; TERMINALS] = {
+        let color_code = ColorCode::new(Color::Yellow, Color::Black);
+        core::array::from_fn(|terminal| {
+            if terminal == CONSOLE {
+                let mut writer = Writer::new(terminal, color_code, text_mode_buffer());
+                writer.active = true;
+                writer.enable_cursor();
+                return Mutex::new(writer);
+            }
+            // the console is terminal 0 and starts out on the screen
+            let screen = unsafe { core::ptr::addr_of_mut!(OFFSCREEN[terminal - 1]) };
+            let mut writer = Writer::new(terminal, color_code, unsafe { &mut *(screen as *mut Buffer) });
+            writer.clear_screen();
+            Mutex::new(writer)
+        })
+    };
+}
+
+static ACTIVE: AtomicUsize = AtomicUsize::new(CONSOLE);
+/// Held while the active terminal changes or is used for the screen.
+static SWITCH: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminalError {
+    NoSuchTerminal(usize),
+}
+
+pub fn active_terminal() -> usize {
+    ACTIVE.load(Ordering::Acquire)
+}
+
+/// Runs `f` on the writer of the terminal on the screen.
+fn with_active<R>(f: impl FnOnce(&mut Writer) -> R) -> R {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let _switch = SWITCH.lock();
+        f(&mut WRITERS[active_terminal()].lock())
+    })
+}
+
+/// Shows `terminal` on the screen. Its contents are swapped with those of
+/// the terminal shown so far, which keeps writing off screen.
+pub fn switch_to(terminal: usize) -> Result<(), TerminalError> {
+    use x86_64::instructions::interrupts;
+    if terminal >= TERMINALS {
+        return Err(TerminalError::NoSuchTerminal(terminal));
+    }
+    interrupts::without_interrupts(|| {
+        let _switch = SWITCH.lock();
+        let current = active_terminal();
+        if current == terminal {
+            return;
+        }
+        // lock in index order like every other place locking two writers
+        let mut first = WRITERS[current.min(terminal)].lock();
+        let mut second = WRITERS[current.max(terminal)].lock();
+        let (from, to) = if current < terminal {
+            (&mut *first, &mut *second)
+        } else {
+            (&mut *second, &mut *first)
+        };
+        for (from_row, to_row) in from.buffer.chars.iter_mut().zip(to.buffer.chars.iter_mut()) {
+            for (shown, hidden) in from_row.iter_mut().zip(to_row.iter_mut()) {
+                let screen_char = shown.read();
+                shown.write(hidden.read());
+                hidden.write(screen_char);
+            }
+        }
+        core::mem::swap(&mut from.buffer, &mut to.buffer);
+        to.graphics = from.graphics.take();
+        if let Some(graphics) = &mut to.graphics {
+            graphics.invalidate();
+        }
+        from.active = false;
+        to.active = true;
+        ACTIVE.store(terminal, Ordering::Release);
+        to.refresh();
     });
+    Ok(())
 }
 
 #[macro_export]
@@ -156,10 +801,94 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
+    print_to(CONSOLE, args);
+}
+
+/// Prints to `terminal`, whether it is shown or not. Out of range
+/// terminals are ignored.
+pub fn print_to(terminal: usize, args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    if let Some(writer) = WRITERS.get(terminal) {
+        interrupts::without_interrupts(|| {
+            writer.lock().write_fmt(args).unwrap();
+        });
+    }
+}
+
+/// Redraws the current line of the console for line editing. Text beyond
+/// the width of the screen is cut off.
+pub fn rewrite_line(text: &str, cursor: Option<usize>) {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| WRITERS[CONSOLE].lock().rewrite_line(text, cursor));
+}
+
+pub fn clear_screen() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| WRITERS[CONSOLE].lock().clear_screen());
+}
+
+/// Draws the screen on `framebuffer` from now on. The text keeps its size
+/// of `BUFFER_WIDTH` by `BUFFER_HEIGHT` characters in the top left corner,
+/// the rest of the framebuffer is free for drawing.
+pub fn use_framebuffer(framebuffer: Framebuffer) {
+    // the text mode memory may show up in the framebuffer, so the text is
+    // kept in memory of its own
+    let layout = Layout::new::<Buffer>();
+    // all zero bytes are a valid screen
+    let shadow = unsafe { alloc::alloc::alloc_zeroed(layout) as *mut Buffer };
+    if shadow.is_null() {
+        alloc::alloc::handle_alloc_error(layout);
+    }
+    let mut shadow = unsafe { Box::from_raw(shadow) };
+    with_active(move |writer| {
+        // the shadow buffer is freed again if it isn't needed
+        if writer.graphics.is_some() {
+            return;
+        }
+        for (row, cells) in shadow.chars.iter_mut().enumerate() {
+            for (col, cell) in cells.iter_mut().enumerate() {
+                cell.write(writer.buffer.chars[row][col].read());
+            }
+        }
+        move_hardware_cursor(None);
+        writer.buffer = Box::leak(shadow);
+        writer.graphics = Some(GraphicsConsole::new(framebuffer));
+        writer.refresh();
+    });
+}
+
+/// Shows `lines` older lines of output of the terminal on the screen,
+/// until its next write.
+pub fn scroll_back(lines: usize) {
+    with_active(|writer| {
+        let offset = writer.scrollback().lock().offset;
+        writer.scroll_view(offset.saturating_add(lines));
+    });
+}
+
+pub fn scroll_forward(lines: usize) {
+    with_active(|writer| {
+        let offset = writer.scrollback().lock().offset;
+        writer.scroll_view(offset.saturating_sub(lines));
+    });
+}
+
+/// How many lines the terminal on the screen is scrolled back.
+pub fn scroll_offset() -> usize {
+    with_active(|writer| writer.scrollback().lock().offset)
+}
+
+/// Prints to `terminal` without copying the output to the kernel message
+/// buffer, for text that gets recorded there by other means.
+pub(crate) fn print_unrecorded(terminal: usize, args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        let mut writer = WRITERS[terminal].lock();
+        writer.record_lines = false;
+        writer.write_fmt(args).unwrap();
+        writer.record_lines = true;
     });
 }
 
@@ -175,7 +904,7 @@ fn test_println_output() {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
     interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
+        let mut writer = WRITERS[CONSOLE].lock();
         let s = "some test string that fitson a single line.";
         writeln!(writer, "\n{}", s).expect("writeln failed");
         for (i, c) in s.chars().enumerate() {
@@ -190,14 +919,134 @@ fn test_println_ok() {
     let s = "This should be last line at first";
     print!("{}", s);
     for (i, c) in s.chars().enumerate() {
-        let screen_char = WRITER.lock().buffer.chars[BUFFER_HEIGHT - 1][i].read();
+        let screen_char = WRITERS[CONSOLE].lock().buffer.chars[BUFFER_HEIGHT - 1][i].read();
         assert_eq!(c, char::from(screen_char.ascii_character));
     }
 
     println!();
 
     for (i, c) in s.chars().enumerate() {
-        let screen_char = WRITER.lock().buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        let screen_char = WRITERS[CONSOLE].lock().buffer.chars[BUFFER_HEIGHT - 2][i].read();
         assert_eq!(c, char::from(screen_char.ascii_character));
     }
 }
+
+#[cfg(test)]
+fn bottom_row() -> Row {
+    let writer = WRITERS[CONSOLE].lock();
+    writer.read_row(BUFFER_HEIGHT - 1)
+}
+
+/// Whether `row` starts with `text`.
+#[cfg(test)]
+fn row_starts_with(row: &Row, text: &str) -> bool {
+    text.chars().count() <= BUFFER_WIDTH
+        && text
+            .chars()
+            .zip(row.iter())
+            .all(|(ch, screen_char)| from_cp437(screen_char.ascii_character) == ch)
+}
+
+#[test_case]
+fn test_control_characters() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITERS[CONSOLE].lock();
+        writer.write_string("\nab\tc\rX\u{8}Y");
+        let row = writer.read_row(BUFFER_HEIGHT - 1);
+        assert!(row_starts_with(&row, "Yb      c "));
+        assert_eq!(writer.column_position, 1);
+    });
+}
+
+#[test_case]
+fn test_ansi_colors_and_movement() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITERS[CONSOLE].lock();
+        let default = writer.default_color;
+        writer.write_string("\n\x1b[31;44mr\x1b[1mR\x1b[0md");
+        let row = writer.read_row(BUFFER_HEIGHT - 1);
+        assert_eq!(row[0].color_code, ColorCode::new(Color::Red, Color::Blue));
+        assert_eq!(
+            row[1].color_code,
+            ColorCode::new(Color::LightRed, Color::Blue)
+        );
+        assert_eq!(row[2].color_code, default);
+        assert_eq!(writer.color_code, default);
+
+        writer.write_string("\x1b[3Dz\x1b[K");
+        assert!(row_starts_with(&writer.read_row(BUFFER_HEIGHT - 1), "z  "));
+
+        writer.write_string("\x1b[2;5Hq");
+        assert_eq!(writer.read_row(1)[4].ascii_character, b'q');
+        write!(writer, "\x1b[{}H", BUFFER_HEIGHT).unwrap();
+        assert_eq!(writer.row_position, BUFFER_HEIGHT - 1);
+    });
+}
+
+#[test_case]
+fn test_cp437_mapping() {
+    println!();
+    print!("é─█π?\u{1f600}");
+    let row = bottom_row();
+    let expected = [0x82, 0xc4, 0xdb, 0xe3, b'?', UNKNOWN_GLYPH];
+    for (screen_char, byte) in row.iter().zip(expected.iter()) {
+        assert_eq!(screen_char.ascii_character, *byte);
+    }
+    assert!(row_starts_with(&row, "é─█π"));
+}
+
+#[test_case]
+fn test_scrollback() {
+    for i in 0..BUFFER_HEIGHT {
+        println!("scrollback line {}", i);
+    }
+    scroll_back(BUFFER_HEIGHT);
+    assert_eq!(scroll_offset(), BUFFER_HEIGHT);
+    assert!(row_starts_with(&bottom_row(), "scrollback line 0 "));
+    scroll_forward(1);
+    assert_eq!(scroll_offset(), BUFFER_HEIGHT - 1);
+    assert!(row_starts_with(&bottom_row(), "scrollback line 1 "));
+    print!("back to the live screen");
+    assert_eq!(scroll_offset(), 0);
+    assert!(row_starts_with(&bottom_row(), "back to the live screen"));
+}
+
+#[test_case]
+fn test_virtual_terminals() {
+    const HIDDEN: usize = TERMINALS - 1;
+    let screen = text_mode_buffer() as *const Buffer;
+    println!();
+    print!("on the console");
+    print_to(HIDDEN, format_args!("\noff screen"));
+    assert!(row_starts_with(&bottom_row(), "on the console"));
+
+    assert_eq!(switch_to(HIDDEN), Ok(()));
+    assert_eq!(active_terminal(), HIDDEN);
+    {
+        let writer = WRITERS[HIDDEN].lock();
+        assert!(core::ptr::eq(&*writer.buffer, screen));
+        assert!(row_starts_with(
+            &writer.read_row(BUFFER_HEIGHT - 1),
+            "off screen"
+        ));
+    }
+    print!(", still written");
+    assert!(row_starts_with(
+        &bottom_row(),
+        "on the console, still written"
+    ));
+
+    assert_eq!(
+        switch_to(TERMINALS),
+        Err(TerminalError::NoSuchTerminal(TERMINALS))
+    );
+    assert_eq!(switch_to(CONSOLE), Ok(()));
+    assert!(core::ptr::eq(&*WRITERS[CONSOLE].lock().buffer, screen));
+    assert!(row_starts_with(
+        &bottom_row(),
+        "on the console, still written"
+    ));
+}
@@ -0,0 +1,372 @@
+//! Virtio devices on PCI. Transitional devices are driven through the
+//! modern interface described by their vendor capabilities when they have
+//! one, and through the legacy registers in their I/O BAR otherwise.
+
+pub mod block;
+pub mod queue;
+
+pub use queue::Virtqueue;
+
+use crate::interrupts::IrqError;
+use crate::memory;
+use crate::pci::{self, Bar, PciDevice};
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const STATUS_DRIVER: u8 = 1 << 1;
+const STATUS_DRIVER_OK: u8 = 1 << 2;
+const STATUS_FEATURES_OK: u8 = 1 << 3;
+const STATUS_FAILED: u8 = 1 << 7;
+
+/// Offered by every device with the modern interface, which is the one
+/// the driver then has to use.
+const F_VERSION_1: u64 = 1 << 32;
+
+/// Set in the ISR status when a queue has used buffers.
+pub const ISR_QUEUE: u8 = 1 << 0;
+
+// registers of legacy devices in BAR 0
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_PFN: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_STATUS: u16 = 0x12;
+const LEGACY_ISR: u16 = 0x13;
+/// The device configuration follows the registers while MSI-X is off.
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+const LEGACY_PAGE_SHIFT: u64 = 12;
+
+// structures the vendor capabilities point to
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_ISR: u8 = 3;
+const CFG_DEVICE: u8 = 4;
+// fields of the vendor capabilities
+const CAP_CFG_TYPE: u16 = 3;
+const CAP_BAR: u16 = 4;
+const CAP_OFFSET: u16 = 8;
+const CAP_LENGTH: u16 = 12;
+const CAP_NOTIFY_MULTIPLIER: u16 = 16;
+
+// the common configuration of modern devices
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DEVICE_FEATURE: u64 = 0x04;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0c;
+const DEVICE_STATUS: u64 = 0x14;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_ENABLE: u64 = 0x1c;
+const QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const QUEUE_DESC: u64 = 0x20;
+const QUEUE_DRIVER: u64 = 0x28;
+const QUEUE_DEVICE: u64 = 0x30;
+
+/// Larger queues offered by modern devices are cut down to this.
+const MAX_QUEUE_SIZE: u16 = 256;
+const RESET_TIMEOUT_POLLS: usize = 1_000_000;
+
+#[derive(Debug)]
+pub enum VirtioError {
+    AlreadyInitialized,
+    /// Neither the vendor capabilities nor an I/O BAR 0 were found.
+    NoTransport,
+    FeaturesRejected,
+    /// The device has no queue with this index.
+    NoQueue(u16),
+    BadQueueSize(u16),
+    NoMemory,
+    /// The device doesn't use an interrupt pin.
+    NoInterrupt,
+    /// The device didn't finish a reset.
+    Timeout,
+    Map(MapToError<Size4KiB>),
+    Irq(IrqError),
+}
+
+/// The registers of a modern device, each found through a capability.
+/// They are unmapped on drop.
+#[derive(Debug)]
+pub struct ModernRegisters {
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    isr: VirtAddr,
+    device: VirtAddr,
+    /// The sizes of the windows in the order above.
+    lengths: [u64; 4],
+}
+
+impl Drop for ModernRegisters {
+    fn drop(&mut self) {
+        let bases = [self.common, self.notify, self.isr, self.device];
+        for (base, length) in bases.iter().zip(self.lengths.iter()) {
+            memory::unmap_mmio(*base, *length);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Transport {
+    Legacy { port: u16 },
+    Modern(ModernRegisters),
+}
+
+unsafe fn read_mmio<T>(base: VirtAddr, offset: u64) -> T {
+    (base + offset).as_ptr::<T>().read_volatile()
+}
+
+unsafe fn write_mmio<T>(base: VirtAddr, offset: u64, value: T) {
+    (base + offset).as_mut_ptr::<T>().write_volatile(value)
+}
+
+fn probe_modern(device: &PciDevice) -> Result<Option<ModernRegisters>, VirtioError> {
+    let (mut common, mut notify, mut isr, mut config) = (None, None, None, None);
+    let mut notify_multiplier = 0;
+    for capability in device.capabilities_with(pci::CAP_VENDOR) {
+        let field = |offset| capability.offset as u16 + offset;
+        let cfg_type = pci::read_u8(device.address, field(CAP_CFG_TYPE));
+        let bar = pci::read_u8(device.address, field(CAP_BAR)) as usize;
+        let base = match device.bars.get(bar) {
+            Some(Some(Bar::Memory { address, .. })) => *address,
+            _ => continue,
+        };
+        let start = base + device.read_config(field(CAP_OFFSET)) as u64;
+        let window = Some((start, device.read_config(field(CAP_LENGTH)) as u64));
+        // the first capability of each type is the one to use
+        match cfg_type {
+            CFG_COMMON if common.is_none() => common = window,
+            CFG_NOTIFY if notify.is_none() => {
+                notify = window;
+                notify_multiplier = device.read_config(field(CAP_NOTIFY_MULTIPLIER));
+            }
+            CFG_ISR if isr.is_none() => isr = window,
+            CFG_DEVICE if config.is_none() => config = window,
+            _ => {}
+        }
+    }
+    let windows = match (common, notify, isr, config) {
+        (Some(common), Some(notify), Some(isr), Some(config)) => [common, notify, isr, config],
+        _ => return Ok(None),
+    };
+    let mut bases = [VirtAddr::zero(); 4];
+    for (i, (start, length)) in windows.iter().enumerate() {
+        match memory::map_mmio(*start, *length) {
+            Ok(base) => bases[i] = base,
+            Err(err) => {
+                for (base, (_, length)) in bases.iter().zip(windows.iter()).take(i) {
+                    memory::unmap_mmio(*base, *length);
+                }
+                return Err(VirtioError::Map(err));
+            }
+        }
+    }
+    Ok(Some(ModernRegisters {
+        common: bases[0],
+        notify: bases[1],
+        notify_multiplier,
+        isr: bases[2],
+        device: bases[3],
+        lengths: [windows[0].1, windows[1].1, windows[2].1, windows[3].1],
+    }))
+}
+
+impl Transport {
+    /// Finds the registers of `device`, preferring the modern interface.
+    pub fn probe(device: &PciDevice) -> Result<Self, VirtioError> {
+        if let Some(registers) = probe_modern(device)? {
+            return Ok(Transport::Modern(registers));
+        }
+        match device.bars[0] {
+            Some(Bar::Io { port, .. }) => Ok(Transport::Legacy { port }),
+            _ => Err(VirtioError::NoTransport),
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, Transport::Legacy { .. })
+    }
+
+    unsafe fn read_port<T: PortRead>(port: u16, register: u16) -> T {
+        Port::<T>::new(port + register).read()
+    }
+
+    unsafe fn write_port<T: PortWrite>(port: u16, register: u16, value: T) {
+        Port::<T>::new(port + register).write(value)
+    }
+
+    fn status(&self) -> u8 {
+        unsafe {
+            match self {
+                Transport::Legacy { port } => Self::read_port(*port, LEGACY_STATUS),
+                Transport::Modern(registers) => read_mmio(registers.common, DEVICE_STATUS),
+            }
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        unsafe {
+            match self {
+                Transport::Legacy { port } => Self::write_port(*port, LEGACY_STATUS, status),
+                Transport::Modern(registers) => write_mmio(registers.common, DEVICE_STATUS, status),
+            }
+        }
+    }
+
+    fn add_status(&self, status: u8) {
+        self.set_status(self.status() | status);
+    }
+
+    /// Resets the device, which then no longer uses its queues.
+    pub fn reset(&self) -> Result<(), VirtioError> {
+        self.set_status(0);
+        // modern devices may take a while to finish the reset
+        for _ in 0..RESET_TIMEOUT_POLLS {
+            if self.status() == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(VirtioError::Timeout)
+    }
+
+    /// Resets the device and negotiates the `supported` features with it.
+    /// Returns the features both sides use.
+    pub fn begin(&self, supported: u64) -> Result<u64, VirtioError> {
+        self.reset()?;
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+        match self {
+            Transport::Legacy { port } => unsafe {
+                let offered: u32 = Self::read_port(*port, LEGACY_DEVICE_FEATURES);
+                let accepted = offered & supported as u32;
+                Self::write_port(*port, LEGACY_DRIVER_FEATURES, accepted);
+                Ok(accepted as u64)
+            },
+            Transport::Modern(registers) => {
+                let common = registers.common;
+                let offered = unsafe {
+                    write_mmio(common, DEVICE_FEATURE_SELECT, 0u32);
+                    let low: u32 = read_mmio(common, DEVICE_FEATURE);
+                    write_mmio(common, DEVICE_FEATURE_SELECT, 1u32);
+                    let high: u32 = read_mmio(common, DEVICE_FEATURE);
+                    (high as u64) << 32 | low as u64
+                };
+                if offered & F_VERSION_1 == 0 {
+                    return Err(VirtioError::FeaturesRejected);
+                }
+                let accepted = offered & (supported | F_VERSION_1);
+                unsafe {
+                    write_mmio(common, DRIVER_FEATURE_SELECT, 0u32);
+                    write_mmio(common, DRIVER_FEATURE, accepted as u32);
+                    write_mmio(common, DRIVER_FEATURE_SELECT, 1u32);
+                    write_mmio(common, DRIVER_FEATURE, (accepted >> 32) as u32);
+                }
+                self.add_status(STATUS_FEATURES_OK);
+                if self.status() & STATUS_FEATURES_OK == 0 {
+                    return Err(VirtioError::FeaturesRejected);
+                }
+                Ok(accepted)
+            }
+        }
+    }
+
+    /// Allocates queue `index` and hands it to the device.
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        match self {
+            Transport::Legacy { port } => unsafe {
+                Self::write_port(*port, LEGACY_QUEUE_SELECT, index);
+                // legacy devices dictate the size
+                let size: u16 = Self::read_port(*port, LEGACY_QUEUE_SIZE);
+                if size == 0 {
+                    return Err(VirtioError::NoQueue(index));
+                }
+                let queue = Virtqueue::new(index, size, 0)?;
+                let pfn = queue.descriptor_table().as_u64() >> LEGACY_PAGE_SHIFT;
+                Self::write_port(*port, LEGACY_QUEUE_PFN, pfn as u32);
+                Ok(queue)
+            },
+            Transport::Modern(registers) => unsafe {
+                let common = registers.common;
+                write_mmio(common, QUEUE_SELECT, index);
+                let max: u16 = read_mmio(common, QUEUE_SIZE);
+                if max == 0 {
+                    return Err(VirtioError::NoQueue(index));
+                }
+                let size = if max > MAX_QUEUE_SIZE {
+                    MAX_QUEUE_SIZE
+                } else {
+                    1 << (15 - max.leading_zeros())
+                };
+                write_mmio(common, QUEUE_SIZE, size);
+                let queue = Virtqueue::new(index, size, read_mmio(common, QUEUE_NOTIFY_OFF))?;
+                let write_u64 = |offset, address: PhysAddr| {
+                    write_mmio(common, offset, address.as_u64() as u32);
+                    write_mmio(common, offset + 4, (address.as_u64() >> 32) as u32);
+                };
+                write_u64(QUEUE_DESC, queue.descriptor_table());
+                write_u64(QUEUE_DRIVER, queue.avail_ring());
+                write_u64(QUEUE_DEVICE, queue.used_ring());
+                write_mmio(common, QUEUE_ENABLE, 1u16);
+                Ok(queue)
+            },
+        }
+    }
+
+    /// Lets the device process the queues.
+    pub fn finish(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device it won't be driven.
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    /// Tells the device new buffers are available on `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        unsafe {
+            match self {
+                Transport::Legacy { port } => {
+                    Self::write_port(*port, LEGACY_QUEUE_NOTIFY, queue.index())
+                }
+                Transport::Modern(registers) => {
+                    let offset = queue.notify_offset as u64 * registers.notify_multiplier as u64;
+                    write_mmio(registers.notify, offset, queue.index());
+                }
+            }
+        }
+    }
+
+    /// Reads and thereby clears the ISR status, which also lowers the
+    /// interrupt line.
+    pub fn acknowledge_interrupt(&self) -> u8 {
+        unsafe {
+            match self {
+                Transport::Legacy { port } => Self::read_port(*port, LEGACY_ISR),
+                Transport::Modern(registers) => read_mmio(registers.isr, 0),
+            }
+        }
+    }
+
+    pub fn read_config_u32(&self, offset: u16) -> u32 {
+        unsafe {
+            match self {
+                Transport::Legacy { port } => Self::read_port(*port, LEGACY_DEVICE_CONFIG + offset),
+                Transport::Modern(registers) => read_mmio(registers.device, offset as u64),
+            }
+        }
+    }
+
+    pub fn read_config_u64(&self, offset: u16) -> u64 {
+        let low = self.read_config_u32(offset) as u64;
+        (self.read_config_u32(offset + 4) as u64) << 32 | low
+    }
+}
@@ -0,0 +1,413 @@
+//! Virtio block devices. Every request is a chain of a header, the data and
+//! a status byte the device writes last, and completes with an interrupt on
+//! the PCI interrupt line.
+
+use super::queue::Buffer;
+use super::{Transport, VirtioError, Virtqueue, ISR_QUEUE, VENDOR_ID};
+use crate::block::{self, BlockDevice, BlockError, BlockFuture, SECTOR_SIZE};
+use crate::interrupts;
+use crate::memory::DmaBuffer;
+use crate::pci::{self, DeviceMatch, PciDevice};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Poll, Waker};
+use futures_util::future::poll_fn;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::PhysAddr;
+
+/// Transitional devices, which also have the legacy interface.
+pub const DEVICE_ID_TRANSITIONAL: u16 = 0x1001;
+pub const DEVICE_ID: u16 = 0x1042;
+
+const F_RO: u64 = 1 << 5;
+/// The capacity in sectors, whatever the block size of the device.
+const CONFIG_CAPACITY: u16 = 0;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// Requests in flight at once, three descriptors each.
+const SLOTS: usize = 8;
+const DESCRIPTORS_PER_REQUEST: usize = 3;
+const PAGE_SIZE: usize = 4096;
+/// Data moves through a page per slot, so longer transfers are split.
+const SECTORS_PER_REQUEST: usize = PAGE_SIZE / SECTOR_SIZE;
+/// Each slot's header and status byte live in the first page.
+const SLOT_HEADER_SIZE: usize = 32;
+const HEADER_SIZE: u32 = 16;
+const STATUS_OFFSET: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    Free,
+    Reserved,
+    /// Submitted as the chain starting at the descriptor.
+    InFlight(u16),
+    Done,
+    /// Its future was dropped, the slot is freed once the device is done.
+    Abandoned(u16),
+}
+
+/// Locked by the interrupt handler, so only with interrupts disabled.
+#[derive(Debug)]
+struct Requests {
+    queue: Virtqueue,
+    slots: [SlotState; SLOTS],
+    wakers: [Option<Waker>; SLOTS],
+    /// Tasks waiting for a free slot.
+    waiting: Vec<Waker>,
+}
+
+impl Requests {
+    fn free_slot(&mut self, slot: usize) {
+        self.slots[slot] = SlotState::Free;
+        for waker in self.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VirtioBlock {
+    name: String,
+    transport: Transport,
+    capacity: u64,
+    read_only: bool,
+    requests: Mutex<Requests>,
+    /// The headers and status bytes, then a data page per slot.
+    buffers: DmaBuffer,
+}
+
+/// Frees the slot when a request is done with it or its future is dropped.
+struct SlotGuard<'a> {
+    device: &'a VirtioBlock,
+    slot: usize,
+}
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        let slot = self.slot;
+        self.device
+            .with_requests(|requests| match requests.slots[slot] {
+                SlotState::InFlight(head) => requests.slots[slot] = SlotState::Abandoned(head),
+                _ => requests.free_slot(slot),
+            });
+    }
+}
+
+impl VirtioBlock {
+    fn new(device: &PciDevice, name: String) -> Result<Self, VirtioError> {
+        if device.interrupt_pin == 0 {
+            return Err(VirtioError::NoInterrupt);
+        }
+        let transport = Transport::probe(device)?;
+        let command = device.command() & !pci::COMMAND_INTERRUPT_DISABLE;
+        device
+            .set_command(command | pci::COMMAND_IO | pci::COMMAND_MEMORY | pci::COMMAND_BUS_MASTER);
+        let setup = || {
+            let features = transport.begin(F_RO)?;
+            let queue = transport.setup_queue(0)?;
+            let buffers = if (queue.size() as usize) < SLOTS * DESCRIPTORS_PER_REQUEST {
+                Err(VirtioError::BadQueueSize(queue.size()))
+            } else {
+                DmaBuffer::allocate(1 + SLOTS).ok_or(VirtioError::NoMemory)
+            };
+            match buffers {
+                Ok(buffers) => Ok((features, queue, buffers)),
+                Err(err) => {
+                    // the device has the queue, so it must let go of it
+                    // before the queue's memory is freed
+                    if transport.reset().is_err() {
+                        core::mem::forget(queue);
+                    }
+                    Err(err)
+                }
+            }
+        };
+        let (features, queue, buffers) = match setup() {
+            Ok(setup) => setup,
+            Err(err) => {
+                transport.fail();
+                return Err(err);
+            }
+        };
+        Ok(Self {
+            name,
+            capacity: transport.read_config_u64(CONFIG_CAPACITY),
+            read_only: features & F_RO != 0,
+            transport,
+            requests: Mutex::new(Requests {
+                queue,
+                slots: [SlotState::Free; SLOTS],
+                wakers: Default::default(),
+                waiting: Vec::new(),
+            }),
+            buffers,
+        })
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.transport.is_legacy()
+    }
+
+    fn with_requests<R>(&self, f: impl FnOnce(&mut Requests) -> R) -> R {
+        without_interrupts(|| f(&mut self.requests.lock()))
+    }
+
+    async fn reserve(&self) -> SlotGuard<'_> {
+        let slot = poll_fn(|cx| {
+            self.with_requests(|requests| {
+                match requests
+                    .slots
+                    .iter()
+                    .position(|slot| *slot == SlotState::Free)
+                {
+                    Some(slot) => {
+                        requests.slots[slot] = SlotState::Reserved;
+                        Poll::Ready(slot)
+                    }
+                    None => {
+                        // a task polled again is already waiting
+                        if !requests
+                            .waiting
+                            .iter()
+                            .any(|waker| waker.will_wake(cx.waker()))
+                        {
+                            requests.waiting.push(cx.waker().clone());
+                        }
+                        Poll::Pending
+                    }
+                }
+            })
+        })
+        .await;
+        SlotGuard { device: self, slot }
+    }
+
+    fn header_addr(&self, slot: usize) -> PhysAddr {
+        self.buffers.phys() + slot * SLOT_HEADER_SIZE
+    }
+
+    fn data_addr(&self, slot: usize) -> PhysAddr {
+        self.buffers.phys() + (1 + slot) * PAGE_SIZE
+    }
+
+    fn pointer<T>(&self, addr: PhysAddr) -> *mut T {
+        (self.buffers.virt() + (addr - self.buffers.phys())).as_mut_ptr()
+    }
+
+    /// The data page of `slot`, which only its guard's owner touches.
+    #[allow(clippy::mut_from_ref)]
+    fn data(&self, guard: &SlotGuard, len: usize) -> &mut [u8] {
+        let data = self.pointer(self.data_addr(guard.slot));
+        unsafe { core::slice::from_raw_parts_mut(data, len) }
+    }
+
+    /// Sends a request for the `len` bytes in the slot's data page and waits
+    /// for the device to complete it.
+    async fn submit(
+        &self,
+        guard: &SlotGuard<'_>,
+        kind: u32,
+        sector: u64,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        let slot = guard.slot;
+        let header = RequestHeader {
+            kind,
+            reserved: 0,
+            sector,
+        };
+        let status = self.header_addr(slot) + STATUS_OFFSET;
+        unsafe {
+            self.pointer::<RequestHeader>(self.header_addr(slot))
+                .write_volatile(header);
+            self.pointer::<u8>(status).write_volatile(!0);
+        }
+        let chain = [
+            Buffer {
+                addr: self.header_addr(slot),
+                len: HEADER_SIZE,
+                writable: false,
+            },
+            Buffer {
+                addr: self.data_addr(slot),
+                len: len as u32,
+                writable: kind == REQUEST_IN,
+            },
+            Buffer {
+                addr: status,
+                len: 1,
+                writable: true,
+            },
+        ];
+        self.with_requests(|requests| {
+            // every slot has its descriptors, so this can't run out
+            let head = requests.queue.add(&chain).expect("virtqueue full");
+            requests.slots[slot] = SlotState::InFlight(head);
+            self.transport.notify(&requests.queue);
+        });
+
+        poll_fn(|cx| {
+            self.with_requests(|requests| {
+                if requests.slots[slot] == SlotState::Done {
+                    Poll::Ready(())
+                } else {
+                    requests.wakers[slot] = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+        })
+        .await;
+        match unsafe { self.pointer::<u8>(status).read_volatile() } {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(BlockError::Unsupported),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    async fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_transfer(self, sector, buffer.len())?;
+        let chunk_size = SECTORS_PER_REQUEST * SECTOR_SIZE;
+        for (index, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+            let guard = self.reserve().await;
+            let start = sector + (index * SECTORS_PER_REQUEST) as u64;
+            self.submit(&guard, REQUEST_IN, start, chunk.len()).await?;
+            chunk.copy_from_slice(self.data(&guard, chunk.len()));
+        }
+        Ok(())
+    }
+
+    async fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_transfer(self, sector, buffer.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        let chunk_size = SECTORS_PER_REQUEST * SECTOR_SIZE;
+        for (index, chunk) in buffer.chunks(chunk_size).enumerate() {
+            let guard = self.reserve().await;
+            self.data(&guard, chunk.len()).copy_from_slice(chunk);
+            let start = sector + (index * SECTORS_PER_REQUEST) as u64;
+            self.submit(&guard, REQUEST_OUT, start, chunk.len()).await?;
+        }
+        Ok(())
+    }
+
+    /// Completes the requests the device is done with.
+    fn handle_interrupt(&self) {
+        if self.transport.acknowledge_interrupt() & ISR_QUEUE == 0 {
+            return;
+        }
+        let mut requests = self.requests.lock();
+        while let Some((head, _)) = requests.queue.pop_used() {
+            let slot = requests.slots.iter().position(|state| {
+                matches!(state, SlotState::InFlight(h) | SlotState::Abandoned(h) if *h == head)
+            });
+            match slot.map(|slot| (slot, requests.slots[slot])) {
+                Some((slot, SlotState::Abandoned(_))) => requests.free_slot(slot),
+                Some((slot, _)) => {
+                    requests.slots[slot] = SlotState::Done;
+                    if let Some(waker) = requests.wakers[slot].take() {
+                        waker.wake();
+                    }
+                }
+                None => log::warn!("{}: unknown request completed", self.name),
+            }
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlock {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read<'a>(&'a self, sector: u64, buffer: &'a mut [u8]) -> BlockFuture<'a> {
+        Box::pin(self.read_sectors(sector, buffer))
+    }
+
+    fn write<'a>(&'a self, sector: u64, buffer: &'a [u8]) -> BlockFuture<'a> {
+        Box::pin(self.write_sectors(sector, buffer))
+    }
+}
+
+/// Locked by the interrupt handler, so only with interrupts disabled.
+static DEVICES: Mutex<Vec<Arc<VirtioBlock>>> = Mutex::new(Vec::new());
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Handles the interrupts of every device, as they may share a line.
+fn on_interrupt() {
+    for device in DEVICES.lock().iter() {
+        device.handle_interrupt();
+    }
+}
+
+/// Sets up the virtio block devices `pci::init` found and registers them
+/// as `vda`, `vdb` and so on. Returns how many were set up.
+pub fn init() -> Result<usize, VirtioError> {
+    if INITIALIZED.swap(true, Ordering::SeqCst) {
+        return Err(VirtioError::AlreadyInitialized);
+    }
+    let found = pci::find(DeviceMatch::id(VENDOR_ID, DEVICE_ID_TRANSITIONAL))
+        .chain(pci::find(DeviceMatch::id(VENDOR_ID, DEVICE_ID)));
+    let mut lines = Vec::new();
+    let mut count = 0;
+    for pci_device in found {
+        let name = format!("vd{}", (b'a' + count as u8) as char);
+        let device = match VirtioBlock::new(pci_device, name) {
+            Ok(device) => Arc::new(device),
+            Err(err) => {
+                log::warn!("virtio block device {}: {:?}", pci_device.address, err);
+                continue;
+            }
+        };
+        let line = pci_device.interrupt_line;
+        if !lines.contains(&line) {
+            if let Err(err) = interrupts::add_shared_handler(line, on_interrupt) {
+                log::warn!("virtio block device {}: {:?}", pci_device.address, err);
+                // nothing else holds the device, whose queue and buffers
+                // are only freed once it stopped using them
+                let reset = device.transport.reset();
+                device.transport.fail();
+                if reset.is_err() {
+                    core::mem::forget(device);
+                }
+                continue;
+            }
+            lines.push(line);
+        }
+        without_interrupts(|| DEVICES.lock().push(device.clone()));
+        device.transport.finish();
+        block::register(device);
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// The devices `init` set up.
+pub fn devices() -> Vec<Arc<VirtioBlock>> {
+    without_interrupts(|| DEVICES.lock().clone())
+}
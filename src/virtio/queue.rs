@@ -0,0 +1,236 @@
+//! Split virtqueues: a descriptor table the driver fills, the available ring
+//! it offers descriptor chains on and the used ring the device returns them
+//! on, laid out in one physically contiguous buffer.
+
+use super::VirtioError;
+use crate::memory::DmaBuffer;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::PhysAddr;
+
+const DESCRIPTOR_SIZE: usize = 16;
+const USED_ELEMENT_SIZE: usize = 8;
+/// Legacy devices expect the used ring on the next page boundary.
+const USED_RING_ALIGN: usize = 4096;
+const PAGE_SIZE: usize = 4096;
+
+const DESC_F_NEXT: u16 = 1 << 0;
+/// The device writes the buffer instead of reading it.
+const DESC_F_WRITE: u16 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// Part of a request, read by the device or written if `writable`.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    pub writable: bool,
+}
+
+/// Where the rings of a queue start, in bytes from the descriptor table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLayout {
+    pub avail: usize,
+    pub used: usize,
+    pub pages: usize,
+}
+
+impl QueueLayout {
+    pub fn new(size: u16) -> Self {
+        let size = size as usize;
+        // flags, index, the ring and the event index
+        let avail_size = 2 + 2 + 2 * size + 2;
+        let used_size = 2 + 2 + USED_ELEMENT_SIZE * size + 2;
+        let avail = DESCRIPTOR_SIZE * size;
+        let used = align_up(avail + avail_size, USED_RING_ALIGN);
+        Self {
+            avail,
+            used,
+            pages: align_up(used + used_size, PAGE_SIZE) / PAGE_SIZE,
+        }
+    }
+}
+
+fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+#[derive(Debug)]
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    layout: QueueLayout,
+    memory: DmaBuffer,
+    /// Unused descriptors are chained through their `next` fields.
+    free_head: u16,
+    free_count: u16,
+    avail_index: u16,
+    last_used: u16,
+    /// Modern devices are notified at an offset that depends on the queue.
+    pub(super) notify_offset: u16,
+}
+
+impl Virtqueue {
+    /// Allocates a queue of `size` descriptors, a power of two.
+    pub fn new(index: u16, size: u16, notify_offset: u16) -> Result<Self, VirtioError> {
+        if !size.is_power_of_two() {
+            return Err(VirtioError::BadQueueSize(size));
+        }
+        let layout = QueueLayout::new(size);
+        let memory = DmaBuffer::allocate(layout.pages).ok_or(VirtioError::NoMemory)?;
+        let queue = Self {
+            index,
+            size,
+            layout,
+            memory,
+            free_head: 0,
+            free_count: size,
+            avail_index: 0,
+            last_used: 0,
+            notify_offset,
+        };
+        for index in 0..size {
+            let descriptor = Descriptor {
+                next: index.wrapping_add(1),
+                ..Descriptor::default()
+            };
+            unsafe { queue.descriptor(index).write_volatile(descriptor) };
+        }
+        Ok(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn descriptor_table(&self) -> PhysAddr {
+        self.memory.phys()
+    }
+
+    pub fn avail_ring(&self) -> PhysAddr {
+        self.memory.phys() + self.layout.avail
+    }
+
+    pub fn used_ring(&self) -> PhysAddr {
+        self.memory.phys() + self.layout.used
+    }
+
+    fn pointer<T>(&self, offset: usize) -> *mut T {
+        (self.memory.virt() + offset).as_mut_ptr()
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        self.pointer(index as usize * DESCRIPTOR_SIZE)
+    }
+
+    /// Chains `buffers` and offers them to the device. Returns the first
+    /// descriptor, which identifies the chain once it is used, or `None` if
+    /// too few descriptors are free.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (position, buffer) in buffers.iter().enumerate() {
+            let descriptor = self.descriptor(index);
+            let next = unsafe { descriptor.read_volatile() }.next;
+            let last = position + 1 == buffers.len();
+            let mut flags = if buffer.writable { DESC_F_WRITE } else { 0 };
+            if !last {
+                flags |= DESC_F_NEXT;
+            }
+            let filled = Descriptor {
+                addr: buffer.addr.as_u64(),
+                len: buffer.len,
+                flags,
+                next,
+            };
+            unsafe { descriptor.write_volatile(filled) };
+            index = next;
+        }
+        self.free_head = index;
+        self.free_count -= buffers.len() as u16;
+
+        let slot = (self.avail_index % self.size) as usize;
+        unsafe {
+            self.pointer::<u16>(self.layout.avail + 4 + 2 * slot)
+                .write_volatile(head)
+        };
+        // the device may only see the new index after the entry
+        fence(Ordering::SeqCst);
+        self.avail_index = self.avail_index.wrapping_add(1);
+        unsafe {
+            self.pointer::<u16>(self.layout.avail + 2)
+                .write_volatile(self.avail_index)
+        };
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Takes the next chain the device is done with and frees its
+    /// descriptors. Returns its first descriptor and the bytes written.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_index = unsafe { self.pointer::<u16>(self.layout.used + 2).read_volatile() };
+        if used_index == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let slot = (self.last_used % self.size) as usize;
+        let element = unsafe {
+            self.pointer::<UsedElement>(self.layout.used + 4 + USED_ELEMENT_SIZE * slot)
+                .read_volatile()
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = element.id as u16;
+        let mut index = head;
+        let mut count = 1;
+        loop {
+            let descriptor = unsafe { self.descriptor(index).read_volatile() };
+            if descriptor.flags & DESC_F_NEXT == 0 {
+                break;
+            }
+            index = descriptor.next;
+            count += 1;
+        }
+        let last = self.descriptor(index);
+        unsafe {
+            let mut descriptor = last.read_volatile();
+            descriptor.next = self.free_head;
+            last.write_volatile(descriptor);
+        }
+        self.free_head = head;
+        self.free_count += count;
+        Some((head, element.len))
+    }
+}
+
+#[test_case]
+fn test_queue_layout() {
+    let layout = QueueLayout::new(128);
+    assert_eq!((layout.avail, layout.used, layout.pages), (2048, 4096, 2));
+    // the available ring pushes the used ring to the third page
+    let layout = QueueLayout::new(256);
+    assert_eq!((layout.avail, layout.used, layout.pages), (4096, 8192, 3));
+    let layout = QueueLayout::new(8);
+    assert_eq!((layout.avail, layout.used, layout.pages), (128, 4096, 2));
+}
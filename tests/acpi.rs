@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os_yawqi::acpi::{self, AcpiError, RegisterSpace};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+    acpi::init().expect("ACPI initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn tables_are_found() {
+    let acpi = acpi::get().unwrap();
+    assert!(acpi.table(b"APIC").is_ok());
+    assert!(acpi.table(b"FACP").is_ok());
+    assert_eq!(
+        acpi.table(b"NONE").err(),
+        Some(AcpiError::TableNotFound(*b"NONE"))
+    );
+    assert_eq!(acpi::init().err(), Some(AcpiError::AlreadyInitialized));
+}
+
+#[test_case]
+fn madt_lists_cpus_and_io_apics() {
+    let madt = acpi::get().unwrap().madt().expect("no MADT");
+    assert!(!madt.processors.is_empty());
+    assert!(!madt.io_apics.is_empty());
+    // QEMU wires the PIT to GSI 2
+    assert_eq!(madt.isa_irq(0).gsi, 2);
+    assert_eq!(madt.isa_irq(1).gsi, 1);
+}
+
+#[test_case]
+fn fadt_describes_power_management() {
+    let acpi = acpi::get().unwrap();
+    let fadt = acpi.fadt().expect("no FADT");
+    assert_ne!(fadt.pm1a_control, 0);
+    let dsdt = acpi.dsdt().expect("no DSDT");
+    assert_eq!(&dsdt.signature(), b"DSDT");
+    assert!(acpi::fadt::parse_s5(dsdt.body()).is_some());
+}
+
+#[test_case]
+fn hpet_is_described() {
+    let hpet = acpi::get().unwrap().hpet().expect("no HPET");
+    assert_eq!(hpet.address.space, RegisterSpace::Memory);
+    assert!(hpet.comparators >= 3);
+}
@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use blog_os_yawqi::memory::{self, AddressSpace, USER_SPACE_START};
+use blog_os_yawqi::thread;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+fn user_pages(count: u64) -> x86_64::structures::paging::page::PageRange {
+    let start = Page::containing_address(VirtAddr::new(USER_SPACE_START));
+    Page::range(start, start + count)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|_, frame_allocator| frame_allocator.free_frames()).unwrap()
+}
+
+#[test_case]
+fn map_protect_unmap() {
+    let space = AddressSpace::new().expect("address space creation failed");
+    let pages = user_pages(2);
+    let page = pages.start;
+    assert_eq!(space.flags(page), None);
+
+    space
+        .map(
+            pages,
+            PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE,
+        )
+        .expect("mapping failed");
+    assert!(space
+        .flags(page)
+        .unwrap()
+        .contains(PageTableFlags::WRITABLE));
+
+    space
+        .protect(pages, PageTableFlags::USER_ACCESSIBLE)
+        .expect("protect failed");
+    assert!(!space
+        .flags(page)
+        .unwrap()
+        .contains(PageTableFlags::WRITABLE));
+
+    space.unmap(pages);
+    assert_eq!(space.flags(page), None);
+}
+
+#[test_case]
+fn drop_frees_all_frames() {
+    let before = free_frames();
+    let space = AddressSpace::new().expect("address space creation failed");
+    space
+        .map(user_pages(16), PageTableFlags::USER_ACCESSIBLE)
+        .expect("mapping failed");
+    assert!(free_frames() < before);
+    drop(space);
+    assert_eq!(free_frames(), before);
+}
+
+#[test_case]
+fn spaces_are_isolated() {
+    let read = |value: u64| {
+        let space = AddressSpace::new().expect("address space creation failed");
+        space
+            .map(user_pages(1), PageTableFlags::USER_ACCESSIBLE)
+            .expect("mapping failed");
+        assert!(space.write(VirtAddr::new(USER_SPACE_START), &value.to_le_bytes()));
+        thread::spawn_in(Arc::new(space), || unsafe {
+            core::ptr::read_volatile(USER_SPACE_START as *const u64)
+        })
+        .expect("spawn failed")
+    };
+    let first = read(1);
+    let second = read(2);
+    assert_eq!(first.join(), 1);
+    assert_eq!(second.join(), 2);
+}
+
+#[test_case]
+fn kernel_heap_is_shared() {
+    let space = Arc::new(AddressSpace::new().expect("address space creation failed"));
+    let boxed = Box::new(41u64);
+    let handle = thread::spawn_in(space, move || *boxed + 1).expect("spawn failed");
+    assert_eq!(handle.join(), 42);
+}
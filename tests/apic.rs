@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os_yawqi::{acpi, apic, time};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+    apic::init().expect("APIC initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+fn wait_until(mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..100 {
+        if done() {
+            return true;
+        }
+        x86_64::instructions::hlt();
+    }
+    done()
+}
+
+#[test_case]
+fn madt_describes_the_machine() {
+    let madt = acpi::get().and_then(|acpi| acpi.madt()).expect("no MADT");
+    assert!(madt.processors.iter().any(|p| p.enabled));
+    assert!(!madt.io_apics.is_empty());
+    let bsp = apic::local_apic_id().expect("local APIC not mapped");
+    assert!(madt.processors.iter().any(|p| p.apic_id == bsp));
+}
+
+#[test_case]
+fn legacy_timer_is_routed() {
+    assert!(apic::is_enabled());
+    let start = time::ticks();
+    assert!(wait_until(|| time::ticks() > start + 2));
+}
+
+#[test_case]
+fn local_timer_ticks() {
+    let start = apic::timer_ticks();
+    assert!(wait_until(|| apic::timer_ticks() > start + 2));
+}
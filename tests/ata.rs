@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use blog_os_yawqi::ata::{self, AtaError};
+use blog_os_yawqi::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use blog_os_yawqi::task::executor::Executor;
+use blog_os_yawqi::task::Task;
+use blog_os_yawqi::test_run_until;
+use bootloader::{entry_point, BootInfo};
+use core::cell::Cell;
+use core::panic::PanicInfo;
+use futures_util::FutureExt;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn drives_are_identified() {
+    // the boot disk and the scratch disk, but not the CD-ROM
+    assert_eq!(ata::init().unwrap(), 2);
+    assert!(matches!(ata::init(), Err(AtaError::AlreadyInitialized)));
+    let drives = ata::drives();
+    let names: Vec<_> = drives.iter().map(|drive| drive.name()).collect();
+    assert_eq!(names, ["hda", "hdd"]);
+    assert_eq!(drives[1].sector_count(), 512);
+    assert!(drives[1].identity().model().starts_with("QEMU"));
+}
+
+#[test_case]
+fn boot_sector_is_read() {
+    let done = Rc::new(Cell::new(false));
+    let mut executor = Executor::new();
+    let finished = done.clone();
+    executor.spawn(Task::new(async move {
+        let boot_disk = block::find("hda").unwrap();
+        // more than one command can transfer
+        let mut buffer = vec![0; 260 * SECTOR_SIZE];
+        boot_disk.read(0, &mut buffer).await.unwrap();
+        assert_eq!(buffer[510..512], [0x55, 0xaa]);
+        finished.set(true);
+    }));
+    test_run_until(&mut executor, || done.get());
+}
+
+#[test_case]
+fn sectors_are_written() {
+    let done = Rc::new(Cell::new(false));
+    let mut executor = Executor::new();
+    let finished = done.clone();
+    executor.spawn(Task::new(async move {
+        let scratch = block::find("hdd").unwrap();
+        // up to the last sector
+        let buffer = vec![0x5a; 3 * SECTOR_SIZE];
+        scratch.write(509, &buffer).await.unwrap();
+        finished.set(true);
+    }));
+    test_run_until(&mut executor, || done.get());
+}
+
+#[test_case]
+fn bad_transfers_are_refused() {
+    let scratch = block::find("hdd").unwrap();
+    let buffer = [0; SECTOR_SIZE];
+    assert_eq!(
+        scratch.write(510, &[0; 2 * SECTOR_SIZE + 1]).now_or_never(),
+        Some(Err(BlockError::BadBufferSize(2 * SECTOR_SIZE + 1)))
+    );
+    assert_eq!(
+        scratch.write(512, &buffer).now_or_never(),
+        Some(Err(BlockError::OutOfRange(512)))
+    );
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use blog_os_yawqi::{dmesg, println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn pushed_lines_are_read_back_in_order() {
+    let first = dmesg::push("first");
+    let second = dmesg::push_fmt(format_args!("second {}", 2));
+    assert_eq!(second, first + 1);
+    assert_eq!(dmesg::read(first).unwrap().text(), "first");
+    let record = dmesg::read(second).unwrap();
+    assert_eq!(record.text(), "second 2");
+    assert!(record.timestamp >= dmesg::read(first).unwrap().timestamp);
+}
+
+#[test_case]
+fn printed_lines_are_recorded() {
+    let start = dmesg::next_seq();
+    println!("hello from the screen");
+    let mut records = dmesg::since(start);
+    assert_eq!(records.next().unwrap().text(), "hello from the screen");
+    assert!(records.next().is_none());
+}
+
+#[test_case]
+fn long_lines_are_cut_off() {
+    let seq = dmesg::push_fmt(format_args!("{:y<1000}", ""));
+    assert_eq!(dmesg::read(seq).unwrap().text().len(), dmesg::LINE_LEN);
+}
+
+#[test_case]
+fn old_records_are_overwritten() {
+    let first = dmesg::push("overwritten");
+    for i in 0..dmesg::CAPACITY {
+        dmesg::push_fmt(format_args!("filler {}", i));
+    }
+    assert!(dmesg::read(first).is_none());
+    assert_eq!(dmesg::records().count(), dmesg::CAPACITY);
+    assert_eq!(dmesg::records().next().unwrap().seq, first + 1);
+}
+
+#[test_case]
+fn dump_formats_every_record() {
+    let seq = dmesg::push("dumped");
+    let mut out = String::new();
+    dmesg::dump(&mut out).unwrap();
+    assert_eq!(out.lines().count(), dmesg::records().count());
+    let last = out.lines().last().unwrap();
+    assert!(last.starts_with('[') && last.ends_with("] dumped"));
+    assert_eq!(dmesg::next_seq(), seq + 1);
+}
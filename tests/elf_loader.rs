@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os_yawqi::elf::{self, programs, ElfError, LoadError};
+use blog_os_yawqi::thread;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn hello_exits_cleanly() {
+    let handle = elf::spawn(programs::HELLO).expect("loading hello failed");
+    assert_eq!(handle.join(), 0);
+}
+
+#[test_case]
+fn counter_uses_zeroed_bss() {
+    let handle = elf::spawn(programs::COUNTER).expect("loading counter failed");
+    assert_eq!(handle.join(), 131328);
+}
+
+#[test_case]
+fn programs_run_side_by_side() {
+    let first = elf::spawn(programs::COUNTER).expect("loading counter failed");
+    let second = elf::spawn(programs::COUNTER).expect("loading counter failed");
+    assert_eq!(first.join(), 131328);
+    assert_eq!(second.join(), 131328);
+}
+
+fn load_patched(patch: impl FnOnce(&mut [u8])) -> Result<(), LoadError> {
+    let mut image: Vec<u8> = programs::HELLO.to_vec();
+    patch(&mut image);
+    elf::load(&image).map(|_| ())
+}
+
+#[test_case]
+fn truncated_image_is_rejected() {
+    let result = elf::load(&programs::HELLO[..32]);
+    assert!(matches!(result, Err(LoadError::Elf(ElfError::TooShort))));
+}
+
+#[test_case]
+fn bad_magic_is_rejected() {
+    let result = load_patched(|image| image[0] = 0);
+    assert!(matches!(result, Err(LoadError::Elf(ElfError::BadMagic))));
+}
+
+#[test_case]
+fn elf32_is_rejected() {
+    let result = load_patched(|image| image[4] = 1);
+    assert!(matches!(result, Err(LoadError::Elf(ElfError::NotElf64))));
+}
+
+#[test_case]
+fn foreign_machine_is_rejected() {
+    let result = load_patched(|image| image[18] = 0xb7);
+    assert!(matches!(
+        result,
+        Err(LoadError::Elf(ElfError::WrongMachine))
+    ));
+}
+
+#[test_case]
+fn program_headers_out_of_bounds_are_rejected() {
+    let result = load_patched(|image| image[32..40].copy_from_slice(&u64::MAX.to_le_bytes()));
+    assert!(matches!(
+        result,
+        Err(LoadError::Elf(ElfError::ProgramHeadersOutOfBounds))
+    ));
+}
+
+#[test_case]
+fn entry_outside_code_is_rejected() {
+    let result = load_patched(|image| image[24..32].copy_from_slice(&0u64.to_le_bytes()));
+    assert!(matches!(result, Err(LoadError::EntryNotExecutable)));
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os_yawqi::memory::BitmapFrameAllocator;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB};
+use x86_64::VirtAddr;
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn allocate_and_free() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+
+    let frame: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    assert_eq!(allocator.free_frames(), free - 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free);
+
+    let again: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    assert_eq!(again, frame);
+    unsafe { allocator.deallocate_frame(again) };
+}
+
+#[test_case]
+fn frames_are_distinct() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let first: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    let second: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    assert_ne!(first, second);
+    unsafe {
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(second);
+    }
+}
+
+#[test_case]
+fn contiguous_allocation() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+
+    let frame = allocator.allocate_contiguous(16, 4).unwrap();
+    assert_eq!(frame.start_address().as_u64() % (4 * 4096), 0);
+    assert_eq!(allocator.free_frames(), free - 16);
+    unsafe { allocator.deallocate_contiguous(frame, 16) };
+    assert_eq!(allocator.free_frames(), free);
+}
+
+#[test_case]
+fn huge_frame_allocation() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let stats = allocator.stats();
+
+    let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+    assert_eq!(frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+    assert_eq!(allocator.stats().used, stats.used + 512);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.stats(), stats);
+}
@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os_yawqi::framebuffer::{self, Framebuffer, FramebufferError, Rgb};
+use blog_os_yawqi::vga_buffer::{self, Color};
+use blog_os_yawqi::{pci, print, println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+    pci::init().expect("PCI enumeration failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+const RED: Rgb = Rgb::new(0xff, 0, 0);
+const BLUE: Rgb = Rgb::new(0, 0, 0xff);
+
+fn screen() -> Framebuffer {
+    framebuffer::get().expect("framebuffer not initialized")
+}
+
+#[test_case]
+fn mode_is_set() {
+    let framebuffer = framebuffer::init(framebuffer::DEFAULT_WIDTH, framebuffer::DEFAULT_HEIGHT)
+        .expect("setting the graphics mode failed");
+    assert_eq!(framebuffer.width(), framebuffer::DEFAULT_WIDTH);
+    assert_eq!(framebuffer.height(), framebuffer::DEFAULT_HEIGHT);
+    assert!(matches!(
+        framebuffer::init(800, 600),
+        Err(FramebufferError::AlreadyInitialized)
+    ));
+}
+
+#[test_case]
+fn rectangles_are_clipped() {
+    let framebuffer = screen();
+    let (width, height) = (framebuffer.width(), framebuffer.height());
+    framebuffer.fill_rect(width - 2, height - 2, 10, 10, RED);
+    assert_eq!(framebuffer.pixel(width - 1, height - 1), Some(RED));
+    assert_eq!(
+        framebuffer.pixel(width - 3, height - 1),
+        Some(Rgb::new(0, 0, 0))
+    );
+    assert_eq!(framebuffer.pixel(width, height - 1), None);
+}
+
+#[test_case]
+fn lines_and_images_are_drawn() {
+    let framebuffer = screen();
+    let bottom = framebuffer.height() - 20;
+    framebuffer.draw_line((10, bottom), (20, bottom + 10), BLUE);
+    for i in 0..=10 {
+        assert_eq!(framebuffer.pixel(10 + i, bottom + i), Some(BLUE));
+    }
+    framebuffer.blit(100, bottom, 2, &[RED, BLUE, BLUE, RED]);
+    assert_eq!(framebuffer.pixel(100, bottom), Some(RED));
+    assert_eq!(framebuffer.pixel(101, bottom), Some(BLUE));
+    assert_eq!(framebuffer.pixel(101, bottom + 1), Some(RED));
+}
+
+#[test_case]
+fn console_is_drawn_on_the_framebuffer() {
+    let framebuffer = screen();
+    vga_buffer::use_framebuffer(framebuffer);
+    println!("drawn in graphics mode");
+    print!("\x1b[H\x1b[44m█ \x1b[0m");
+    assert_eq!(framebuffer.pixel(3, 3), Some(Color::Yellow.into()));
+    let (x, y) = Framebuffer::cell_position(1, 0);
+    assert_eq!(framebuffer.pixel(x + 3, y + 3), Some(Color::Blue.into()));
+}
@@ -0,0 +1,57 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os_yawqi::memory::stack::{self, KernelStack, StackError, StackKind};
+use blog_os_yawqi::thread;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os_yawqi::gdt;
+
+    blog_os_yawqi::test_init(boot_info);
+    gdt::install_guarded_stacks().expect("interrupt stack allocation failed");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn guard_area_is_recognised() {
+    let stack = KernelStack::allocate(4, StackKind::Thread(1000)).expect("allocation failed");
+    unsafe { (stack.top() - 8u64).as_mut_ptr::<u64>().write_volatile(1) };
+    assert_eq!(stack::guard_hit(stack.bottom() - 8u64), Some(stack.info()));
+    assert_eq!(stack::guard_hit(stack.bottom()), None);
+}
+
+#[test_case]
+fn slots_are_reused() {
+    for _ in 0..stack::MAX_KERNEL_STACKS + 16 {
+        KernelStack::allocate(1, StackKind::Thread(1000)).expect("allocation failed");
+    }
+}
+
+#[test_case]
+fn oversized_stack_is_rejected() {
+    let result = KernelStack::allocate(stack::MAX_STACK_PAGES + 1, StackKind::Boot);
+    assert!(matches!(result, Err(StackError::TooLarge)));
+}
+
+#[test_case]
+fn freed_stack_is_forgotten() {
+    let stack = KernelStack::allocate(2, StackKind::Thread(1000)).expect("allocation failed");
+    let guard = stack.bottom() - 8u64;
+    drop(stack);
+    assert_eq!(stack::guard_hit(guard), None);
+}
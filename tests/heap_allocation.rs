@@ -12,15 +12,7 @@ use core::panic::PanicInfo;
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use blog_os_yawqi::allocator;
-    use blog_os_yawqi::memory::{self, BootInfoFrameAllocator};
-    use x86_64::VirtAddr;
-
-    blog_os_yawqi::init();
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::new(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    blog_os_yawqi::test_init(boot_info);
 
     test_main();
     loop {}
@@ -59,3 +51,31 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn heap_grows_beyond_initial_size() {
+    let n = HEAP_SIZE;
+    let mut vec: Vec<u64> = Vec::with_capacity(n);
+    for i in 0..n {
+        vec.push(i as u64);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n as u64 - 1) * n as u64 / 2);
+}
+
+#[test_case]
+fn allocation_beyond_limit_fails() {
+    use alloc::alloc::{alloc, Layout};
+    use blog_os_yawqi::allocator::heap_limit;
+    let layout = Layout::from_size_align(heap_limit() * 2, 4096).unwrap();
+    assert!(unsafe { alloc(layout) }.is_null());
+}
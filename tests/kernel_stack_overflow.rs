@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+
+use blog_os_yawqi::{exit_qemu, hlt_loop, serial_print, serial_println, thread, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os_yawqi::gdt;
+
+    serial_print!("kernel_stack_overflow::thread_overflow_is_reported...\t");
+    blog_os_yawqi::test_init(boot_info);
+    gdt::install_guarded_stacks().expect("interrupt stack allocation failed");
+    thread::init();
+
+    thread::spawn(stack_overflow).expect("spawn failed").join();
+    panic!("Execution after stack overflow!");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read();
+}
+
+/// Keeps the start of the panic message.
+struct Message {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message {
+        buf: [0; 256],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    let text = core::str::from_utf8(&message.buf[..message.len]).unwrap_or("");
+    if text.contains("STACK OVERFLOW") && text.contains("stack of thread") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os_yawqi::task::keyboard::{self, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use futures_util::{FutureExt, StreamExt};
+use pc_keyboard::{DecodedKey, KeyCode, KeyEvent, KeyState};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+/// Scancode set 1 press and release of the A key.
+const A_DOWN: u8 = 0x1e;
+const A_UP: u8 = 0x9e;
+const Q_DOWN: u8 = 0x10;
+
+#[test_case]
+fn every_subscriber_gets_the_input() {
+    let mut events = keyboard::subscribe_events();
+    let mut first = keyboard::subscribe_keys();
+    let mut second = keyboard::subscribe_keys();
+    keyboard::handle_scancode(A_DOWN);
+    keyboard::handle_scancode(A_UP);
+
+    let a = DecodedKey::Unicode('a');
+    assert_eq!(first.next().now_or_never(), Some(Some(a)));
+    assert_eq!(second.next().now_or_never(), Some(Some(a)));
+    // releases only show up as events
+    assert_eq!(first.next().now_or_never(), None);
+    assert_eq!(
+        events.next().now_or_never(),
+        Some(Some(KeyEvent::new(KeyCode::A, KeyState::Down)))
+    );
+    assert_eq!(
+        events.next().now_or_never(),
+        Some(Some(KeyEvent::new(KeyCode::A, KeyState::Up)))
+    );
+}
+
+#[test_case]
+fn dropped_subscribers_are_skipped() {
+    let dropped = keyboard::subscribe_keys();
+    drop(dropped);
+    let mut keys = keyboard::subscribe_keys();
+    keyboard::handle_scancode(A_DOWN);
+    keyboard::handle_scancode(A_UP);
+    assert_eq!(
+        keys.next().now_or_never(),
+        Some(Some(DecodedKey::Unicode('a')))
+    );
+}
+
+#[test_case]
+fn layouts_are_switched_at_runtime() {
+    let mut keys = keyboard::subscribe_keys();
+    keyboard::set_layout(Layout::Azerty);
+    assert_eq!(keyboard::layout(), Layout::Azerty);
+    keyboard::handle_scancode(Q_DOWN);
+    keyboard::set_layout(Layout::Us);
+    keyboard::handle_scancode(Q_DOWN);
+    assert_eq!(
+        keys.next().now_or_never(),
+        Some(Some(DecodedKey::Unicode('a')))
+    );
+    assert_eq!(
+        keys.next().now_or_never(),
+        Some(Some(DecodedKey::Unicode('q')))
+    );
+}
@@ -0,0 +1,87 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os_yawqi::dmesg;
+use blog_os_yawqi::logger::{self, LoggerError};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use log::LevelFilter;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+    // keep the test output on the serial port readable
+    logger::set_sink_level(&logger::SERIAL, LevelFilter::Off);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn lines_reach_the_memory_sink() {
+    logger::MEMORY.clear();
+    log::info!("answer is {}", 42);
+    let contents = logger::MEMORY.contents();
+    assert!(contents.ends_with("INFO  logging: answer is 42\n"));
+    assert!(contents.starts_with('['));
+}
+
+#[test_case]
+fn lines_reach_the_message_buffer() {
+    let start = dmesg::next_seq();
+    log::info!("answer is {}", 42);
+    let mut records = dmesg::since(start);
+    let record = records.next().expect("nothing was recorded");
+    assert_eq!(record.text(), "INFO  logging: answer is 42");
+    assert!(records.next().is_none());
+}
+
+#[test_case]
+fn levels_are_filtered() {
+    logger::MEMORY.clear();
+    log::debug!("hidden");
+    logger::set_level(LevelFilter::Debug);
+    log::debug!("shown");
+    logger::set_level(LevelFilter::Info);
+    let contents = logger::MEMORY.contents();
+    assert!(!contents.contains("hidden"));
+    assert!(contents.contains("shown"));
+}
+
+#[test_case]
+fn module_levels_override_the_default() {
+    logger::MEMORY.clear();
+    logger::set_module_level("logging::quiet", LevelFilter::Error);
+    logger::set_module_level("logging::loud", LevelFilter::Trace);
+    log::warn!(target: "logging::quiet::inner", "dropped");
+    log::trace!(target: "logging::loud", "kept");
+    log::trace!(target: "logging::loudness", "not a submodule");
+    log::info!(target: "logging", "default");
+    let contents = logger::MEMORY.contents();
+    assert!(!contents.contains("dropped"));
+    assert!(contents.contains("kept"));
+    assert!(!contents.contains("not a submodule"));
+    assert!(contents.contains("default"));
+}
+
+#[test_case]
+fn long_messages_are_truncated() {
+    logger::MEMORY.clear();
+    log::error!("{:x<1000}", "");
+    let contents = logger::MEMORY.contents();
+    assert!(contents.len() < logger::MAX_LINE + 64);
+}
+
+#[test_case]
+fn logger_is_installed_once() {
+    assert_eq!(logger::init(), Err(LoggerError::AlreadyInitialized));
+}
@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use blog_os_yawqi::elf::{self, programs};
+use blog_os_yawqi::memory::fault::{self, FaultError};
+use blog_os_yawqi::memory::{self, AddressSpace, Region, RegionKind};
+use blog_os_yawqi::thread;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+fn kernel_region(start: u64, flags: PageTableFlags) -> Region {
+    let region = Region::new(VirtAddr::new(start), 2 * 4096, flags, RegionKind::Anonymous);
+    memory::add_kernel_region(region).expect("region registration failed");
+    region
+}
+
+#[test_case]
+fn kernel_region_is_mapped_on_demand() {
+    let region = kernel_region(0x5555_0000_0000, PageTableFlags::WRITABLE);
+    let ptr = region.start.as_mut_ptr::<u64>();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.add(512).write_volatile(42);
+        assert_eq!(ptr.add(512).read_volatile(), 42);
+    }
+}
+
+#[test_case]
+fn write_to_read_only_region_is_denied() {
+    let region = kernel_region(0x5555_1000_0000, PageTableFlags::empty());
+    let result = fault::resolve(region.start, PageFaultErrorCode::CAUSED_BY_WRITE);
+    assert_eq!(result, Err(FaultError::AccessDenied(region)));
+}
+
+#[test_case]
+fn address_outside_regions_is_not_resolved() {
+    let result = fault::resolve(VirtAddr::new(0x6666_0000_0000), PageFaultErrorCode::empty());
+    assert_eq!(result, Err(FaultError::NoRegion));
+}
+
+#[test_case]
+fn reserved_user_memory_is_mapped_on_demand() {
+    let space = AddressSpace::new().expect("address space creation failed");
+    let flags = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+    let addr = space.reserve(3 * 4096, flags).expect("reserve failed");
+    let handle = thread::spawn_in(Arc::new(space), move || {
+        let ptr = addr.as_mut_ptr::<u64>();
+        unsafe {
+            ptr.add(1024).write_volatile(7);
+            ptr.read_volatile() + ptr.add(1024).read_volatile()
+        }
+    })
+    .expect("spawn failed");
+    assert_eq!(handle.join(), 7);
+}
+
+#[test_case]
+fn program_uses_lazy_stack_and_mmap() {
+    let handle = elf::spawn(programs::LAZY).expect("loading lazy failed");
+    assert_eq!(handle.join(), 12);
+}
@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os_yawqi::pci::{self, Bar, DeviceMatch, PciAddress, PciError, CLASS_BRIDGE};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+    blog_os_yawqi::acpi::init().expect("ACPI initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+/// QEMU's standard VGA adapter.
+const VGA: DeviceMatch = DeviceMatch::id(0x1234, 0x1111);
+
+#[test_case]
+fn devices_are_found() {
+    assert!(pci::devices().is_empty());
+    let devices = pci::init().expect("PCI enumeration failed");
+    assert!(!devices.is_empty());
+    assert_eq!(pci::init().unwrap_err(), PciError::AlreadyInitialized);
+
+    let host = &devices[0];
+    assert_eq!(host.address, PciAddress::new(0, 0, 0));
+    assert_eq!((host.class, host.subclass), (CLASS_BRIDGE, 0));
+    assert_eq!(pci::find(DeviceMatch::default()).count(), devices.len());
+}
+
+#[test_case]
+fn bars_are_decoded() {
+    let vga = pci::find(VGA).next().expect("no VGA adapter");
+    match vga.bars[0] {
+        Some(Bar::Memory { address, size, .. }) => {
+            assert!(address.as_u64() != 0);
+            assert!(size.is_power_of_two() && size >= 0x100_0000);
+        }
+        other => panic!("unexpected BAR0 {:?}", other),
+    }
+    assert_eq!(vga.read_config(0), 0x1111_1234);
+}
+
+#[test_case]
+fn devices_match_by_class() {
+    let display = DeviceMatch::class(0x03, 0x00);
+    assert!(pci::find(display).any(|device| VGA.matches(device)));
+    let vga = pci::find(VGA).next().unwrap();
+    assert_eq!(vga.class_name(), "display controller");
+}
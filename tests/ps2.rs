@@ -0,0 +1,50 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os_yawqi::ps2::mouse::{MouseError, MouseStream};
+use blog_os_yawqi::ps2::{self, Ps2Error};
+use blog_os_yawqi::task::keyboard::{self, ScancodeSet};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn keyboard_and_mouse_are_found() {
+    assert_eq!(ps2::ports(), None);
+    let ports = ps2::init().expect("PS/2 initialization failed");
+    assert!(ports.first && ports.second);
+    assert_eq!(ps2::ports(), Some(ports));
+    assert_eq!(ps2::init(), Err(Ps2Error::AlreadyInitialized));
+}
+
+#[test_case]
+fn mouse_stream_opens_once() {
+    MouseStream::new().expect("opening the mouse stream failed");
+    assert!(matches!(MouseStream::new(), Err(MouseError::AlreadyOpen)));
+}
+
+#[test_case]
+fn translation_can_be_turned_off() {
+    keyboard::set_scancode_set(ScancodeSet::Set2).unwrap();
+    assert_eq!(keyboard::scancode_set(), ScancodeSet::Set2);
+    keyboard::set_scancode_set(ScancodeSet::Set1).unwrap();
+    assert_eq!(keyboard::scancode_set(), ScancodeSet::Set1);
+}
@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os_yawqi::rtc::{self, RtcError};
+use blog_os_yawqi::time;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn hardware_clock_reads_a_plausible_date() {
+    let now = rtc::read();
+    assert!(now.year >= 2020);
+    assert!((1..=12).contains(&now.month));
+    assert!((1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
+
+#[test_case]
+fn wall_clock_follows_the_hardware_clock() {
+    assert_eq!(rtc::now(), None);
+    let boot = rtc::init();
+    let now = rtc::unix_time().unwrap();
+    assert!(now >= boot.to_unix() && now <= boot.to_unix() + 1);
+}
+
+#[test_case]
+fn periodic_interrupts_fire() {
+    assert!(matches!(
+        rtc::enable_periodic(1000),
+        Err(RtcError::InvalidFrequency(1000))
+    ));
+    rtc::enable_periodic(1024).expect("enabling the periodic interrupt failed");
+    let start = rtc::periodic_ticks();
+    let deadline = time::ticks() + 100;
+    while rtc::periodic_ticks() < start + 10 && time::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+    rtc::disable_periodic();
+    assert!(rtc::periodic_ticks() >= start + 10);
+}
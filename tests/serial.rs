@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use blog_os_yawqi::serial::{self, SerialError, SerialStream, SERIAL1};
+use blog_os_yawqi::task::executor::Executor;
+use blog_os_yawqi::task::Task;
+use blog_os_yawqi::time;
+use bootloader::{entry_point, BootInfo};
+use core::cell::RefCell;
+use core::panic::PanicInfo;
+use futures_util::StreamExt;
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn looped_back_bytes_are_received() {
+    let mut stream = SerialStream::new().expect("opening the serial stream failed");
+    assert!(matches!(SerialStream::new(), Err(SerialError::AlreadyOpen)));
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    let bytes = received.clone();
+    executor.spawn(Task::new(async move {
+        while let Some(byte) = stream.next().await {
+            bytes.borrow_mut().push(byte);
+            if byte == b'\n' {
+                break;
+            }
+        }
+    }));
+
+    // nothing may be printed while the port talks to itself
+    serial::set_loopback(true);
+    interrupts::without_interrupts(|| {
+        let mut port = SERIAL1.lock();
+        for byte in b"ping\n" {
+            port.send(*byte);
+        }
+    });
+    let deadline = time::ticks() + 500;
+    while received.borrow().last() != Some(&b'\n') && time::ticks() < deadline {
+        executor.run_ready_tasks();
+        x86_64::instructions::hlt();
+    }
+    serial::set_loopback(false);
+    assert_eq!(received.borrow().as_slice(), b"ping\n");
+}
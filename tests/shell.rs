@@ -0,0 +1,158 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::String;
+use blog_os_yawqi::memory;
+use blog_os_yawqi::shell::{self, Command, LineEditor, ShellError};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use pc_keyboard::{DecodedKey, KeyCode};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+static ARGS_SEEN: AtomicUsize = AtomicUsize::new(0);
+
+fn count_args(args: &[&str]) {
+    ARGS_SEEN.store(args.len(), Ordering::SeqCst);
+}
+
+#[test_case]
+fn registered_commands_are_executed() {
+    let command = Command {
+        name: "count",
+        usage: "[args...]",
+        help: "count the arguments",
+        run: count_args,
+    };
+    shell::register(command).unwrap();
+    assert!(shell::commands().iter().any(|c| c.name == "count"));
+    shell::execute("  count a  b c ").unwrap();
+    assert_eq!(ARGS_SEEN.load(Ordering::SeqCst), 3);
+    assert_eq!(
+        shell::register(command).unwrap_err(),
+        ShellError::AlreadyRegistered("count")
+    );
+}
+
+#[test_case]
+fn builtins_cannot_be_replaced() {
+    let command = Command {
+        name: "help",
+        usage: "",
+        help: "",
+        run: count_args,
+    };
+    assert_eq!(
+        shell::register(command).unwrap_err(),
+        ShellError::AlreadyRegistered("help")
+    );
+}
+
+#[test_case]
+fn unknown_commands_are_reported() {
+    assert_eq!(
+        shell::execute("frobnicate now"),
+        Err(ShellError::UnknownCommand(String::from("frobnicate")))
+    );
+    assert_eq!(shell::execute("   "), Ok(()));
+}
+
+#[test_case]
+fn builtins_run() {
+    for line in &[
+        "echo hi",
+        "mem",
+        "ps",
+        "uptime",
+        "walk 0xb8000",
+        "dmesg 2",
+        "layout",
+        "lspci -v",
+    ] {
+        shell::execute(line).unwrap();
+    }
+}
+
+#[test_case]
+fn walk_reaches_mapped_pages() {
+    let value = 0u64;
+    let entries = memory::walk(VirtAddr::from_ptr(&value));
+    let last = entries.iter().flatten().last().unwrap();
+    assert!(last.flags().contains(PageTableFlags::PRESENT));
+    assert!(entries[0].is_some());
+}
+
+#[test_case]
+fn frame_stats_add_up() {
+    let stats = memory::frame_stats().unwrap();
+    assert_eq!(stats.used + stats.free, stats.total);
+    assert!(stats.used > 0);
+}
+
+fn type_str(editor: &mut LineEditor, text: &str) {
+    for ch in text.chars() {
+        editor.handle(DecodedKey::Unicode(ch));
+    }
+}
+
+#[test_case]
+fn editing_in_the_middle() {
+    let mut editor = LineEditor::new(16);
+    type_str(&mut editor, "helo");
+    editor.handle(DecodedKey::RawKey(KeyCode::ArrowLeft));
+    type_str(&mut editor, "l");
+    assert_eq!(editor.line(), "hello");
+    assert_eq!(editor.cursor(), 4);
+    editor.handle(DecodedKey::RawKey(KeyCode::Home));
+    editor.handle(DecodedKey::RawKey(KeyCode::Delete));
+    editor.handle(DecodedKey::RawKey(KeyCode::End));
+    editor.handle(DecodedKey::Unicode('\u{8}'));
+    assert_eq!(editor.line(), "ell");
+    assert_eq!(
+        editor.handle(DecodedKey::Unicode('\n')).as_deref(),
+        Some("ell")
+    );
+    assert_eq!(editor.line(), "");
+}
+
+#[test_case]
+fn history_browsing() {
+    let mut editor = LineEditor::new(16);
+    type_str(&mut editor, "first\nsecond\nsecond\ndraft");
+    assert_eq!(editor.history().count(), 2);
+    editor.handle(DecodedKey::RawKey(KeyCode::ArrowUp));
+    assert_eq!(editor.line(), "second");
+    editor.handle(DecodedKey::RawKey(KeyCode::ArrowUp));
+    editor.handle(DecodedKey::RawKey(KeyCode::ArrowUp));
+    assert_eq!(editor.line(), "first");
+    editor.handle(DecodedKey::RawKey(KeyCode::ArrowDown));
+    editor.handle(DecodedKey::RawKey(KeyCode::ArrowDown));
+    assert_eq!(editor.line(), "draft");
+}
+
+#[test_case]
+fn line_length_is_limited() {
+    let mut editor = LineEditor::new(3);
+    type_str(&mut editor, "abcd");
+    assert_eq!(editor.line(), "abc");
+}
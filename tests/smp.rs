@@ -0,0 +1,153 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use blog_os_yawqi::{apic, percpu, smp};
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+
+entry_point!(main);
+
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use blog_os_yawqi::gdt;
+
+    blog_os_yawqi::test_init(boot_info);
+    gdt::install_guarded_stacks().expect("interrupt stack allocation failed");
+    apic::init().expect("APIC initialization failed");
+    STARTED.store(
+        smp::start_aps().expect("AP startup failed"),
+        Ordering::SeqCst,
+    );
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+fn wait_until(mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..200 {
+        if done() {
+            return true;
+        }
+        apic::busy_wait(1000);
+    }
+    done()
+}
+
+#[test_case]
+fn all_processors_start() {
+    // the test runner starts QEMU with -smp 4
+    assert_eq!(STARTED.load(Ordering::SeqCst), 3);
+    assert_eq!(smp::online_cpus(), 4);
+    assert!(percpu::current().is_bsp());
+}
+
+#[test_case]
+fn cpus_have_their_own_data() {
+    let mut ids = [None; percpu::MAX_CPUS];
+    for cpu in percpu::cpus() {
+        assert!(ids.iter().flatten().all(|id| *id != cpu.apic_id()));
+        ids[cpu.index] = Some(cpu.apic_id());
+    }
+}
+
+#[test_case]
+fn tasks_run_on_the_requested_cpu() {
+    static SEEN: AtomicU64 = AtomicU64::new(0);
+    for index in 1..smp::online_cpus() {
+        smp::spawn_on(index, async {
+            SEEN.fetch_or(1 << percpu::current().index, Ordering::SeqCst);
+        })
+        .expect("spawn failed");
+    }
+    assert!(wait_until(|| SEEN.load(Ordering::SeqCst) == 0b1110));
+}
+
+#[test_case]
+fn application_processors_take_timer_interrupts() {
+    static TICKS: AtomicU64 = AtomicU64::new(0);
+    smp::spawn_on(1, async {
+        TICKS.store(apic::timer_ticks(), Ordering::SeqCst);
+    })
+    .expect("spawn failed");
+    assert!(wait_until(|| TICKS.load(Ordering::SeqCst) > 0));
+}
+
+static SIGNAL: AtomicBool = AtomicBool::new(false);
+static SIGNAL_WAKER: AtomicWaker = AtomicWaker::new();
+
+struct Signal;
+
+impl Future for Signal {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        SIGNAL_WAKER.register(cx.waker());
+        if SIGNAL.load(Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[test_case]
+fn wakeups_cross_cpus() {
+    static DONE: AtomicBool = AtomicBool::new(false);
+    static WAITING: AtomicBool = AtomicBool::new(false);
+    smp::spawn_on(2, async {
+        WAITING.store(true, Ordering::SeqCst);
+        Signal.await;
+        DONE.store(true, Ordering::SeqCst);
+    })
+    .expect("spawn failed");
+    assert!(wait_until(|| WAITING.load(Ordering::SeqCst)));
+    apic::busy_wait(10_000);
+    assert!(!DONE.load(Ordering::SeqCst));
+
+    SIGNAL.store(true, Ordering::SeqCst);
+    SIGNAL_WAKER.wake();
+    assert!(wait_until(|| DONE.load(Ordering::SeqCst)));
+}
+
+/// Fills fresh heap blocks, so that the heap grows and its new pages are
+/// mapped on first touch.
+fn touch_new_heap_pages() {
+    let blocks: Vec<Vec<u8>> = (0..16).map(|i| vec![i as u8; 64 * 1024]).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        assert!(block.iter().all(|byte| *byte == i as u8));
+    }
+}
+
+#[test_case]
+fn heap_grows_on_several_cpus_at_once() {
+    static FINISHED: AtomicUsize = AtomicUsize::new(0);
+    for index in 1..smp::online_cpus() {
+        smp::spawn_on(index, async {
+            touch_new_heap_pages();
+            FINISHED.fetch_add(1, Ordering::SeqCst);
+        })
+        .expect("spawn failed");
+    }
+    touch_new_heap_pages();
+    assert!(wait_until(
+        || FINISHED.load(Ordering::SeqCst) == smp::online_cpus() - 1
+    ));
+}
@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use blog_os_yawqi::{thread, time};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn spawn_and_join() {
+    let handle = thread::spawn(|| 6 * 7).expect("spawn failed");
+    assert_eq!(handle.join(), 42);
+}
+
+#[test_case]
+fn threads_run_concurrently() {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let handles: alloc::vec::Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(|| {
+                for _ in 0..100 {
+                    COUNTER.fetch_add(1, Ordering::SeqCst);
+                    thread::yield_now();
+                }
+            })
+            .expect("spawn failed")
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 400);
+}
+
+#[test_case]
+fn cpu_bound_thread_is_preempted() {
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let spinner = thread::spawn(|| {
+        while !STOP.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    })
+    .expect("spawn failed");
+    thread::yield_now();
+    STOP.store(true, Ordering::SeqCst);
+    spinner.join();
+}
+
+#[test_case]
+fn sleep_waits_for_duration() {
+    let start = time::uptime();
+    thread::sleep(Duration::from_millis(3));
+    assert!(time::uptime() - start >= Duration::from_millis(3));
+}
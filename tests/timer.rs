@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use blog_os_yawqi::task::executor::Executor;
+use blog_os_yawqi::task::timer::{self, Interval};
+use blog_os_yawqi::task::Task;
+use blog_os_yawqi::test_run_until;
+use blog_os_yawqi::time;
+use bootloader::{entry_point, BootInfo};
+use core::cell::Cell;
+use core::panic::PanicInfo;
+use core::time::Duration;
+use futures_util::StreamExt;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn uptime_advances() {
+    let start = time::ticks();
+    while time::ticks() < start + 5 {
+        x86_64::instructions::hlt();
+    }
+    assert!(time::uptime() > time::ticks_to_duration(start));
+}
+
+#[test_case]
+fn sleep_completes_after_its_duration() {
+    let done = Rc::new(Cell::new(None));
+    let mut executor = Executor::new();
+    let start = time::uptime();
+    let result = done.clone();
+    executor.spawn(Task::new(async move {
+        timer::sleep(Duration::from_millis(20)).await;
+        result.set(Some(time::uptime()));
+    }));
+    test_run_until(&mut executor, || done.get().is_some());
+    assert!(done.get().unwrap() - start >= Duration::from_millis(20));
+}
+
+#[test_case]
+fn sleeps_finish_in_deadline_order() {
+    let order = Rc::new(Cell::new(0u32));
+    let mut executor = Executor::new();
+    for (position, millis) in IntoIterator::into_iter([(2, 30), (0, 5), (1, 15)]) {
+        let order = order.clone();
+        executor.spawn(Task::new(async move {
+            timer::sleep(Duration::from_millis(millis)).await;
+            assert_eq!(order.get(), position);
+            order.set(position + 1);
+        }));
+    }
+    test_run_until(&mut executor, || order.get() == 3);
+}
+
+#[test_case]
+fn dropped_sleep_is_cancelled() {
+    let sleep = timer::sleep(Duration::from_millis(1));
+    drop(sleep);
+    let deadline = time::ticks() + 5;
+    while time::ticks() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn interval_ticks_periodically() {
+    let count = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    let start = time::uptime();
+    let ticks = count.clone();
+    executor.spawn(Task::new(async move {
+        let mut interval = Interval::new(Duration::from_millis(10));
+        interval.tick().await;
+        ticks.set(1);
+        for n in 2..=3 {
+            interval.next().await;
+            ticks.set(n);
+        }
+    }));
+    test_run_until(&mut executor, || count.get() == 3);
+    assert!(time::uptime() - start >= Duration::from_millis(25));
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os_yawqi::syscall::SyscallError;
+use blog_os_yawqi::{thread, usermode};
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+// Each program exits with the result of its last system call.
+global_asm!(
+    r#"
+.global test_unknown_syscall
+test_unknown_syscall:
+    mov eax, 999
+    int 0x80
+    mov rdi, rax
+    xor eax, eax
+    int 0x80
+.global test_unknown_syscall_end
+test_unknown_syscall_end:
+
+.global test_write_kernel_memory
+test_write_kernel_memory:
+    mov eax, 1
+    mov edi, 1
+    movabs rsi, 0x444444440000
+    mov edx, 16
+    int 0x80
+    mov rdi, rax
+    xor eax, eax
+    int 0x80
+.global test_write_kernel_memory_end
+test_write_kernel_memory_end:
+"#
+);
+
+extern "C" {
+    fn test_unknown_syscall();
+    fn test_unknown_syscall_end();
+    fn test_write_kernel_memory();
+    fn test_write_kernel_memory_end();
+}
+
+fn run(start: unsafe extern "C" fn(), end: unsafe extern "C" fn()) -> u64 {
+    let start = start as usize;
+    let code = unsafe { core::slice::from_raw_parts(start as *const u8, end as usize - start) };
+    let entry = usermode::load_code(code).expect("user code mapping failed");
+    let stack = usermode::allocate_user_stack().expect("user stack allocation failed");
+    usermode::spawn_user(entry, stack)
+        .expect("spawn failed")
+        .join()
+}
+
+#[test_case]
+fn hello_exits_cleanly() {
+    let entry = usermode::load_code(usermode::hello_program()).expect("user code mapping failed");
+    let stack = usermode::allocate_user_stack().expect("user stack allocation failed");
+    let handle = usermode::spawn_user(entry, stack).expect("spawn failed");
+    assert_eq!(handle.join(), 0);
+}
+
+#[test_case]
+fn unknown_syscall_is_rejected() {
+    let code = run(test_unknown_syscall, test_unknown_syscall_end);
+    assert_eq!(code, SyscallError::NoSys.as_u64());
+}
+
+#[test_case]
+fn write_from_kernel_memory_faults() {
+    let code = run(test_write_kernel_memory, test_write_kernel_memory_end);
+    assert_eq!(code, SyscallError::Fault.as_u64());
+}
@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os_yawqi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use alloc::vec;
+use blog_os_yawqi::block::{self, BlockError, SECTOR_SIZE};
+use blog_os_yawqi::pci;
+use blog_os_yawqi::task::executor::Executor;
+use blog_os_yawqi::task::Task;
+use blog_os_yawqi::test_run_until;
+use blog_os_yawqi::virtio::{self, VirtioError};
+use bootloader::{entry_point, BootInfo};
+use core::cell::Cell;
+use core::panic::PanicInfo;
+use futures_util::FutureExt;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os_yawqi::test_init(boot_info);
+    pci::init().expect("PCI enumeration failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os_yawqi::test_panic_handler(info)
+}
+
+#[test_case]
+fn both_transports_are_set_up() {
+    assert_eq!(virtio::block::init().unwrap(), 2);
+    assert!(matches!(
+        virtio::block::init(),
+        Err(VirtioError::AlreadyInitialized)
+    ));
+
+    let devices = virtio::block::devices();
+    assert!(!devices[0].is_legacy() && devices[1].is_legacy());
+    let vda = block::find("vda").unwrap();
+    let vdb = block::find("vdb").unwrap();
+    assert_eq!((vda.sector_count(), vdb.sector_count()), (2048, 1024));
+    assert!(!vda.read_only());
+}
+
+#[test_case]
+fn requests_complete_on_interrupts() {
+    let done = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    for device in block::devices() {
+        let done = done.clone();
+        executor.spawn(Task::new(async move {
+            // longer than a single request
+            let mut buffer = vec![0xaa; 20 * SECTOR_SIZE];
+            device.read(3, &mut buffer).await.unwrap();
+            assert!(buffer.iter().all(|byte| *byte == 0));
+            device.write(5, &buffer[..SECTOR_SIZE]).await.unwrap();
+            done.set(done.get() + 1);
+        }));
+    }
+    test_run_until(&mut executor, || done.get() == 2);
+}
+
+#[test_case]
+fn bad_transfers_are_refused() {
+    let device = block::find("vdb").unwrap();
+    let mut buffer = [0; SECTOR_SIZE];
+    let end = device.sector_count();
+    assert_eq!(
+        device.read(end, &mut buffer).now_or_never(),
+        Some(Err(BlockError::OutOfRange(end)))
+    );
+    assert_eq!(
+        device.write(0, &buffer[..100]).now_or_never(),
+        Some(Err(BlockError::BadBufferSize(100)))
+    );
+}